@@ -0,0 +1,201 @@
+use anyhow::{bail, Result};
+use redis_clone_rust::data::Value;
+use redis_clone_rust::error::{self, Error};
+use redis_clone_rust::module::{Context, Module, ModuleCommand, ModuleType, ModuleValue};
+use redis_clone_rust::resp::RespOut;
+use std::any::Any;
+use std::sync::Arc;
+
+// A port of the hellotype example module of Redis: a sorted list of integers stored under a key.
+// Run the server with it available using `cargo run --example hellotype -- --loadmodule hellotype`.
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    redis_clone_rust::run(vec![Box::new(HelloType)]).await
+}
+
+const TYPE_NAME: &str = "hellotype";
+
+pub struct HelloType;
+
+impl Module for HelloType {
+    fn name(&self) -> &str {
+        "hellotype"
+    }
+
+    fn commands(&self) -> Vec<Arc<dyn ModuleCommand>> {
+        vec![Arc::new(Insert), Arc::new(Range), Arc::new(Len)]
+    }
+
+    fn types(&self) -> Vec<Arc<dyn ModuleType>> {
+        vec![Arc::new(HelloTypeType)]
+    }
+}
+
+struct HelloTypeType;
+
+impl ModuleType for HelloTypeType {
+    fn name(&self) -> &str {
+        TYPE_NAME
+    }
+
+    fn load(&self, bytes: &[u8]) -> Result<Box<dyn ModuleValue>> {
+        if !bytes.len().is_multiple_of(8) {
+            bail!("invalid {} value", TYPE_NAME);
+        }
+        let values = bytes
+            .chunks_exact(8)
+            .map(|chunk| i64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Ok(Box::new(HelloTypeValue { values }))
+    }
+}
+
+/// The integers in ascending order.
+#[derive(Default)]
+struct HelloTypeValue {
+    values: Vec<i64>,
+}
+
+impl ModuleValue for HelloTypeValue {
+    fn type_name(&self) -> &str {
+        TYPE_NAME
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The value of the key, `None` if it does not exist.
+fn get<'a>(ctx: &'a mut Context, key: &str) -> error::Result<Option<&'a HelloTypeValue>> {
+    match ctx.data().get(key) {
+        None => Ok(None),
+        Some(Value::Module(value)) => match value.as_any().downcast_ref() {
+            Some(value) => Ok(Some(value)),
+            None => Err(Error::WrongType),
+        },
+        Some(_) => Err(Error::WrongType),
+    }
+}
+
+/// HELLOTYPE.INSERT key value
+struct Insert;
+
+impl ModuleCommand for Insert {
+    fn name(&self) -> &str {
+        "hellotype.insert"
+    }
+
+    fn arity(&self) -> i64 {
+        3
+    }
+
+    fn flags(&self) -> &str {
+        "write deny-oom"
+    }
+
+    fn key_positions(&self) -> (i64, i64, i64) {
+        (1, 1, 1)
+    }
+
+    fn call(&self, ctx: &mut Context, args: &[String]) -> error::Result<RespOut> {
+        let key = &args[0];
+        let Ok(value) = args[1].parse::<i64>() else {
+            return Err(Error::Err(
+                "invalid value: must be a signed 64 bit integer".to_string(),
+            ));
+        };
+
+        if get(ctx, key)?.is_none() {
+            let empty = Box::new(HelloTypeValue::default());
+            ctx.data().set(key.clone(), Value::Module(empty), None);
+        }
+        let Some(Value::Module(list)) = ctx.data().get_mut(key) else {
+            unreachable!("checked above");
+        };
+        let list = list.as_any_mut().downcast_mut::<HelloTypeValue>().unwrap();
+        let pos = list.values.partition_point(|v| *v < value);
+        list.values.insert(pos, value);
+        let len = list.values.len();
+
+        ctx.notify_keyspace_event("hellotype.insert", key);
+        ctx.replicate_verbatim();
+        Ok(RespOut::Integer(len as i64))
+    }
+}
+
+/// HELLOTYPE.RANGE key first count
+struct Range;
+
+impl ModuleCommand for Range {
+    fn name(&self) -> &str {
+        "hellotype.range"
+    }
+
+    fn arity(&self) -> i64 {
+        4
+    }
+
+    fn flags(&self) -> &str {
+        "readonly"
+    }
+
+    fn key_positions(&self) -> (i64, i64, i64) {
+        (1, 1, 1)
+    }
+
+    fn call(&self, ctx: &mut Context, args: &[String]) -> error::Result<RespOut> {
+        let (Ok(first), Ok(count)) = (args[1].parse::<usize>(), args[2].parse::<usize>()) else {
+            return Err(Error::Err("invalid first or count parameters".to_string()));
+        };
+
+        let values = match get(ctx, &args[0])? {
+            Some(list) => list
+                .values
+                .iter()
+                .skip(first)
+                .take(count)
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(RespOut::Array(
+            values.into_iter().map(RespOut::Integer).collect(),
+        ))
+    }
+}
+
+/// HELLOTYPE.LEN key
+struct Len;
+
+impl ModuleCommand for Len {
+    fn name(&self) -> &str {
+        "hellotype.len"
+    }
+
+    fn arity(&self) -> i64 {
+        2
+    }
+
+    fn flags(&self) -> &str {
+        "readonly fast"
+    }
+
+    fn key_positions(&self) -> (i64, i64, i64) {
+        (1, 1, 1)
+    }
+
+    fn call(&self, ctx: &mut Context, args: &[String]) -> error::Result<RespOut> {
+        let len = get(ctx, &args[0])?.map_or(0, |list| list.values.len());
+        Ok(RespOut::Integer(len as i64))
+    }
+}
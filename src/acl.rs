@@ -0,0 +1,801 @@
+use crate::category;
+use crate::command::Commands;
+use crate::utils::{constant_time_eq, glob_match, hex_encode, now_ms};
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+// Users and what they may do, see https://redis.io/docs/management/security/acl/
+// A user is described by rules applied in order, like `on >secret ~cache:* +@read -keys`.
+
+pub type SharedAcl = Arc<RwLock<Acl>>;
+
+/// The user connections start as, and that AUTH with only a password authenticates.
+pub const DEFAULT_USER: &str = "default";
+
+/// Most entries kept by ACL LOG.
+const LOG_MAX_LEN: usize = 128;
+
+/// Milliseconds within which the same denial updates its log entry instead of adding one.
+const LOG_GROUP_MS: u128 = 60_000;
+
+/// Keys matching `pattern` may be read and/or written.
+#[derive(Clone)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, true) => format!("~{}", self.pattern),
+            (true, false) => format!("%R~{}", self.pattern),
+            (false, _) => format!("%W~{}", self.pattern),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct User {
+    name: String,
+    enabled: bool,
+    /// Any password is accepted.
+    nopass: bool,
+    /// SHA-256 hashes of the passwords, hex encoded.
+    passwords: Vec<String>,
+    /// Upper case names of the allowed commands.
+    commands: HashSet<String>,
+    /// Subcommands allowed even though their command is not, like `CONFIG|GET`.
+    allowed_subcommands: HashSet<String>,
+    /// Subcommands denied even though their command is allowed.
+    denied_subcommands: HashSet<String>,
+    /// The command rules since the last `+@all` or `-@all`, to describe the user.
+    command_rules: Vec<String>,
+    keys: Vec<KeyPattern>,
+    /// Patterns of the pub/sub channels the user may access.
+    channels: Vec<String>,
+}
+
+/// Why a user may not run a command.
+pub enum Denied {
+    /// The command, lower case.
+    Command(String),
+    Key(String),
+    Channel(String),
+}
+
+impl Denied {
+    /// The reason reported by ACL LOG.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Denied::Command(_) => "command",
+            Denied::Key(_) => "key",
+            Denied::Channel(_) => "channel",
+        }
+    }
+
+    /// The command, key or channel that was denied.
+    pub fn object(&self) -> &str {
+        match self {
+            Denied::Command(object) | Denied::Key(object) | Denied::Channel(object) => object,
+        }
+    }
+
+    /// The message of the NOPERM error, without the code.
+    pub fn message(&self, user: &str) -> String {
+        match self {
+            Denied::Command(command) => format!(
+                "User {} has no permissions to run the '{}' command",
+                user, command
+            ),
+            Denied::Key(_) => "No permissions to access a key".to_string(),
+            Denied::Channel(_) => "No permissions to access a channel".to_string(),
+        }
+    }
+}
+
+/// The hash a password is stored as.
+fn hash_password(password: &str) -> String {
+    hex_encode(&Sha256::digest(password.as_bytes()))
+}
+
+fn check_hash(hash: &str) -> Result<()> {
+    if hash.len() != 64 || !hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters");
+    }
+    Ok(())
+}
+
+/// The channels a pub/sub command publishes or subscribes to, and whether they are
+/// patterns, which must be allowed as they are rather than match an allowed pattern.
+fn channels<'a>(name: &str, args: &'a [String]) -> (&'a [String], bool) {
+    match name {
+        "PUBLISH" | "SPUBLISH" => (args.get(1..2).unwrap_or_default(), false),
+        "SUBSCRIBE" | "SSUBSCRIBE" => (&args[1..], false),
+        "PSUBSCRIBE" => (&args[1..], true),
+        _ => (&[], false),
+    }
+}
+
+impl User {
+    /// A new user may do nothing until rules say otherwise.
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            commands: HashSet::new(),
+            allowed_subcommands: HashSet::new(),
+            denied_subcommands: HashSet::new(),
+            command_rules: vec!["-@all".to_string()],
+            keys: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn apply_all(&mut self, rules: &[&str], commands: &Commands) -> Result<()> {
+        for rule in rules {
+            self.apply(rule, commands)?;
+        }
+        Ok(())
+    }
+
+    /// Apply an ACL rule, like `on`, `>password`, `~pattern` or `+@category`.
+    fn apply(&mut self, rule: &str, commands: &Commands) -> Result<()> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.apply("~*", commands)?,
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.apply("&*", commands)?,
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => self.apply("+@all", commands)?,
+            "nocommands" => self.apply("-@all", commands)?,
+            "reset" => self.apply_all(
+                &["resetpass", "resetkeys", "resetchannels", "off", "-@all"],
+                commands,
+            )?,
+            _ => self.apply_with_argument(rule, commands)?,
+        }
+        Ok(())
+    }
+
+    /// Apply a rule that starts with a symbol followed by a password, pattern or command.
+    fn apply_with_argument(&mut self, rule: &str, commands: &Commands) -> Result<()> {
+        let (symbol, arg) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+        match symbol {
+            ">" | "#" => {
+                let hash = match symbol {
+                    ">" => hash_password(arg),
+                    _ => {
+                        check_hash(arg)?;
+                        arg.to_string()
+                    }
+                };
+                if !self.passwords.contains(&hash) {
+                    self.passwords.push(hash);
+                }
+                self.nopass = false;
+            }
+            "<" | "!" => {
+                let hash = match symbol {
+                    "<" => hash_password(arg),
+                    _ => {
+                        check_hash(arg)?;
+                        arg.to_string()
+                    }
+                };
+                let Some(pos) = self.passwords.iter().position(|p| *p == hash) else {
+                    bail!("The password you are trying to remove from the user does not exist");
+                };
+                self.passwords.remove(pos);
+            }
+            "~" => self.add_key_pattern(arg, true, true),
+            "%" => {
+                let Some((permissions, pattern)) = arg.split_once('~') else {
+                    bail!("Syntax error");
+                };
+                let mut read = false;
+                let mut write = false;
+                for permission in permissions.chars() {
+                    match permission.to_ascii_uppercase() {
+                        'R' => read = true,
+                        'W' => write = true,
+                        _ => bail!("Syntax error"),
+                    }
+                }
+                if !read && !write {
+                    bail!("Syntax error");
+                }
+                self.add_key_pattern(pattern, read, write);
+            }
+            "&" => {
+                if !self.channels.iter().any(|channel| channel == arg) {
+                    self.channels.push(arg.to_string());
+                }
+            }
+            "+" | "-" => self.apply_command_rule(symbol == "+", arg, commands)?,
+            _ => bail!("Syntax error"),
+        }
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) {
+        match self.keys.iter_mut().find(|key| key.pattern == pattern) {
+            Some(key) => {
+                key.read |= read;
+                key.write |= write;
+            }
+            None => self.keys.push(KeyPattern {
+                pattern: pattern.to_string(),
+                read,
+                write,
+            }),
+        }
+    }
+
+    /// Allow or deny a command, a subcommand like `config|get`, or a category like `@read`.
+    fn apply_command_rule(&mut self, allow: bool, arg: &str, commands: &Commands) -> Result<()> {
+        let unknown = || anyhow!("Unknown command or category name in ACL");
+        let rule = format!("{}{}", if allow { '+' } else { '-' }, arg.to_lowercase());
+
+        if let Some(name) = arg.strip_prefix('@') {
+            let categories = match name.eq_ignore_ascii_case("all") {
+                true => u32::MAX,
+                false => category::from_name(name).ok_or_else(unknown)?,
+            };
+            for name in commands.in_categories(categories) {
+                self.set_command(allow, name);
+            }
+            if categories == u32::MAX {
+                self.allowed_subcommands.clear();
+                self.denied_subcommands.clear();
+                self.command_rules.clear();
+            }
+        } else if let Some((name, subcommand)) = arg.split_once('|') {
+            let name = name.to_uppercase();
+            if commands.get(&name).is_none() || subcommand.is_empty() {
+                return Err(unknown());
+            }
+            let subcommand = format!("{}|{}", name, subcommand.to_uppercase());
+            match allow {
+                true => {
+                    self.denied_subcommands.remove(&subcommand);
+                    self.allowed_subcommands.insert(subcommand);
+                }
+                false => {
+                    self.allowed_subcommands.remove(&subcommand);
+                    self.denied_subcommands.insert(subcommand);
+                }
+            }
+        } else {
+            let name = arg.to_uppercase();
+            if commands.get(&name).is_none() {
+                return Err(unknown());
+            }
+            self.set_command(allow, &name);
+        }
+
+        self.command_rules.push(rule);
+        Ok(())
+    }
+
+    /// Allow or deny a whole command, forgetting the rules about its subcommands.
+    fn set_command(&mut self, allow: bool, name: &str) {
+        let prefix = format!("{}|", name);
+        self.allowed_subcommands.retain(|s| !s.starts_with(&prefix));
+        self.denied_subcommands.retain(|s| !s.starts_with(&prefix));
+        match allow {
+            true => self.commands.insert(name.to_string()),
+            false => self.commands.remove(name),
+        };
+    }
+
+    /// Whether `password` is one of the passwords of the user.
+    fn has_password(&self, password: &str) -> bool {
+        let hash = hash_password(password);
+        self.nopass
+            || self
+                .passwords
+                .iter()
+                .any(|p| constant_time_eq(p.as_bytes(), hash.as_bytes()))
+    }
+
+    /// Check that the user may run the command with upper case `name` and arguments `args`,
+    /// which start with the name, reading and/or writing `keys`.
+    pub fn check(
+        &self,
+        name: &str,
+        args: &[String],
+        keys: &[String],
+        read: bool,
+        write: bool,
+    ) -> Result<(), Denied> {
+        let subcommand = args
+            .get(1)
+            .map(|arg| format!("{}|{}", name, arg.to_uppercase()));
+        let subcommand_allowed = subcommand
+            .as_ref()
+            .is_some_and(|s| self.allowed_subcommands.contains(s));
+        let subcommand_denied = subcommand
+            .as_ref()
+            .filter(|s| self.denied_subcommands.contains(*s));
+        if let Some(subcommand) = subcommand_denied {
+            return Err(Denied::Command(subcommand.to_lowercase()));
+        }
+        if !self.commands.contains(name) && !subcommand_allowed {
+            return Err(Denied::Command(name.to_lowercase()));
+        }
+
+        for key in keys {
+            let allowed = self.keys.iter().any(|pattern| {
+                (pattern.read || !read)
+                    && (pattern.write || !write)
+                    && glob_match(&pattern.pattern, key)
+            });
+            if !allowed {
+                return Err(Denied::Key(key.clone()));
+            }
+        }
+
+        let (channels, literal) = channels(name, args);
+        for channel in channels {
+            let allowed = self.channels.iter().any(|pattern| match literal {
+                true => pattern == channel,
+                false => glob_match(pattern, channel),
+            });
+            if !allowed {
+                return Err(Denied::Channel(channel.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// `on`/`off`, and `nopass` if it applies.
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut res = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            res.push("nopass");
+        }
+        res
+    }
+
+    pub fn passwords(&self) -> &[String] {
+        &self.passwords
+    }
+
+    pub fn describe_commands(&self) -> String {
+        self.command_rules.join(" ")
+    }
+
+    pub fn describe_keys(&self) -> String {
+        self.keys
+            .iter()
+            .map(KeyPattern::describe)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        self.channels
+            .iter()
+            .map(|channel| format!("&{}", channel))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The user as a line of ACL LIST and of the ACL file, rules that recreate it.
+    pub fn describe(&self) -> String {
+        let mut res = vec![format!("user {}", self.name)];
+        res.extend(self.flags().into_iter().map(String::from));
+        res.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        if !self.keys.is_empty() {
+            res.push(self.describe_keys());
+        }
+        res.push(match self.channels.is_empty() {
+            true => "resetchannels".to_string(),
+            false => self.describe_channels(),
+        });
+        res.push(self.describe_commands());
+        res.join(" ")
+    }
+}
+
+/// A denied command or failed authentication, see ACL LOG.
+pub struct LogEntry {
+    pub id: u64,
+    /// Number of times it happened within [`LOG_GROUP_MS`] of each other.
+    pub count: u64,
+    /// `command`, `key`, `channel` or `auth`.
+    pub reason: &'static str,
+    /// `toplevel`, `multi` or `lua`.
+    pub context: &'static str,
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub created_ms: u128,
+    pub updated_ms: u128,
+}
+
+pub struct Acl {
+    users: BTreeMap<String, User>,
+    /// Newest first.
+    log: VecDeque<LogEntry>,
+    next_log_id: u64,
+    /// Where ACL LOAD and ACL SAVE read and write the users.
+    file: Option<PathBuf>,
+}
+
+impl Acl {
+    /// Only the default user, who may do anything, with the password `requirepass` if any.
+    pub fn new(commands: &Commands, requirepass: Option<&str>, file: Option<PathBuf>) -> Self {
+        let mut acl = Self {
+            users: BTreeMap::from([(DEFAULT_USER.to_string(), Self::default_user(commands))]),
+            log: VecDeque::new(),
+            next_log_id: 0,
+            file,
+        };
+        acl.set_requirepass(requirepass);
+        acl
+    }
+
+    /// Make `requirepass` the only password of the default user, or let it in without
+    /// a password if there is none or it is empty.
+    pub fn set_requirepass(&mut self, requirepass: Option<&str>) {
+        let Some(default) = self.users.get_mut(DEFAULT_USER) else {
+            return;
+        };
+        match requirepass.filter(|password| !password.is_empty()) {
+            Some(password) => {
+                default.passwords = vec![hash_password(password)];
+                default.nopass = false;
+            }
+            None => {
+                default.passwords.clear();
+                default.nopass = true;
+            }
+        }
+    }
+
+    fn default_user(commands: &Commands) -> User {
+        let mut user = User::new(DEFAULT_USER);
+        user.apply_all(&["on", "nopass", "~*", "&*", "+@all"], commands)
+            .expect("valid rules");
+        user
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// All users, ordered by name.
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    /// Whether new connections must authenticate before running commands,
+    /// because the default user needs a password or is disabled.
+    pub fn requires_auth(&self) -> bool {
+        self.users
+            .get(DEFAULT_USER)
+            .is_none_or(|user| !user.nopass || !user.enabled)
+    }
+
+    /// Whether AUTH with only a password makes sense, the default user having a password.
+    pub fn default_user_has_password(&self) -> bool {
+        self.users
+            .get(DEFAULT_USER)
+            .is_some_and(|user| !user.nopass)
+    }
+
+    /// Whether `name` is an enabled user with the password `password`.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        self.users
+            .get(name)
+            .is_some_and(|user| user.enabled && user.has_password(password))
+    }
+
+    /// Create or modify a user with ACL rules. Nothing changes unless all rules are valid.
+    pub fn set_user(&mut self, name: &str, rules: &[String], commands: &Commands) -> Result<()> {
+        let mut user = match self.users.get(name) {
+            Some(user) => user.clone(),
+            None => User::new(name),
+        };
+        for rule in rules {
+            if let Err(e) = user.apply(rule, commands) {
+                bail!("Error in ACL SETUSER modifier '{}': {}", rule, e);
+            }
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Delete a user, returning whether it existed.
+    pub fn del_user(&mut self, name: &str) -> Result<bool> {
+        if name == DEFAULT_USER {
+            bail!("The 'default' user cannot be removed");
+        }
+        Ok(self.users.remove(name).is_some())
+    }
+
+    /// Record a denied command or failed authentication,
+    /// counting it with the same one if it happened recently.
+    pub fn log(
+        &mut self,
+        reason: &'static str,
+        context: &'static str,
+        object: &str,
+        username: &str,
+        client_info: String,
+    ) {
+        let now = now_ms();
+        let same = self.log.iter().position(|entry| {
+            entry.reason == reason
+                && entry.context == context
+                && entry.object == object
+                && entry.username == username
+                && now.saturating_sub(entry.updated_ms) < LOG_GROUP_MS
+        });
+        let entry = match same.and_then(|pos| self.log.remove(pos)) {
+            Some(entry) => LogEntry {
+                count: entry.count + 1,
+                client_info,
+                updated_ms: now,
+                ..entry
+            },
+            None => {
+                self.next_log_id += 1;
+                LogEntry {
+                    id: self.next_log_id - 1,
+                    count: 1,
+                    reason,
+                    context,
+                    object: object.to_string(),
+                    username: username.to_string(),
+                    client_info,
+                    created_ms: now,
+                    updated_ms: now,
+                }
+            }
+        };
+        self.log.push_front(entry);
+        self.log.truncate(LOG_MAX_LEN);
+    }
+
+    /// The log entries, newest first.
+    pub fn log_entries(&self) -> impl Iterator<Item = &LogEntry> {
+        self.log.iter()
+    }
+
+    pub fn reset_log(&mut self) {
+        self.log.clear();
+    }
+
+    fn file(&self) -> Result<&PathBuf> {
+        self.file.as_ref().ok_or_else(|| {
+            anyhow!("This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.")
+        })
+    }
+
+    /// Replace the users with those of the ACL file, one `user <name> [rule ...]` per line.
+    /// Nothing changes if any line is invalid.
+    pub fn load(&mut self, commands: &Commands) -> Result<()> {
+        let path = self.file()?;
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            anyhow!(
+                "Error loading ACLs, opening file '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        let mut users = BTreeMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let (name, rules) = match words.as_slice() {
+                [] => continue,
+                [word, ..] if word.starts_with('#') => continue,
+                ["user", name, rules @ ..] => (*name, rules),
+                _ => bail!(
+                    "{}:{}: should start with user keyword",
+                    path.display(),
+                    i + 1
+                ),
+            };
+            if users.contains_key(name) {
+                bail!(
+                    "{}:{}: Duplicate user '{}' found",
+                    path.display(),
+                    i + 1,
+                    name
+                );
+            }
+            let mut user = User::new(name);
+            if let Err(e) = user.apply_all(rules, commands) {
+                bail!("{}:{}: {}", path.display(), i + 1, e);
+            }
+            users.insert(name.to_string(), user);
+        }
+
+        // there always is a default user
+        users
+            .entry(DEFAULT_USER.to_string())
+            .or_insert_with(|| Self::default_user(commands));
+        self.users = users;
+        Ok(())
+    }
+
+    /// Write the users to the ACL file.
+    pub fn save(&self) -> Result<()> {
+        let path = self.file()?;
+        let content = self
+            .users
+            .values()
+            .map(|user| user.describe() + "\n")
+            .collect::<String>();
+
+        // never leave a half written file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::Modules;
+
+    fn acl_with(rules: &[&str]) -> (Acl, Commands) {
+        let commands = Commands::new(&Modules::new()).unwrap();
+        let mut acl = Acl::new(&commands, None, None);
+        let rules = rules
+            .iter()
+            .map(|rule| rule.to_string())
+            .collect::<Vec<_>>();
+        acl.set_user("alice", &rules, &commands).unwrap();
+        (acl, commands)
+    }
+
+    fn check(
+        acl: &Acl,
+        args: &[&str],
+        keys: &[&str],
+        read: bool,
+        write: bool,
+    ) -> Result<(), Denied> {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let keys = keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        acl.user("alice")
+            .unwrap()
+            .check(&args[0].to_uppercase(), &args, &keys, read, write)
+    }
+
+    #[test]
+    fn new_users_may_do_nothing() {
+        let (acl, _) = acl_with(&[]);
+        let user = acl.user("alice").unwrap();
+        assert!(!user.enabled());
+        assert_eq!(user.describe(), "user alice off resetchannels -@all");
+        assert!(!acl.authenticate("alice", ""));
+        assert!(matches!(
+            check(&acl, &["get", "k"], &["k"], true, false),
+            Err(Denied::Command(_))
+        ));
+    }
+
+    #[test]
+    fn passwords() {
+        let (mut acl, commands) = acl_with(&["on", ">secret", ">other"]);
+        assert!(acl.authenticate("alice", "secret"));
+        assert!(acl.authenticate("alice", "other"));
+        assert!(!acl.authenticate("alice", "wrong"));
+
+        acl.set_user("alice", &["<other".to_string()], &commands)
+            .unwrap();
+        assert!(!acl.authenticate("alice", "other"));
+        assert!(acl
+            .set_user("alice", &["<other".to_string()], &commands)
+            .is_err());
+
+        acl.set_user("alice", &["nopass".to_string()], &commands)
+            .unwrap();
+        assert!(acl.authenticate("alice", "anything"));
+        acl.set_user("alice", &["off".to_string()], &commands)
+            .unwrap();
+        assert!(!acl.authenticate("alice", "anything"));
+    }
+
+    #[test]
+    fn commands_categories_and_subcommands() {
+        let (acl, _) = acl_with(&["on", "allkeys", "+@string", "-set", "+config|get"]);
+        assert!(check(&acl, &["get", "k"], &["k"], true, false).is_ok());
+        assert!(matches!(
+            check(&acl, &["set", "k", "v"], &["k"], false, true),
+            Err(Denied::Command(name)) if name == "set"
+        ));
+        assert!(check(&acl, &["config", "get", "port"], &[], false, false).is_ok());
+        assert!(matches!(
+            check(&acl, &["config", "set", "port", "1"], &[], false, false),
+            Err(Denied::Command(name)) if name == "config"
+        ));
+        assert_eq!(
+            acl.user("alice").unwrap().describe_commands(),
+            "-@all +@string -set +config|get"
+        );
+    }
+
+    #[test]
+    fn key_patterns_with_permissions() {
+        let (acl, _) = acl_with(&["on", "+@all", "~cache:*", "%R~shared:*"]);
+        assert!(check(&acl, &["set", "cache:1", "v"], &["cache:1"], false, true).is_ok());
+        assert!(check(&acl, &["get", "shared:1"], &["shared:1"], true, false).is_ok());
+        assert!(matches!(
+            check(&acl, &["set", "shared:1", "v"], &["shared:1"], false, true),
+            Err(Denied::Key(key)) if key == "shared:1"
+        ));
+        assert!(matches!(
+            check(&acl, &["get", "other"], &["other"], true, false),
+            Err(Denied::Key(_))
+        ));
+        assert_eq!(
+            acl.user("alice").unwrap().describe_keys(),
+            "~cache:* %R~shared:*"
+        );
+    }
+
+    #[test]
+    fn channel_patterns() {
+        let (acl, _) = acl_with(&["on", "+@all", "&news.*"]);
+        assert!(check(&acl, &["publish", "news.tech", "hi"], &[], false, false).is_ok());
+        assert!(matches!(
+            check(&acl, &["subscribe", "sport"], &[], false, false),
+            Err(Denied::Channel(channel)) if channel == "sport"
+        ));
+        // patterns must be allowed as they are
+        assert!(check(&acl, &["psubscribe", "news.*"], &[], false, false).is_ok());
+        assert!(check(&acl, &["psubscribe", "news.t*"], &[], false, false).is_err());
+    }
+
+    #[test]
+    fn bad_rules_are_rejected() {
+        let commands = Commands::new(&Modules::new()).unwrap();
+        let mut acl = Acl::new(&commands, None, None);
+        for rule in [
+            "+nosuchcommand",
+            "+@nosuchcategory",
+            "%X~key",
+            "#nothex",
+            "bogus",
+        ] {
+            assert!(
+                acl.set_user("bob", &[rule.to_string()], &commands).is_err(),
+                "{} was accepted",
+                rule
+            );
+        }
+    }
+}
@@ -1,17 +1,62 @@
-use crate::data::SharedData;
+use crate::info::ReplicaRole;
+use crate::notify;
+use crate::resp::RespIn;
+use crate::state::State;
 use std::time::Duration;
 use tokio::time;
 
-pub async fn delete_expired(data: SharedData) {
+pub async fn delete_expired(state: State) {
     let mut interval = time::interval(Duration::from_secs(60));
 
     loop {
         interval.tick().await;
 
+        // replicas get their deletes from the master
+        if state.info.read().await.replication.role() != ReplicaRole::MASTER {
+            continue;
+        }
+        // expiring writes, which CLIENT PAUSE holds back
+        if state.clients.read().await.is_paused() {
+            continue;
+        }
+
         println!("(INFO) Checking for expired keys");
 
-        let mut data = data.write().await;
+        let mut data = state.data.write().await;
+
+        let keys = data.expire_keys();
+
+        let pubsub = state.pubsub.read().await;
+        for key in &keys {
+            pubsub.notify_keyspace_event(notify::EXPIRED, "expired", key);
+        }
+
+        // propagate while holding the data lock so replicas see deletes in order
+        let mut info = state.info.write().await;
+        for key in keys {
+            info.replication.propagate(&crate::replication::del(key));
+        }
+    }
+}
+
+/// PING the replicas every repl-ping-replica-period, through the replication stream,
+/// so they can tell an idle link from a dead one, see repl-timeout.
+pub async fn ping_replicas(state: State) {
+    loop {
+        let period = state
+            .info
+            .read()
+            .await
+            .replication
+            .config
+            .ping_replica_period;
+        time::sleep(period).await;
 
-        data.expire_keys();
+        // replicas pass on the PINGs of their master
+        let mut info = state.info.write().await;
+        if info.replication.role() == ReplicaRole::MASTER && info.replication.num_replicas() > 0 {
+            info.replication
+                .propagate(&RespIn::Array(vec!["PING".to_string()]));
+        }
     }
 }
@@ -0,0 +1,90 @@
+use crate::flags;
+
+// ACL categories of commands, as reported by COMMAND INFO.
+// See https://redis.io/docs/management/security/acl/#command-categories
+
+pub const KEYSPACE: u32 = 1 << 0;
+pub const READ: u32 = 1 << 1;
+pub const WRITE: u32 = 1 << 2;
+pub const SET: u32 = 1 << 3;
+pub const SORTEDSET: u32 = 1 << 4;
+pub const LIST: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const STRING: u32 = 1 << 7;
+pub const BITMAP: u32 = 1 << 8;
+pub const HYPERLOGLOG: u32 = 1 << 9;
+pub const GEO: u32 = 1 << 10;
+pub const STREAM: u32 = 1 << 11;
+pub const PUBSUB: u32 = 1 << 12;
+pub const ADMIN: u32 = 1 << 13;
+pub const FAST: u32 = 1 << 14;
+pub const SLOW: u32 = 1 << 15;
+pub const BLOCKING: u32 = 1 << 16;
+pub const DANGEROUS: u32 = 1 << 17;
+pub const CONNECTION: u32 = 1 << 18;
+pub const TRANSACTION: u32 = 1 << 19;
+pub const SCRIPTING: u32 = 1 << 20;
+
+pub const NAMES: &[(u32, &str)] = &[
+    (KEYSPACE, "keyspace"),
+    (READ, "read"),
+    (WRITE, "write"),
+    (SET, "set"),
+    (SORTEDSET, "sortedset"),
+    (LIST, "list"),
+    (HASH, "hash"),
+    (STRING, "string"),
+    (BITMAP, "bitmap"),
+    (HYPERLOGLOG, "hyperloglog"),
+    (GEO, "geo"),
+    (STREAM, "stream"),
+    (PUBSUB, "pubsub"),
+    (ADMIN, "admin"),
+    (FAST, "fast"),
+    (SLOW, "slow"),
+    (BLOCKING, "blocking"),
+    (DANGEROUS, "dangerous"),
+    (CONNECTION, "connection"),
+    (TRANSACTION, "transaction"),
+    (SCRIPTING, "scripting"),
+];
+
+/// The categories a command with the given flags belongs to regardless of what it does,
+/// like in Redis: `@write` for write commands, `@slow` unless it is fast, and so on.
+pub fn implicit(command_flags: u32) -> u32 {
+    let mut res = 0;
+    if command_flags & flags::WRITE != 0 {
+        res |= WRITE;
+    }
+    if command_flags & flags::READONLY != 0 {
+        res |= READ;
+    }
+    if command_flags & flags::ADMIN != 0 {
+        res |= ADMIN | DANGEROUS;
+    }
+    if command_flags & flags::PUBSUB != 0 {
+        res |= PUBSUB;
+    }
+    res |= match command_flags & flags::FAST != 0 {
+        true => FAST,
+        false => SLOW,
+    };
+    res
+}
+
+/// The category with the given name, without the `@`.
+pub fn from_name(name: &str) -> Option<u32> {
+    NAMES
+        .iter()
+        .find(|(_, n)| n.eq_ignore_ascii_case(name))
+        .map(|(category, _)| *category)
+}
+
+/// The names of the categories set in `categories`.
+pub fn names(categories: u32) -> Vec<&'static str> {
+    NAMES
+        .iter()
+        .filter(|(category, _)| categories & category != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
@@ -0,0 +1,408 @@
+use crate::acl::DEFAULT_USER;
+use crate::data::Data;
+use crate::resp::{RespIn, RespOut};
+use crate::utils::now_ms;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Notify, RwLock};
+use tokio::time::{self, Instant};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// State of a single connection that lives across commands.
+#[derive(Default)]
+pub struct Client {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    /// Our address the client connected to.
+    pub local_addr: Option<SocketAddr>,
+    /// Set with CLIENT SETNAME or HELLO SETNAME.
+    pub name: Option<String>,
+    /// Client library, announced with CLIENT SETINFO.
+    pub lib_name: Option<String>,
+    pub lib_ver: Option<String>,
+    pub created_ms: u128,
+    /// When the client last sent a command.
+    pub last_interaction_ms: u128,
+    /// The last command, like `client|list`.
+    pub last_command: String,
+    /// Bytes received but not parsed yet.
+    pub query_buffer: usize,
+    /// Notified to close the connection, see CLIENT KILL.
+    pub kill: Arc<Notify>,
+    /// Which replies are sent, see CLIENT REPLY.
+    pub reply: ReplyMode,
+    /// Set with CLIENT NO-EVICT. Only shown by CLIENT LIST: clients are never evicted,
+    /// there is no maxmemory-clients.
+    pub no_evict: bool,
+    /// Set with CLIENT NO-TOUCH. Only shown by CLIENT LIST: keys have no access time
+    /// to leave alone, as no eviction policy uses one.
+    pub no_touch: bool,
+    /// The connection is our link to the master.
+    pub is_master: bool,
+    /// The client authenticated, or did not need to, see requirepass.
+    pub authenticated: bool,
+    /// The ACL user the client runs commands as.
+    pub user: String,
+    /// Reply to the master for the current command, see REPLCONF GETACK.
+    pub force_reply: bool,
+    /// Port the peer listens on, announced with REPLCONF listening-port.
+    pub listening_port: Option<u16>,
+    /// IP the peer wants to be reached at, announced with REPLCONF ip-address.
+    pub announced_ip: Option<String>,
+    /// The peer accepts snapshots of unknown length, announced with REPLCONF capa eof.
+    pub capa_eof: bool,
+    /// Set once the peer has issued PSYNC.
+    pub replica_id: Option<u64>,
+    /// The replication stream to forward to the replica.
+    pub replication_stream: Option<UnboundedReceiver<Vec<u8>>>,
+    /// Where messages that are not replies to a command, like pub/sub messages, are sent.
+    pub push: Option<UnboundedSender<RespOut>>,
+    /// Subscribed pub/sub channels.
+    pub channels: HashSet<String>,
+    /// Subscribed pub/sub patterns.
+    pub patterns: HashSet<String>,
+    /// Subscribed sharded pub/sub channels.
+    pub shard_channels: HashSet<String>,
+    /// The transaction started with MULTI.
+    pub multi: Option<Multi>,
+    /// Keys watched with WATCH, aborting the next EXEC if they change.
+    pub watched: HashMap<String, WatchedKey>,
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let now = now_ms();
+        Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            user: DEFAULT_USER.to_string(),
+            created_ms: now,
+            last_interaction_ms: now,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_addr(addr: SocketAddr, local_addr: SocketAddr) -> Self {
+        Self {
+            addr: Some(addr),
+            local_addr: Some(local_addr),
+            ..Self::new()
+        }
+    }
+
+    /// A description of the client for CLIENT LIST and logs,
+    /// like `id=3 addr=127.0.0.1:50112 laddr=127.0.0.1:6379 name= age=0 ...`.
+    pub fn info(&self) -> String {
+        self.listing().line(now_ms())
+    }
+
+    /// What other connections see of the client.
+    pub fn listing(&self) -> Listing {
+        let kind = if self.is_master {
+            ClientType::Master
+        } else if self.replica_id.is_some() {
+            ClientType::Replica
+        } else if self.is_subscribed() {
+            ClientType::PubSub
+        } else {
+            ClientType::Normal
+        };
+        let mut flags = match kind {
+            ClientType::Normal => "",
+            ClientType::Replica => "S",
+            ClientType::Master => "M",
+            ClientType::PubSub => "P",
+        }
+        .to_string();
+        if self.multi.is_some() {
+            flags.push('x');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if self.no_touch {
+            flags.push('T');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        Listing {
+            id: self.id,
+            addr: self.addr,
+            local_addr: self.local_addr,
+            name: self.name.clone().unwrap_or_default(),
+            created_ms: self.created_ms,
+            last_interaction_ms: self.last_interaction_ms,
+            kind,
+            flags,
+            sub: self.channels.len(),
+            psub: self.patterns.len(),
+            ssub: self.shard_channels.len(),
+            multi: self
+                .multi
+                .as_ref()
+                .map_or(-1, |multi| multi.commands.len() as i64),
+            watch: self.watched.len(),
+            query_buffer: self.query_buffer,
+            last_command: self.last_command.clone(),
+            user: self.user.clone(),
+            lib_name: self.lib_name.clone().unwrap_or_default(),
+            lib_ver: self.lib_ver.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Whether the reply to the command just run is dropped, see CLIENT REPLY.
+    pub fn skip_reply(&mut self) -> bool {
+        match self.reply {
+            ReplyMode::On => false,
+            ReplyMode::Off => true,
+            ReplyMode::SkipThis => {
+                self.reply = ReplyMode::SkipNext;
+                true
+            }
+            ReplyMode::SkipNext => {
+                self.reply = ReplyMode::On;
+                true
+            }
+        }
+    }
+
+    /// Number of channels and patterns subscribed to.
+    pub fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Number of sharded channels subscribed to.
+    pub fn shard_subscription_count(&self) -> usize {
+        self.shard_channels.len()
+    }
+
+    /// While subscribed to anything only pub/sub commands are accepted.
+    pub fn is_subscribed(&self) -> bool {
+        self.subscription_count() + self.shard_subscription_count() > 0
+    }
+}
+
+/// Which replies are sent to the client.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum ReplyMode {
+    #[default]
+    On,
+    Off,
+    /// CLIENT REPLY SKIP was just run: neither its reply nor the next is sent.
+    SkipThis,
+    SkipNext,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClientType {
+    Normal,
+    Replica,
+    Master,
+    PubSub,
+}
+
+impl ClientType {
+    /// The type named like in CLIENT LIST TYPE, `slave` being the old name of replicas.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "normal" => Some(Self::Normal),
+            "replica" | "slave" => Some(Self::Replica),
+            "master" => Some(Self::Master),
+            "pubsub" => Some(Self::PubSub),
+            _ => None,
+        }
+    }
+}
+
+/// A client as of its last command, see [`Client::listing`].
+#[derive(Clone)]
+pub struct Listing {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub name: String,
+    pub created_ms: u128,
+    pub last_interaction_ms: u128,
+    pub kind: ClientType,
+    pub flags: String,
+    pub sub: usize,
+    pub psub: usize,
+    pub ssub: usize,
+    /// Commands queued after MULTI, -1 outside of a transaction.
+    pub multi: i64,
+    pub watch: usize,
+    pub query_buffer: usize,
+    pub last_command: String,
+    pub user: String,
+    pub lib_name: String,
+    pub lib_ver: String,
+}
+
+impl Listing {
+    /// Seconds since the client connected.
+    pub fn age(&self, now: u128) -> u128 {
+        now.saturating_sub(self.created_ms) / 1000
+    }
+
+    /// A line of CLIENT LIST, with the fields of Redis that make sense here.
+    /// obl, oll and omem are always 0: replies are written as soon as they are made,
+    /// so there is no output buffer to report. Pushed messages waiting to be written
+    /// are not counted.
+    pub fn line(&self, now: u128) -> String {
+        let addr = |addr: Option<SocketAddr>| addr.map_or(String::new(), |addr| addr.to_string());
+        let idle = now.saturating_sub(self.last_interaction_ms) / 1000;
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db=0 sub={} psub={} ssub={} multi={} watch={} qbuf={} obl=0 oll=0 omem=0 cmd={} user={} resp=2 lib-name={} lib-ver={}",
+            self.id,
+            addr(self.addr),
+            addr(self.local_addr),
+            self.name,
+            self.age(now),
+            idle,
+            self.flags,
+            self.sub,
+            self.psub,
+            self.ssub,
+            self.multi,
+            self.watch,
+            self.query_buffer,
+            match self.last_command.as_str() {
+                "" => "NULL",
+                cmd => cmd,
+            },
+            self.user,
+            self.lib_name,
+            self.lib_ver,
+        )
+    }
+}
+
+pub type SharedClients = Arc<RwLock<Clients>>;
+
+/// The connected clients, for CLIENT LIST and CLIENT KILL, and whether they are paused.
+pub struct Clients {
+    clients: BTreeMap<u64, (Listing, Arc<Notify>)>,
+    pause: watch::Sender<Option<Pause>>,
+}
+
+/// Commands of clients are held back until `until`, see CLIENT PAUSE.
+#[derive(Clone, Copy)]
+pub struct Pause {
+    pub until: Instant,
+    /// Only commands that may write are held back otherwise.
+    pub all: bool,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self {
+            clients: BTreeMap::new(),
+            pause: watch::Sender::new(None),
+        }
+    }
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a client, or update it after it ran a command.
+    pub fn update(&mut self, client: &Client) {
+        self.clients
+            .insert(client.id, (client.listing(), Arc::clone(&client.kill)));
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    /// The clients, ordered by ID.
+    pub fn iter(&self) -> impl Iterator<Item = &Listing> {
+        self.clients.values().map(|(listing, _)| listing)
+    }
+
+    /// Close the connection of client `id`.
+    pub fn kill(&self, id: u64) {
+        if let Some((_, kill)) = self.clients.get(&id) {
+            // stored if the connection is busy, so it closes once the command is done
+            kill.notify_one();
+        }
+    }
+
+    pub fn pause(&self, pause: Pause) {
+        self.pause.send_replace(Some(pause));
+    }
+
+    pub fn unpause(&self) {
+        self.pause.send_replace(None);
+    }
+
+    /// Clients are paused, at least for writes.
+    pub fn is_paused(&self) -> bool {
+        self.pause
+            .borrow()
+            .is_some_and(|pause| pause.until > Instant::now())
+    }
+
+    /// Follows pauses, see [`wait_while_paused`].
+    pub fn pauses(&self) -> watch::Receiver<Option<Pause>> {
+        self.pause.subscribe()
+    }
+}
+
+/// Wait until clients are not paused for the command, which writes if `write`.
+pub async fn wait_while_paused(mut pauses: watch::Receiver<Option<Pause>>, write: bool) {
+    loop {
+        let pause = *pauses.borrow_and_update();
+        let Some(pause) = pause.filter(|pause| pause.all || write) else {
+            return;
+        };
+        if pause.until <= Instant::now() {
+            return;
+        }
+        tokio::select! {
+            _ = time::sleep_until(pause.until) => return,
+            // unpaused, or paused again with other settings
+            _ = pauses.changed() => {}
+        }
+    }
+}
+
+/// Commands queued between MULTI and EXEC.
+#[derive(Default)]
+pub struct Multi {
+    pub commands: Vec<RespIn>,
+    /// A command could not be queued, so EXEC must fail.
+    pub failed: bool,
+}
+
+/// State of a key when it was watched.
+pub struct WatchedKey {
+    version: Option<u64>,
+    deletions: u64,
+    expired: bool,
+}
+
+impl WatchedKey {
+    pub fn new(data: &dyn Data, key: &str) -> Self {
+        Self {
+            version: data.version(key),
+            deletions: data.deletions(),
+            expired: data.is_expired(key),
+        }
+    }
+
+    /// The key was written, deleted or flushed, or it expired, since it was watched.
+    /// A key that did not exist may have been created and deleted again: any deletion
+    /// since then counts, as there is no telling which key it was.
+    pub fn is_modified(&self, data: &dyn Data, key: &str) -> bool {
+        data.version(key) != self.version
+            || (self.version.is_none() && data.deletions() != self.deletions)
+            || (!self.expired && data.is_expired(key))
+    }
+}
@@ -1,11 +1,33 @@
-use crate::data::SharedData;
-use crate::info::SharedInfo;
+use crate::acl::DEFAULT_USER;
+use crate::category;
+use crate::client::{self, Client, ClientType, Multi, Pause, ReplyMode, WatchedKey};
+use crate::data::{Data, Value};
+use crate::error::{bail, Error, Result};
+use crate::file;
+use crate::flags;
+use crate::info::{ReplicaRole, Stats};
+use crate::module::{Context, ModuleCommand, Modules};
+use crate::notify;
+use crate::replication::{MasterLink, ReplicaHandle, ReplicaState};
 use crate::resp::{RespIn, RespOut};
-use anyhow::{bail, Result};
+use crate::scripting;
+use crate::state::State;
+use crate::utils::{glob_match, hex_encode, now_ms};
 use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard, OwnedRwLockWriteGuard, RwLockReadGuard, RwLockWriteGuard};
+use tokio::time;
 
 struct Args<'b> {
     items: &'b Vec<String>,
+    /// The arguments as received, if some are not text.
+    raw: Option<&'b Vec<Vec<u8>>>,
     pos: Cell<usize>,
 }
 
@@ -14,35 +36,112 @@ unsafe impl Send for Args<'_> {}
 unsafe impl Sync for Args<'_> {}
 
 struct Handler<'a, 'b, 'c> {
-    data: &'a SharedData,
-    info: &'b SharedInfo,
-    args: Args<'c>,
+    state: &'a State,
+    args: Args<'b>,
+    client: &'c mut Client,
+    /// The data set locked by EXEC for the whole transaction.
+    exec_data: Option<&'a Mutex<OwnedRwLockWriteGuard<DynData>>>,
+    /// The command is called by a script.
+    in_script: bool,
 }
 
-pub async fn handle(value: RespIn, data: &SharedData, info: &SharedInfo) -> Vec<RespOut> {
-    match handle_value(value, data, info).await {
+type DynData = dyn Data + Send + Sync;
+
+/// Access to the data set, either locked for this command or for the running transaction.
+enum DataGuard<'a, G> {
+    Shared(G),
+    Exec(MutexGuard<'a, OwnedRwLockWriteGuard<DynData>>),
+}
+
+impl<G: Deref<Target = DynData>> Deref for DataGuard<'_, G> {
+    type Target = DynData;
+
+    fn deref(&self) -> &DynData {
+        match self {
+            DataGuard::Shared(guard) => &**guard,
+            DataGuard::Exec(guard) => &***guard,
+        }
+    }
+}
+
+impl<G: DerefMut<Target = DynData>> DerefMut for DataGuard<'_, G> {
+    fn deref_mut(&mut self) -> &mut DynData {
+        match self {
+            DataGuard::Shared(guard) => &mut **guard,
+            DataGuard::Exec(guard) => &mut ***guard,
+        }
+    }
+}
+
+pub async fn handle(value: RespIn, state: &State, client: &mut Client) -> Vec<RespOut> {
+    let res = handle_value(value, state, client).await;
+    let info = state.info.read().await;
+    Stats::incr(&info.stats.total_commands_processed);
+    match res {
         Ok(res) => res,
-        Err(e) => vec![RespOut::Error(format!("failed to handle: {}", e))],
+        Err(e) => {
+            Stats::incr(&info.stats.total_error_replies);
+            vec![error_reply(e)]
+        }
     }
 }
 
-async fn handle_value(value: RespIn, data: &SharedData, info: &SharedInfo) -> Result<Vec<RespOut>> {
-    match value {
+/// Client names and library info are shown in CLIENT LIST, where they must not break the line.
+fn is_valid_client_name(name: &str) -> bool {
+    name.chars().all(|c| ('!'..='~').contains(&c))
+}
+
+fn error_reply(e: Error) -> RespOut {
+    RespOut::Error(e.to_string())
+}
+
+async fn handle_value(value: RespIn, state: &State, client: &mut Client) -> Result<Vec<RespOut>> {
+    match &value {
         RespIn::Array(arr) => {
-            let handler = Handler::new(data, info, Args::new(&arr));
+            let mut handler = Handler::new(state, Args::new(arr), client);
+            handler.handle().await
+        }
+        RespIn::Binary(raw) => {
+            let items = value.to_strings();
+            // the only binary payloads are those of FUNCTION DUMP
+            if !is_function_restore(&items) {
+                bail!("Protocol error: invalid UTF-8 in bulk string");
+            }
+            let mut handler = Handler::new(state, Args::with_raw(&items, Some(raw)), client);
             handler.handle().await
         }
     }
 }
 
+fn is_function_restore(args: &[String]) -> bool {
+    args.len() > 1
+        && args[0].eq_ignore_ascii_case("FUNCTION")
+        && args[1].eq_ignore_ascii_case("RESTORE")
+}
+
 impl<'b> Args<'b> {
     fn new(items: &'b Vec<String>) -> Self {
+        Self::with_raw(items, None)
+    }
+
+    fn with_raw(items: &'b Vec<String>, raw: Option<&'b Vec<Vec<u8>>>) -> Self {
         Self {
             items,
+            raw,
             pos: Cell::new(0),
         }
     }
 
+    /// Next argument as received, for arguments that may be binary.
+    fn next_bytes(&self) -> Result<Vec<u8>> {
+        let pos = self.pos.get();
+        let item = self.next()?;
+        match self.raw {
+            Some(raw) => Ok(raw[pos].clone()),
+            None => Ok(item.as_bytes().to_vec()),
+        }
+    }
+
     fn next(&self) -> Result<&String> {
         let pos = self.pos.get();
         if !self.has_next() {
@@ -60,28 +159,907 @@ impl<'b> Args<'b> {
 
 type Resp = Result<Vec<RespOut>>;
 
+type BoxFuture<'h, T> = Pin<Box<dyn Future<Output = T> + Send + 'h>>;
+
+/// A built-in command, a method of [`Handler`].
+type CommandFn = for<'h, 'a, 'b, 'c> fn(&'h mut Handler<'a, 'b, 'c>) -> BoxFuture<'h, Resp>;
+
+/// A command of [`BUILTIN_COMMANDS`].
+struct Builtin {
+    name: &'static str,
+    /// Number of arguments including the name, negative means at least that many.
+    arity: i64,
+    flags: u32,
+    /// Positions of the first and last key and the step between keys.
+    keys: (i64, i64, i64),
+    /// ACL categories on top of those implied by the flags.
+    categories: u32,
+    group: &'static str,
+    summary: &'static str,
+    run: CommandFn,
+}
+
+/// An entry of [`BUILTIN_COMMANDS`], `h => ...` running the command with the [`Handler`] `h`.
+macro_rules! builtin {
+    (
+        $name:literal, $arity:expr, $flags:expr, $keys:expr, $categories:expr,
+        $group:literal, $summary:literal,
+        $h:ident => $body:expr
+    ) => {
+        Builtin {
+            name: $name,
+            arity: $arity,
+            flags: $flags,
+            keys: $keys,
+            categories: $categories,
+            group: $group,
+            summary: $summary,
+            run: |$h| Box::pin(async move { $body }),
+        }
+    };
+}
+
+/// The table of built-in commands: name, arity, flags, key positions, ACL categories,
+/// group and summary for COMMAND DOCS, and how to run it.
+#[rustfmt::skip]
+const BUILTIN_COMMANDS: &[Builtin] = &[
+    builtin! {
+        "PING", -1, flags::FAST | flags::STALE, (0, 0, 0), category::CONNECTION,
+        "connection", "Returns the server's liveliness response.",
+        h => h.ping()
+    },
+    builtin! {
+        "ECHO", 2, flags::FAST, (0, 0, 0), category::CONNECTION,
+        "connection", "Returns the given string.",
+        h => h.echo()
+    },
+    builtin! {
+        "AUTH", -2, flags::NOSCRIPT | flags::LOADING | flags::STALE | flags::FAST | flags::NO_AUTH | flags::ALLOW_BUSY, (0, 0, 0), category::CONNECTION,
+        "connection", "Authenticates the connection.",
+        h => h.auth().await
+    },
+    builtin! {
+        "HELLO", -1, flags::NOSCRIPT | flags::LOADING | flags::STALE | flags::FAST | flags::NO_AUTH | flags::ALLOW_BUSY, (0, 0, 0), category::CONNECTION,
+        "connection", "Handshakes with the Redis server.",
+        h => h.hello().await
+    },
+    builtin! {
+        "GET", 2, flags::READONLY | flags::FAST, (1, 1, 1), category::STRING,
+        "string", "Returns the string value of a key.",
+        h => h.get().await
+    },
+    builtin! {
+        "SET", -3, flags::WRITE | flags::DENYOOM, (1, 1, 1), category::STRING,
+        "string", "Sets the string value of a key, ignoring its type.",
+        h => h.set().await
+    },
+    builtin! {
+        "DEL", -2, flags::WRITE, (1, -1, 1), category::KEYSPACE,
+        "generic", "Deletes one or more keys.",
+        h => h.del().await
+    },
+    builtin! {
+        "UNLINK", -2, flags::WRITE | flags::FAST, (1, -1, 1), category::KEYSPACE,
+        "generic", "Asynchronously deletes one or more keys.",
+        h => h.del().await
+    },
+    builtin! {
+        "INFO", -1, flags::LOADING | flags::STALE, (0, 0, 0), category::DANGEROUS,
+        "server", "Returns information and statistics about the server.",
+        h => h.info().await
+    },
+    builtin! {
+        "REPLCONF", -1, flags::ADMIN | flags::NOSCRIPT | flags::LOADING | flags::STALE, (0, 0, 0), 0,
+        "server", "An internal command for configuring the replication stream.",
+        h => h.replconf().await
+    },
+    builtin! {
+        "PSYNC", -3, flags::ADMIN | flags::NOSCRIPT | flags::NO_MULTI, (0, 0, 0), 0,
+        "server", "An internal command used in replication.",
+        h => h.psync().await
+    },
+    builtin! {
+        "WAIT", 3, flags::NOSCRIPT, (0, 0, 0), category::CONNECTION | category::BLOCKING,
+        "generic", "Blocks until the preceding writes of the connection reached some replicas.",
+        h => h.wait().await
+    },
+    builtin! {
+        "REPLICAOF", 3, flags::ADMIN | flags::NOSCRIPT | flags::STALE | flags::NO_MULTI, (0, 0, 0), 0,
+        "server", "Configures a server as replica of another, or promotes it to a master.",
+        h => h.replicaof().await
+    },
+    builtin! {
+        "SLAVEOF", 3, flags::ADMIN | flags::NOSCRIPT | flags::STALE | flags::NO_MULTI, (0, 0, 0), 0,
+        "server", "Configures a server as replica of another, or promotes it to a master.",
+        h => h.replicaof().await
+    },
+    builtin! {
+        "ROLE", 1, flags::LOADING | flags::STALE | flags::FAST, (0, 0, 0), category::ADMIN | category::DANGEROUS,
+        "server", "Returns the replication role.",
+        h => h.role().await
+    },
+    builtin! {
+        "SUBSCRIBE", -2, flags::PUBSUB | flags::NOSCRIPT | flags::LOADING, (0, 0, 0), 0,
+        "pubsub", "Listens for messages published to channels.",
+        h => h.subscribe().await
+    },
+    builtin! {
+        "UNSUBSCRIBE", -1, flags::PUBSUB | flags::NOSCRIPT | flags::LOADING, (0, 0, 0), 0,
+        "pubsub", "Stops listening to messages posted to channels.",
+        h => h.unsubscribe().await
+    },
+    builtin! {
+        "PSUBSCRIBE", -2, flags::PUBSUB | flags::NOSCRIPT | flags::LOADING, (0, 0, 0), 0,
+        "pubsub", "Listens for messages published to channels that match patterns.",
+        h => h.psubscribe().await
+    },
+    builtin! {
+        "PUNSUBSCRIBE", -1, flags::PUBSUB | flags::NOSCRIPT | flags::LOADING, (0, 0, 0), 0,
+        "pubsub", "Stops listening to messages published to channels that match patterns.",
+        h => h.punsubscribe().await
+    },
+    builtin! {
+        "SSUBSCRIBE", -2, flags::PUBSUB | flags::NOSCRIPT | flags::LOADING, (1, -1, 1), 0,
+        "pubsub", "Listens for messages published to shard channels.",
+        h => h.ssubscribe().await
+    },
+    builtin! {
+        "SUNSUBSCRIBE", -1, flags::PUBSUB | flags::NOSCRIPT | flags::LOADING, (1, -1, 1), 0,
+        "pubsub", "Stops listening to messages posted to shard channels.",
+        h => h.sunsubscribe().await
+    },
+    builtin! {
+        "PUBLISH", 3, flags::PUBSUB | flags::LOADING | flags::FAST, (0, 0, 0), 0,
+        "pubsub", "Posts a message to a channel.",
+        h => h.publish().await
+    },
+    builtin! {
+        "SPUBLISH", 3, flags::PUBSUB | flags::LOADING | flags::FAST, (1, 1, 1), 0,
+        "pubsub", "Posts a message to a shard channel.",
+        h => h.spublish().await
+    },
+    builtin! {
+        "PUBSUB", -2, flags::PUBSUB | flags::LOADING, (0, 0, 0), 0,
+        "pubsub", "A container for pub/sub introspection commands.",
+        h => h.pubsub().await
+    },
+    builtin! {
+        "RESET", 1, flags::NOSCRIPT | flags::LOADING | flags::FAST, (0, 0, 0), category::CONNECTION,
+        "connection", "Resets the connection.",
+        h => h.reset().await
+    },
+    builtin! {
+        "MULTI", 1, flags::NOSCRIPT | flags::LOADING | flags::FAST, (0, 0, 0), category::TRANSACTION,
+        "transactions", "Starts a transaction.",
+        h => h.multi()
+    },
+    builtin! {
+        "EXEC", 1, flags::NOSCRIPT | flags::LOADING, (0, 0, 0), category::TRANSACTION,
+        "transactions", "Executes all commands in a transaction.",
+        h => h.exec().await
+    },
+    builtin! {
+        "DISCARD", 1, flags::NOSCRIPT | flags::LOADING | flags::FAST, (0, 0, 0), category::TRANSACTION,
+        "transactions", "Discards a transaction.",
+        h => h.discard()
+    },
+    builtin! {
+        "WATCH", -2, flags::NOSCRIPT | flags::LOADING | flags::FAST, (1, -1, 1), category::TRANSACTION,
+        "transactions", "Monitors changes to keys to determine the execution of a transaction.",
+        h => h.watch().await
+    },
+    builtin! {
+        "UNWATCH", 1, flags::NOSCRIPT | flags::LOADING | flags::FAST, (0, 0, 0), category::TRANSACTION,
+        "transactions", "Forgets about watched keys of a transaction.",
+        h => h.unwatch()
+    },
+    builtin! {
+        "EVAL", -3, flags::NOSCRIPT | flags::MOVABLEKEYS, (0, 0, 0), category::SCRIPTING,
+        "scripting", "Executes a server-side Lua script.",
+        h => h.eval(false, false).await
+    },
+    builtin! {
+        "EVALSHA", -3, flags::NOSCRIPT | flags::MOVABLEKEYS, (0, 0, 0), category::SCRIPTING,
+        "scripting", "Executes a server-side Lua script by SHA1 digest.",
+        h => h.eval(true, false).await
+    },
+    builtin! {
+        "EVAL_RO", -3, flags::READONLY | flags::NOSCRIPT | flags::MOVABLEKEYS, (0, 0, 0), category::SCRIPTING,
+        "scripting", "Executes a read-only server-side Lua script.",
+        h => h.eval(false, true).await
+    },
+    builtin! {
+        "EVALSHA_RO", -3, flags::READONLY | flags::NOSCRIPT | flags::MOVABLEKEYS, (0, 0, 0), category::SCRIPTING,
+        "scripting", "Executes a read-only server-side Lua script by SHA1 digest.",
+        h => h.eval(true, true).await
+    },
+    builtin! {
+        "SCRIPT", -2, flags::NOSCRIPT | flags::ALLOW_BUSY, (0, 0, 0), category::SCRIPTING,
+        "scripting", "A container for Lua scripts management commands.",
+        h => h.script()
+    },
+    builtin! {
+        "FCALL", -3, flags::NOSCRIPT | flags::MOVABLEKEYS, (0, 0, 0), category::SCRIPTING,
+        "scripting", "Invokes a function.",
+        h => h.fcall(false).await
+    },
+    builtin! {
+        "FCALL_RO", -3, flags::READONLY | flags::NOSCRIPT | flags::MOVABLEKEYS, (0, 0, 0), category::SCRIPTING,
+        "scripting", "Invokes a read-only function.",
+        h => h.fcall(true).await
+    },
+    builtin! {
+        "FUNCTION", -2, flags::NOSCRIPT | flags::ALLOW_BUSY, (0, 0, 0), category::SCRIPTING,
+        "scripting", "A container for function commands.",
+        h => h.function().await
+    },
+    builtin! {
+        "MODULE", -2, flags::ADMIN | flags::NOSCRIPT, (0, 0, 0), 0,
+        "server", "A container for module commands.",
+        h => h.module()
+    },
+    builtin! {
+        "COMMAND", -1, flags::LOADING | flags::STALE, (0, 0, 0), category::CONNECTION,
+        "server", "Returns detailed information about all commands.",
+        h => h.command()
+    },
+    builtin! {
+        "ACL", -2, flags::ADMIN | flags::NOSCRIPT | flags::LOADING | flags::STALE, (0, 0, 0), 0,
+        "server", "A container for Access List Control commands.",
+        h => h.acl().await
+    },
+    builtin! {
+        "CLIENT", -2, flags::NOSCRIPT | flags::LOADING | flags::STALE, (0, 0, 0), category::CONNECTION,
+        "connection", "A container for client connection commands.",
+        h => h.client_command().await
+    },
+    builtin! {
+        "CONFIG", -2, flags::ADMIN | flags::NOSCRIPT | flags::LOADING | flags::STALE, (0, 0, 0), 0,
+        "server", "A container for server configuration commands.",
+        h => h.config().await
+    },
+];
+
+pub type SharedCommands = Arc<Commands>;
+
+/// How a command runs.
+enum CommandImpl {
+    Builtin(CommandFn),
+    Module(Arc<dyn ModuleCommand>),
+}
+
+pub struct Command {
+    /// Lower case, as in COMMAND replies.
+    name: String,
+    arity: i64,
+    flags: u32,
+    keys: (i64, i64, i64),
+    categories: u32,
+    group: String,
+    summary: String,
+    /// The module that added the command.
+    module: Option<String>,
+    imp: CommandImpl,
+}
+
+impl Command {
+    fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        (self.arity < 0 || argc == self.arity) && argc >= self.arity.abs()
+    }
+
+    /// The keys in `args`, which start with the command name.
+    fn keys(&self, args: &[String]) -> Result<Vec<String>> {
+        // scripts take `numkeys key [key ...]`
+        if self.has_flag(flags::MOVABLEKEYS) {
+            let Some(numkeys) = args.get(2).and_then(|n| n.parse::<usize>().ok()) else {
+                bail!("Invalid arguments specified for command");
+            };
+            let Some(keys) = args.get(3..3 + numkeys) else {
+                bail!("Invalid arguments specified for command");
+            };
+            return Ok(keys.to_vec());
+        }
+
+        let (first, last, step) = self.keys;
+        if first == 0 {
+            return Ok(Vec::new());
+        }
+        let last = match last < 0 {
+            true => args.len() as i64 + last,
+            false => last.min(args.len() as i64 - 1),
+        };
+        Ok((first..=last)
+            .step_by(step as usize)
+            .map(|i| args[i as usize].clone())
+            .collect())
+    }
+
+    /// The reply to COMMAND INFO.
+    fn info(&self) -> RespOut {
+        let (first, last, step) = self.keys;
+        let status = |s: String| RespOut::SimpleString(s);
+        RespOut::Array(vec![
+            RespOut::BulkString(self.name.clone()),
+            RespOut::Integer(self.arity),
+            RespOut::Array(
+                flags::names(self.flags)
+                    .into_iter()
+                    .map(|name| status(name.to_string()))
+                    .collect(),
+            ),
+            RespOut::Integer(first),
+            RespOut::Integer(last),
+            RespOut::Integer(step),
+            RespOut::Array(
+                category::names(self.categories)
+                    .into_iter()
+                    .map(|name| status(format!("@{}", name)))
+                    .collect(),
+            ),
+            // tips
+            RespOut::Array(Vec::new()),
+            RespOut::Array(self.key_specs()),
+            // subcommands
+            RespOut::Array(Vec::new()),
+        ])
+    }
+
+    /// Whether the command reads and whether it writes its keys.
+    fn key_access(&self) -> (bool, bool) {
+        // scripts may read and write unless they are read-only
+        if self.has_flag(flags::MOVABLEKEYS) && !self.has_flag(flags::READONLY) {
+            return (true, true);
+        }
+        match self.has_flag(flags::WRITE) {
+            true => (false, true),
+            false => (true, false),
+        }
+    }
+
+    /// The key specifications of COMMAND INFO, describing how to find the keys.
+    fn key_specs(&self) -> Vec<RespOut> {
+        let bulk = |s: &str| RespOut::BulkString(s.to_string());
+        let access = match (self.has_flag(flags::PUBSUB), self.key_access()) {
+            (true, _) => "NOT_KEY",
+            (false, (_, true)) => "RW",
+            (false, (_, false)) => "RO",
+        };
+
+        let (begin_search, find_keys) = if self.has_flag(flags::MOVABLEKEYS) {
+            (
+                vec![bulk("index"), RespOut::Integer(2)],
+                (
+                    "keynum",
+                    vec![
+                        bulk("keynumidx"),
+                        RespOut::Integer(0),
+                        bulk("firstkey"),
+                        RespOut::Integer(1),
+                        bulk("keystep"),
+                        RespOut::Integer(1),
+                    ],
+                ),
+            )
+        } else {
+            let (first, last, step) = self.keys;
+            if first == 0 {
+                return Vec::new();
+            }
+            (
+                vec![bulk("index"), RespOut::Integer(first)],
+                (
+                    "range",
+                    vec![
+                        bulk("lastkey"),
+                        // relative to the first key, or to the end when negative
+                        RespOut::Integer(if last < 0 { last } else { last - first }),
+                        bulk("keystep"),
+                        RespOut::Integer(step),
+                        bulk("limit"),
+                        RespOut::Integer(0),
+                    ],
+                ),
+            )
+        };
+
+        let (find_type, find_spec) = find_keys;
+        vec![RespOut::Array(vec![
+            bulk("flags"),
+            RespOut::Array(vec![RespOut::SimpleString(access.to_string())]),
+            bulk("begin_search"),
+            RespOut::Array(vec![
+                bulk("type"),
+                bulk("index"),
+                bulk("spec"),
+                RespOut::Array(begin_search),
+            ]),
+            bulk("find_keys"),
+            RespOut::Array(vec![
+                bulk("type"),
+                bulk(find_type),
+                bulk("spec"),
+                RespOut::Array(find_spec),
+            ]),
+        ])]
+    }
+
+    /// The reply to COMMAND DOCS.
+    fn docs(&self) -> RespOut {
+        let bulk = |s: &str| RespOut::BulkString(s.to_string());
+        let mut res = Vec::new();
+        if !self.summary.is_empty() {
+            res.push(bulk("summary"));
+            res.push(bulk(&self.summary));
+        }
+        res.push(bulk("group"));
+        res.push(bulk(&self.group));
+        if let Some(module) = &self.module {
+            res.push(bulk("module"));
+            res.push(bulk(module));
+        }
+        RespOut::Array(res)
+    }
+}
+
+/// The commands the server knows: the built-in ones and those added by modules.
+pub struct Commands {
+    commands: HashMap<String, Command>,
+}
+
+impl Commands {
+    pub fn new(modules: &Modules) -> Result<Self> {
+        let mut commands = HashMap::new();
+        for builtin in BUILTIN_COMMANDS {
+            let command = Command {
+                name: builtin.name.to_lowercase(),
+                arity: builtin.arity,
+                flags: builtin.flags,
+                keys: builtin.keys,
+                categories: builtin.categories | category::implicit(builtin.flags),
+                group: builtin.group.to_string(),
+                summary: builtin.summary.to_string(),
+                module: None,
+                imp: CommandImpl::Builtin(builtin.run),
+            };
+            commands.insert(builtin.name.to_string(), command);
+        }
+        for (module, command) in modules.commands() {
+            let name = command.name().to_uppercase();
+            if commands.contains_key(&name) {
+                bail!("Command {} already exists", command.name());
+            }
+            let flags = flags::parse_module_flags(command.flags())?;
+            let command = Command {
+                name: command.name().to_lowercase(),
+                arity: command.arity(),
+                flags,
+                keys: command.key_positions(),
+                categories: category::implicit(flags),
+                group: "module".to_string(),
+                summary: String::new(),
+                module: Some(module.clone()),
+                imp: CommandImpl::Module(Arc::clone(command)),
+            };
+            commands.insert(name, command);
+        }
+        Ok(Self { commands })
+    }
+
+    /// The command with the given upper case name.
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// The flags of the command with the given upper case name, none if it is unknown.
+    pub fn flags(&self, name: &str) -> u32 {
+        self.get(name).map_or(0, |command| command.flags)
+    }
+
+    /// Upper case names of the commands in any of `categories`.
+    pub fn in_categories(&self, categories: u32) -> impl Iterator<Item = &str> {
+        self.commands
+            .iter()
+            .filter(move |(_, command)| command.categories & categories != 0)
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// All commands, ordered by name.
+    fn sorted(&self) -> Vec<&Command> {
+        let mut res = self.commands.values().collect::<Vec<_>>();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        res
+    }
+}
+
+/// Commands run right away instead of being queued after MULTI.
+const MULTI_COMMANDS: &[&str] = &["MULTI", "EXEC", "DISCARD", "WATCH", "QUIT", "RESET"];
+
+/// Scripts that may write.
+const SCRIPT_COMMANDS: &[&str] = &["EVAL", "EVALSHA", "FCALL"];
+
+/// Commands with subcommands, shown like `client|list` in CLIENT LIST.
+const CONTAINER_COMMANDS: &[&str] = &[
+    "ACL", "CLIENT", "COMMAND", "CONFIG", "FUNCTION", "MODULE", "PUBSUB", "SCRIPT",
+];
+
+/// Commands accepted while subscribed to pub/sub channels.
+const SUBSCRIBED_COMMANDS: &[&str] = &[
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "SSUBSCRIBE",
+    "SUNSUBSCRIBE",
+    "PING",
+    "QUIT",
+    "RESET",
+];
+
 impl<'a, 'b, 'c> Handler<'a, 'b, 'c> {
-    fn new(data: &'a SharedData, info: &'b SharedInfo, args: Args<'c>) -> Handler<'a, 'b, 'c> {
-        Self { data, info, args }
+    fn new(state: &'a State, args: Args<'b>, client: &'c mut Client) -> Handler<'a, 'b, 'c> {
+        Self {
+            state,
+            args,
+            client,
+            exec_data: None,
+            in_script: false,
+        }
     }
 
-    async fn handle(&self) -> Resp {
+    async fn handle(&mut self) -> Resp {
         let cmd = self.args.next()?;
+        let name = cmd.to_uppercase();
+
+        let state = self.state;
+        let command = state.commands.get(&name);
+        let top_level = self.exec_data.is_none() && !self.in_script;
+        if top_level {
+            self.client.last_interaction_ms = now_ms();
+            if command.is_some() {
+                self.client.last_command = self.command_label(&name);
+            }
+        }
+        if let Err(e) = self.check(cmd, &name, command).await {
+            // a command that can not be queued fails the whole transaction
+            if let Some(multi) = &mut self.client.multi {
+                multi.failed = true;
+            }
+            return Err(e);
+        }
+
+        if self.client.multi.is_some() && !MULTI_COMMANDS.contains(&name.as_str()) {
+            let request = self.request();
+            if let Some(multi) = &mut self.client.multi {
+                multi.commands.push(request);
+            }
+            return Ok(vec![RespOut::SimpleString("QUEUED".to_string())]);
+        }
+
+        // replicas and the master keep going, so that they can catch up
+        if top_level && !self.client.is_master && self.client.replica_id.is_none() {
+            let pauses = state.clients.read().await.pauses();
+            client::wait_while_paused(pauses, self.may_replicate(&name)).await;
+        }
+
+        // known, see check
+        let command = command.unwrap();
+        // the master evicts for its replicas, and killing a busy script must not wait for it
+        if !self.client.is_master
+            && command.has_flag(flags::DENYOOM | flags::WRITE)
+            && !command.has_flag(flags::ALLOW_BUSY)
+        {
+            self.evict_if_needed(command.has_flag(flags::DENYOOM))
+                .await?;
+        }
+
+        let lookups = self.lookup_keys(command).await;
+        let res = match &command.imp {
+            CommandImpl::Builtin(f) => f(self).await,
+            CommandImpl::Module(command) => self.module_command(&**command).await,
+        };
+        if let Some(lookups) = lookups {
+            self.notify_lookups(command, lookups).await;
+        }
+        res
+    }
+
+    /// Whether each key of the command exists, if keymiss or new events are wanted.
+    async fn lookup_keys(&self, command: &Command) -> Option<Vec<(String, bool)>> {
+        let notify_flags = self.state.pubsub.read().await.notify_flags();
+        // scripts raise them from the commands they call
+        // ALLOW_BUSY ones must not wait on the data lock a busy script holds
+        if notify_flags & (notify::KEY_MISS | notify::NEW) == 0
+            || command.has_flag(flags::MOVABLEKEYS | flags::ALLOW_BUSY)
+            || command.keys.0 == 0
+        {
+            return None;
+        }
+        let keys = command.keys(self.args.items).ok()?;
+        if keys.is_empty() {
+            return None;
+        }
+        let data = self.data_read().await;
+        Some(
+            keys.into_iter()
+                .map(|key| {
+                    let exists = data.get(&key).is_some();
+                    (key, exists)
+                })
+                .collect(),
+        )
+    }
+
+    /// Raise `keymiss` for the missing keys a read looked up and `new` for the keys a write created.
+    async fn notify_lookups(&self, command: &Command, lookups: Vec<(String, bool)>) {
+        let data = self.data_read().await;
+        let pubsub = self.state.pubsub.read().await;
+        for (key, existed) in lookups {
+            if existed {
+                continue;
+            }
+            if command.has_flag(flags::READONLY) {
+                pubsub.notify_keyspace_event(notify::KEY_MISS, "keymiss", &key);
+            } else if command.has_flag(flags::WRITE) && data.get(&key).is_some() {
+                pubsub.notify_keyspace_event(notify::NEW, "new", &key);
+            }
+        }
+    }
+
+    /// The command as shown in CLIENT LIST, with the subcommand of containers.
+    fn command_label(&self, name: &str) -> String {
+        let label = match self.args.items.get(1) {
+            Some(subcommand) if CONTAINER_COMMANDS.contains(&name) => {
+                format!("{}|{}", name, subcommand)
+            }
+            _ => name.to_string(),
+        };
+        label.to_lowercase()
+    }
+
+    /// Whether the command may write or be replicated, so that CLIENT PAUSE WRITE holds it back.
+    fn may_replicate(&self, name: &str) -> bool {
+        let writes = |name: &str| {
+            self.state.commands.flags(name) & flags::WRITE != 0
+                || SCRIPT_COMMANDS.contains(&name)
+                || ["PUBLISH", "SPUBLISH"].contains(&name)
+        };
+        match (name, &self.client.multi) {
+            // EXEC is run right away, the transaction is what may write
+            ("EXEC", Some(multi)) => multi
+                .commands
+                .iter()
+                .any(|cmd| writes(&cmd.to_strings()[0].to_uppercase())),
+            _ => writes(name),
+        }
+    }
+
+    /// Reject commands that are unknown, have the wrong number of arguments
+    /// or are not allowed in the current state of the connection or server.
+    async fn check(&self, cmd: &str, name: &str, command: Option<&Command>) -> Result<()> {
+        let Some(command) = command else {
+            let args = self.args.items[1..]
+                .iter()
+                .map(|arg| format!("'{}' ", arg))
+                .collect::<String>();
+            bail!(
+                "unknown command '{}', with args beginning with: {}",
+                cmd,
+                args
+            );
+        };
+        if !command.check_arity(self.args.items.len()) {
+            bail!(
+                "wrong number of arguments for '{}' command",
+                cmd.to_lowercase()
+            );
+        }
+
+        if !command.has_flag(flags::NO_AUTH) {
+            if !self.client.authenticated && self.state.acl.read().await.requires_auth() {
+                return Err(Error::NoAuth);
+            }
+            // the master may do anything, it is the source of truth
+            if !self.client.is_master {
+                self.check_permissions(name, command).await?;
+            }
+        }
+
+        // a script holds the data lock, only commands that stop it get through
+        if !self.client.is_master
+            && !command.has_flag(flags::ALLOW_BUSY)
+            && self.state.scripts.is_busy(self.client.id)
+        {
+            return Err(Error::Busy);
+        }
+
+        if self.client.multi.is_some() && command.has_flag(flags::NO_MULTI) {
+            bail!("Command not allowed inside a transaction");
+        }
+
+        if self.client.is_subscribed() && !SUBSCRIBED_COMMANDS.contains(&name) {
+            bail!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                cmd.to_lowercase()
+            );
+        }
+
+        // the master may do anything, it is the source of truth
+        if !self.client.is_master {
+            let info = self.state.info.read().await;
+            if !command.has_flag(flags::STALE) && !info.replication.can_serve_data() {
+                return Err(Error::MasterDown);
+            }
+        }
+        if command.has_flag(flags::WRITE) {
+            self.check_writable().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reject commands, keys and channels the user of the connection may not access,
+    /// recording the denial for ACL LOG.
+    async fn check_permissions(&self, name: &str, command: &Command) -> Result<()> {
+        let args = self.args.items.as_slice();
+        // the keys of pub/sub commands are channels
+        let keys = match command.has_flag(flags::PUBSUB) {
+            true => Vec::new(),
+            false => command.keys(args)?,
+        };
+        let (read, write) = command.key_access();
+
+        let acl = self.state.acl.read().await;
+        // the user was deleted or disabled since the client authenticated
+        let Some(user) = acl.user(&self.client.user).filter(|user| user.enabled()) else {
+            return Err(Error::NoAuth);
+        };
+        let Err(denied) = user.check(name, args, &keys, read, write) else {
+            return Ok(());
+        };
+        drop(acl);
+
+        let context = match (self.in_script, self.exec_data.is_some()) {
+            (true, _) => "lua",
+            (false, true) => "multi",
+            (false, false) => "toplevel",
+        };
+        self.state.acl.write().await.log(
+            denied.reason(),
+            context,
+            denied.object(),
+            &self.client.user,
+            self.client.info(),
+        );
+        Err(Error::NoPerm(denied.message(&self.client.user)))
+    }
 
-        match cmd.to_uppercase().as_str() {
-            "PING" => self.ping(),
-            "ECHO" => self.echo(),
-            "GET" => self.get().await,
-            "SET" => self.set().await,
-            "INFO" => self.info().await,
-            "REPLCONF" => self.replconf(),
-            "PSYNC" => self.psync(),
-            _ => bail!("unknown command: {}", cmd),
+    /// Reject writes on read-only replicas and without enough good replicas.
+    async fn check_writable(&self) -> Result<()> {
+        // the master may do anything, it is the source of truth
+        if self.client.is_master {
+            return Ok(());
         }
+
+        let info = self.state.info.read().await;
+        if !info.replication.accepts_writes() {
+            return Err(Error::ReadOnly);
+        }
+        if !info.replication.has_enough_good_replicas() {
+            return Err(Error::NoReplicas);
+        }
+        Ok(())
     }
 
     fn ping(&self) -> Resp {
-        Ok(vec![RespOut::SimpleString("PONG".to_string())])
+        let message = match self.args.has_next() {
+            true => Some(self.args.next()?.clone()),
+            false => None,
+        };
+
+        // subscribers can only receive arrays
+        let res = match (self.client.is_subscribed(), message) {
+            (true, message) => RespOut::Array(vec![
+                RespOut::BulkString("pong".to_string()),
+                RespOut::BulkString(message.unwrap_or_default()),
+            ]),
+            (false, Some(message)) => RespOut::BulkString(message),
+            (false, None) => RespOut::SimpleString("PONG".to_string()),
+        };
+        Ok(vec![res])
+    }
+
+    /// AUTH [username] password
+    async fn auth(&mut self) -> Resp {
+        let args = self.rest();
+        let (user, password) = match args.as_slice() {
+            [password] => (None, password),
+            [user, password] => (Some(user.as_str()), password),
+            _ => bail!("syntax error"),
+        };
+        self.authenticate(user, password).await?;
+        Ok(vec![RespOut::SimpleString("OK".to_string())])
+    }
+
+    /// Authenticate the connection as `user`, the default user if `None`.
+    /// Failures are recorded for ACL LOG.
+    async fn authenticate(&mut self, user: Option<&str>, password: &str) -> Result<()> {
+        let mut acl = self.state.acl.write().await;
+        if user.is_none() && !acl.default_user_has_password() {
+            bail!("AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?");
+        }
+
+        let user = user.unwrap_or(DEFAULT_USER);
+        if !acl.authenticate(user, password) {
+            acl.log("auth", "toplevel", "AUTH", user, self.client.info());
+            return Err(Error::WrongPass);
+        }
+        self.client.authenticated = true;
+        self.client.user = user.to_string();
+        Ok(())
+    }
+
+    /// HELLO [protover [AUTH username password]]
+    /// Only RESP2 is spoken, so the protocol version can only be 2.
+    async fn hello(&mut self) -> Resp {
+        if self.args.has_next() {
+            let Ok(protover) = self.args.next()?.parse::<i64>() else {
+                bail!("Protocol version is not an integer or out of range");
+            };
+            if protover != 2 {
+                return Err(Error::NoProto);
+            }
+        }
+        while self.args.has_next() {
+            let option = self.args.next()?;
+            match option.to_uppercase().as_str() {
+                "AUTH" if self.args.items.len() - self.args.pos.get() >= 2 => {
+                    let user = self.args.next()?.clone();
+                    let password = self.args.next()?.clone();
+                    self.authenticate(Some(&user), &password).await?;
+                }
+                "SETNAME" if self.args.has_next() => {
+                    let name = self.args.next()?.clone();
+                    self.set_client_name(name)?;
+                }
+                _ => bail!("Syntax error in HELLO option '{}'", option),
+            }
+        }
+
+        if !self.client.authenticated && self.state.acl.read().await.requires_auth() {
+            return Err(Error::Reply("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".to_string()));
+        }
+
+        let info = self.state.info.read().await;
+        let bulk = |s: &str| RespOut::BulkString(s.to_string());
+        let role = match info.replication.role() {
+            ReplicaRole::MASTER => "master",
+            ReplicaRole::SLAVE => "replica",
+        };
+        Ok(vec![RespOut::Array(vec![
+            bulk("server"),
+            bulk("redis"),
+            bulk("version"),
+            bulk("7.2.0"),
+            bulk("proto"),
+            RespOut::Integer(2),
+            bulk("id"),
+            RespOut::Integer(self.client.id as i64),
+            bulk("mode"),
+            bulk("standalone"),
+            bulk("role"),
+            bulk(role),
+            bulk("modules"),
+            self.module_list(),
+        ])])
     }
 
     fn echo(&self) -> Resp {
@@ -89,12 +1067,15 @@ impl<'a, 'b, 'c> Handler<'a, 'b, 'c> {
     }
 
     async fn get(&self) -> Resp {
-        let data = self.data.read().await;
-
         let key = self.args.next()?.as_str();
 
+        self.expire_if_needed(key).await;
+
+        let data = self.data_read().await;
+
         let res = match data.get(key) {
-            Some(value) => RespOut::BulkString(value),
+            Some(Value::String(value)) => RespOut::BulkString(value.clone()),
+            Some(Value::Module(_)) => return Err(Error::WrongType),
             None => RespOut::Null,
         };
         Ok(vec![res])
@@ -104,31 +1085,86 @@ impl<'a, 'b, 'c> Handler<'a, 'b, 'c> {
         let key = self.args.next()?.clone();
         let value = self.args.next()?.clone();
 
-        let mut px: Option<u128> = None;
+        let mut expires_at: Option<u128> = None;
 
         while self.args.has_next() {
             let arg = self.args.next()?;
             match arg.to_uppercase().as_str() {
-                "PX" => px = Some(self.args.next()?.parse()?),
+                "PX" => expires_at = Some(now_ms() + self.args.next()?.parse::<u128>()?),
+                "EX" => expires_at = Some(now_ms() + self.args.next()?.parse::<u128>()? * 1000),
+                "PXAT" => expires_at = Some(self.args.next()?.parse()?),
+                "EXAT" => expires_at = Some(self.args.next()?.parse::<u128>()? * 1000),
                 s => bail!("Unknown argument {}", s),
             }
         }
 
-        let mut data = self.data.write().await;
+        let mut data = self.data_write().await;
+
+        data.set(key.clone(), Value::String(value.clone()), expires_at);
+
+        {
+            let pubsub = self.state.pubsub.read().await;
+            pubsub.notify_keyspace_event(notify::STRING, "set", &key);
+            if expires_at.is_some() {
+                pubsub.notify_keyspace_event(notify::GENERIC, "expire", &key);
+            }
+        }
+
+        // replicas get the absolute expire time so the key expires at the same moment everywhere
+        let mut cmd = vec!["SET".to_string(), key, value];
+        if let Some(expires_at) = expires_at {
+            cmd.push("PXAT".to_string());
+            cmd.push(expires_at.to_string());
+        }
 
-        data.set(key, value, px);
+        // propagate while holding the data lock so replicas see writes in the same order
+        self.state
+            .info
+            .write()
+            .await
+            .replication
+            .propagate(&RespIn::Array(cmd));
 
         Ok(vec![RespOut::SimpleString("OK".to_string())])
     }
 
+    async fn del(&self) -> Resp {
+        let mut keys = vec![self.args.next()?];
+        while self.args.has_next() {
+            keys.push(self.args.next()?);
+        }
+
+        for key in &keys {
+            self.expire_if_needed(key).await;
+        }
+
+        let mut data = self.data_write().await;
+
+        let pubsub = self.state.pubsub.read().await;
+        let mut count = 0;
+        for key in keys {
+            if data.del(key) {
+                pubsub.notify_keyspace_event(notify::GENERIC, "del", key);
+                count += 1;
+            }
+        }
+
+        if count > 0 {
+            self.propagate().await;
+        }
+
+        Ok(vec![RespOut::Integer(count)])
+    }
+
     async fn info(&self) -> Resp {
+        let info = self.state.info.read().await;
         let res = match self.args.has_next() {
-            false => self.info.get_all(),
+            false => info.get_all(),
             true => {
                 let mut res = Vec::new();
                 while self.args.has_next() {
                     let arg = self.args.next()?;
-                    if let Some(s) = self.info.get_section(arg.as_str()) {
+                    if let Some(s) = info.get_section(arg.as_str()) {
                         res.push(s);
                     }
                 }
@@ -136,24 +1172,1620 @@ impl<'a, 'b, 'c> Handler<'a, 'b, 'c> {
             }
         };
 
-        return Ok(vec![RespOut::BulkString(res)]);
+        Ok(vec![RespOut::BulkString(res)])
     }
 
-    fn replconf(&self) -> Resp {
+    async fn replconf(&mut self) -> Resp {
+        while self.args.has_next() {
+            let option = self.args.next()?;
+            match option.to_lowercase().as_str() {
+                "listening-port" => self.client.listening_port = Some(self.args.next()?.parse()?),
+                "ip-address" => self.client.announced_ip = Some(self.args.next()?.clone()),
+                // psync2 is assumed, every supported replica speaks it
+                "capa" => {
+                    if self.args.next()?.to_lowercase() == "eof" {
+                        self.client.capa_eof = true;
+                    }
+                }
+                "ack" => {
+                    let offset = self.args.next()?.parse()?;
+                    if let Some(id) = self.client.replica_id {
+                        self.state.info.write().await.replication.ack(id, offset);
+                    }
+                    // ACKs are never replied to
+                    return Ok(vec![]);
+                }
+                "getack" => {
+                    self.args.next()?;
+                    // only our master asks, Redis ignores anyone else as well
+                    if !self.client.is_master {
+                        return Ok(vec![]);
+                    }
+                    let offset = self
+                        .state
+                        .info
+                        .read()
+                        .await
+                        .replication
+                        .psync_state()
+                        .map_or(0, |(_, offset)| offset);
+                    self.client.force_reply = true;
+                    return Ok(vec![RespOut::Array(
+                        crate::replication::ack(offset)
+                            .into_vec()
+                            .into_iter()
+                            .map(RespOut::BulkString)
+                            .collect(),
+                    )]);
+                }
+                s => bail!("Unrecognized REPLCONF option: {}", s),
+            }
+        }
         Ok(vec![RespOut::SimpleString("OK".to_string())])
     }
 
-    fn psync(&self) -> Resp {
-        Ok(vec![
-            RespOut::SimpleString(
-                format!(
-                    "FULLRESYNC {} {}",
-                    self.info.replication.master_replid(),
-                    self.info.replication.master_repl_offset()
-                )
-                .to_string(),
-            ),
-            crate::file::construct_rdb_file(&self.data),
-        ])
+    async fn psync(&mut self) -> Resp {
+        let replid = self.args.next()?;
+        let offset: i64 = self.args.next()?.parse()?;
+
+        let mut info = self.state.info.write().await;
+        let replication = &mut info.replication;
+
+        // a replica passes on the stream of its own master, so it needs to have one
+        if !replication.can_serve_replicas() {
+            return Err(Error::NoMasterLink);
+        }
+
+        let id = replication.next_replica_id();
+        let ip = match &self.client.announced_ip {
+            Some(ip) => ip.clone(),
+            None => self
+                .client
+                .addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+        };
+        let port = self.client.listening_port.unwrap_or(0);
+        let (mut replica, stream) = ReplicaHandle::new(id, ip, port);
+
+        let res = match replication.partial_resync(replid, offset.max(0) as u64) {
+            Some(missing) => {
+                println!(
+                    "(INFO) Partial resync from offset {}, sending {} bytes",
+                    offset,
+                    missing.len()
+                );
+                replica.send(&missing);
+                vec![RespOut::SimpleString(format!(
+                    "CONTINUE {}",
+                    replication.master_replid()
+                ))]
+            }
+            None => {
+                replica.state = ReplicaState::WaitBgsave;
+                replica.capa_eof = self.client.capa_eof;
+                if replication.schedule_full_sync() {
+                    let data = self.state.data.clone();
+                    let functions = self.state.functions.clone();
+                    let info = self.state.info.clone();
+                    tokio::spawn(crate::replication::full_sync(data, functions, info));
+                }
+                // FULLRESYNC is sent along with the snapshot
+                vec![]
+            }
+        };
+
+        replication.add_replica(replica);
+        self.client.replica_id = Some(id);
+        self.client.replication_stream = Some(stream);
+
+        Ok(res)
+    }
+
+    async fn wait(&self) -> Resp {
+        let numreplicas: usize = self.args.next()?.parse()?;
+        let timeout: u64 = self.args.next()?.parse()?;
+
+        let (offset, acks) = {
+            let mut info = self.state.info.write().await;
+            let replication = &mut info.replication;
+            if replication.role() != ReplicaRole::MASTER {
+                bail!("WAIT cannot be used with replica instances");
+            }
+            let offset = *replication.master_repl_offset();
+            // EXEC holds the data lock, which replicas need to catch up, so it can not block
+            if self.exec_data.is_some() {
+                return Ok(vec![RespOut::Integer(replication.num_acked(offset) as i64)]);
+            }
+            if replication.num_acked(offset) < numreplicas {
+                replication.propagate(&crate::replication::getack());
+            }
+            (offset, replication.acks())
+        };
+
+        // a timeout of 0 blocks forever
+        let deadline = (timeout > 0).then(|| time::Instant::now() + Duration::from_millis(timeout));
+
+        loop {
+            let notified = acks.notified();
+            tokio::pin!(notified);
+            // register before checking so an ACK in between is not missed
+            notified.as_mut().enable();
+
+            let acked = self.state.info.read().await.replication.num_acked(offset);
+            if acked >= numreplicas {
+                return Ok(vec![RespOut::Integer(acked as i64)]);
+            }
+
+            match deadline {
+                Some(deadline) => {
+                    if time::timeout_at(deadline, notified).await.is_err() {
+                        let acked = self.state.info.read().await.replication.num_acked(offset);
+                        return Ok(vec![RespOut::Integer(acked as i64)]);
+                    }
+                }
+                None => notified.await,
+            }
+        }
     }
+
+    async fn replicaof(&self) -> Resp {
+        let host = self.args.next()?.clone();
+        let port = self.args.next()?;
+
+        let new_master = match (host.to_uppercase().as_str(), port.to_uppercase().as_str()) {
+            ("NO", "ONE") => None,
+            _ => Some((host, port.parse::<u16>()?)),
+        };
+
+        let link = {
+            let mut info = self.state.info.write().await;
+            let replication = &mut info.replication;
+            if let Some((host, port)) = &new_master {
+                if replication.role() == ReplicaRole::SLAVE
+                    && replication.master_host() == Some(host)
+                    && replication.master_port() == Some(*port)
+                {
+                    return Ok(vec![RespOut::SimpleString(
+                        "OK Already connected to specified master".to_string(),
+                    )]);
+                }
+            }
+            replication.take_link()
+        };
+
+        // let the link finish the command it is applying before changing roles
+        if let Some(link) = link {
+            link.stop().await;
+        }
+
+        // CONFIG REWRITE saves the master we follow now
+        let mut config = self.state.config.write().await;
+        config.set_master(new_master.as_ref());
+        let mut info = self.state.info.write().await;
+        match new_master {
+            None => {
+                println!("(INFO) Promoted to master");
+                info.replication.promote();
+            }
+            Some((host, port)) => {
+                println!("(INFO) Replicating from {}:{}", host, port);
+                info.replication.follow(host, port);
+                let link = MasterLink::start(self.state.clone());
+                info.replication.set_link(link);
+            }
+        }
+
+        Ok(vec![RespOut::SimpleString("OK".to_string())])
+    }
+
+    async fn role(&self) -> Resp {
+        let info = self.state.info.read().await;
+        let replication = &info.replication;
+
+        let res = match replication.role() {
+            ReplicaRole::MASTER => vec![
+                RespOut::BulkString("master".to_string()),
+                RespOut::Integer(*replication.master_repl_offset() as i64),
+                RespOut::Array(
+                    replication
+                        .replicas()
+                        .iter()
+                        .map(|replica| {
+                            RespOut::Array(vec![
+                                RespOut::BulkString(replica.ip.clone()),
+                                RespOut::BulkString(replica.port.to_string()),
+                                RespOut::BulkString(replica.ack_offset.to_string()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ],
+            ReplicaRole::SLAVE => vec![
+                RespOut::BulkString("slave".to_string()),
+                RespOut::BulkString(replication.master_host().cloned().unwrap_or_default()),
+                RespOut::Integer(replication.master_port().unwrap_or(0) as i64),
+                RespOut::BulkString(replication.link_state().to_string()),
+                RespOut::Integer(match replication.psync_state() {
+                    Some((_, offset)) => offset as i64,
+                    None => -1,
+                }),
+            ],
+        };
+
+        Ok(vec![RespOut::Array(res)])
+    }
+
+    async fn subscribe(&mut self) -> Resp {
+        let channels = self.rest_at_least_one()?;
+        let tx = self.push_sender()?;
+
+        let mut pubsub = self.state.pubsub.write().await;
+        let mut res = Vec::new();
+        for channel in channels {
+            if self.client.channels.insert(channel.clone()) {
+                pubsub.subscribe(&channel, self.client.id, tx.clone());
+            }
+            res.push(subscription_reply(
+                "subscribe",
+                Some(channel),
+                self.client.subscription_count(),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn unsubscribe(&mut self) -> Resp {
+        let mut channels = self.rest();
+        if channels.is_empty() {
+            channels = self.client.channels.iter().cloned().collect();
+        }
+
+        let mut pubsub = self.state.pubsub.write().await;
+        let mut res = Vec::new();
+        for channel in channels {
+            if self.client.channels.remove(&channel) {
+                pubsub.unsubscribe(&channel, self.client.id);
+            }
+            res.push(subscription_reply(
+                "unsubscribe",
+                Some(channel),
+                self.client.subscription_count(),
+            ));
+        }
+        if res.is_empty() {
+            res.push(subscription_reply(
+                "unsubscribe",
+                None,
+                self.client.subscription_count(),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn psubscribe(&mut self) -> Resp {
+        let patterns = self.rest_at_least_one()?;
+        let tx = self.push_sender()?;
+
+        let mut pubsub = self.state.pubsub.write().await;
+        let mut res = Vec::new();
+        for pattern in patterns {
+            if self.client.patterns.insert(pattern.clone()) {
+                pubsub.psubscribe(&pattern, self.client.id, tx.clone());
+            }
+            res.push(subscription_reply(
+                "psubscribe",
+                Some(pattern),
+                self.client.subscription_count(),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn punsubscribe(&mut self) -> Resp {
+        let mut patterns = self.rest();
+        if patterns.is_empty() {
+            patterns = self.client.patterns.iter().cloned().collect();
+        }
+
+        let mut pubsub = self.state.pubsub.write().await;
+        let mut res = Vec::new();
+        for pattern in patterns {
+            if self.client.patterns.remove(&pattern) {
+                pubsub.punsubscribe(&pattern, self.client.id);
+            }
+            res.push(subscription_reply(
+                "punsubscribe",
+                Some(pattern),
+                self.client.subscription_count(),
+            ));
+        }
+        if res.is_empty() {
+            res.push(subscription_reply(
+                "punsubscribe",
+                None,
+                self.client.subscription_count(),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn publish(&self) -> Resp {
+        let channel = self.args.next()?;
+        let message = self.args.next()?;
+
+        let receivers = self.state.pubsub.read().await.publish(channel, message);
+
+        // subscribers on replicas get the message as well
+        self.propagate().await;
+
+        Ok(vec![RespOut::Integer(receivers as i64)])
+    }
+
+    async fn ssubscribe(&mut self) -> Resp {
+        // without cluster mode every slot is served here, so channels need not share one
+        let channels = self.rest_at_least_one()?;
+        let tx = self.push_sender()?;
+
+        let mut pubsub = self.state.pubsub.write().await;
+        let mut res = Vec::new();
+        for channel in channels {
+            if self.client.shard_channels.insert(channel.clone()) {
+                pubsub.ssubscribe(&channel, self.client.id, tx.clone());
+            }
+            res.push(subscription_reply(
+                "ssubscribe",
+                Some(channel),
+                self.client.shard_subscription_count(),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn sunsubscribe(&mut self) -> Resp {
+        let mut channels = self.rest();
+        if channels.is_empty() {
+            channels = self.client.shard_channels.iter().cloned().collect();
+        }
+
+        let mut pubsub = self.state.pubsub.write().await;
+        let mut res = Vec::new();
+        for channel in channels {
+            if self.client.shard_channels.remove(&channel) {
+                pubsub.sunsubscribe(&channel, self.client.id);
+            }
+            res.push(subscription_reply(
+                "sunsubscribe",
+                Some(channel),
+                self.client.shard_subscription_count(),
+            ));
+        }
+        if res.is_empty() {
+            res.push(subscription_reply(
+                "sunsubscribe",
+                None,
+                self.client.shard_subscription_count(),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn spublish(&self) -> Resp {
+        let channel = self.args.next()?;
+        let message = self.args.next()?;
+
+        let receivers = self.state.pubsub.read().await.spublish(channel, message);
+
+        // there is no cluster, so like PUBLISH it goes to every replica
+        self.propagate().await;
+
+        Ok(vec![RespOut::Integer(receivers as i64)])
+    }
+
+    async fn pubsub(&self) -> Resp {
+        let subcommand = self.args.next()?;
+        let pubsub = self.state.pubsub.read().await;
+
+        let res = match subcommand.to_uppercase().as_str() {
+            "CHANNELS" => {
+                let pattern = match self.args.has_next() {
+                    true => Some(self.args.next()?.as_str()),
+                    false => None,
+                };
+                RespOut::Array(
+                    pubsub
+                        .channels(pattern)
+                        .into_iter()
+                        .map(RespOut::BulkString)
+                        .collect(),
+                )
+            }
+            "NUMSUB" => {
+                let mut res = Vec::new();
+                for channel in self.rest() {
+                    let count = pubsub.numsub(&channel);
+                    res.push(RespOut::BulkString(channel));
+                    res.push(RespOut::Integer(count as i64));
+                }
+                RespOut::Array(res)
+            }
+            "NUMPAT" => RespOut::Integer(pubsub.numpat() as i64),
+            "SHARDCHANNELS" => {
+                let pattern = match self.args.has_next() {
+                    true => Some(self.args.next()?.as_str()),
+                    false => None,
+                };
+                RespOut::Array(
+                    pubsub
+                        .shard_channels(pattern)
+                        .into_iter()
+                        .map(RespOut::BulkString)
+                        .collect(),
+                )
+            }
+            "SHARDNUMSUB" => {
+                let mut res = Vec::new();
+                for channel in self.rest() {
+                    let count = pubsub.shard_numsub(&channel);
+                    res.push(RespOut::BulkString(channel));
+                    res.push(RespOut::Integer(count as i64));
+                }
+                RespOut::Array(res)
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+
+        Ok(vec![res])
+    }
+
+    fn multi(&mut self) -> Resp {
+        if self.client.multi.is_some() {
+            bail!("MULTI calls can not be nested");
+        }
+        self.client.multi = Some(Multi::default());
+        Ok(vec![RespOut::SimpleString("OK".to_string())])
+    }
+
+    /// Run the queued commands while holding the data lock, so no other client sees
+    /// or touches the data in between. Nothing runs if a watched key changed.
+    async fn exec(&mut self) -> Resp {
+        let Some(multi) = self.client.multi.take() else {
+            bail!("EXEC without MULTI");
+        };
+        let watched = std::mem::take(&mut self.client.watched);
+        if multi.failed {
+            return Err(Error::ExecAbort);
+        }
+
+        let data = Arc::clone(&self.state.data).write_owned().await;
+        if watched
+            .iter()
+            .any(|(key, watched)| watched.is_modified(&*data, key))
+        {
+            return Ok(vec![RespOut::Null]);
+        }
+        let data = Mutex::new(data);
+
+        let commands = multi
+            .commands
+            .iter()
+            .map(|cmd| (cmd.to_strings(), cmd.binary()))
+            .collect::<Vec<_>>();
+
+        // replicas apply the transaction atomically as well
+        let wrap = commands.iter().any(|(args, _)| {
+            let name = args[0].to_uppercase();
+            self.state.commands.flags(&name) & flags::WRITE != 0
+                || SCRIPT_COMMANDS.contains(&name.as_str())
+        });
+        if wrap {
+            propagate_command(self.state, "MULTI").await;
+        }
+
+        let mut res = Vec::new();
+        for (args, raw) in &commands {
+            let mut handler = Handler::new(self.state, Args::with_raw(args, *raw), self.client);
+            handler.exec_data = Some(&data);
+            let reply = match Box::pin(handler.handle()).await {
+                Ok(mut replies) if replies.len() == 1 => replies.remove(0),
+                Ok(replies) => RespOut::Array(replies),
+                Err(e) => error_reply(e),
+            };
+            res.push(reply);
+        }
+
+        if wrap {
+            propagate_command(self.state, "EXEC").await;
+        }
+
+        Ok(vec![RespOut::Array(res)])
+    }
+
+    /// Run a Lua script while holding the data lock.
+    /// Replicas get the writes it made rather than the script.
+    async fn eval(&mut self, by_sha: bool, read_only: bool) -> Resp {
+        let script = self.args.next()?;
+        let body = match by_sha {
+            true => match self.state.scripts.get(script) {
+                Some(body) => body,
+                None => return Err(Error::NoScript),
+            },
+            false => {
+                self.state.scripts.load(script);
+                script.clone()
+            }
+        };
+        let (keys, argv) = self.keys_and_args()?;
+
+        self.run_script(read_only, |killed, call| {
+            scripting::run(&body, keys, argv, killed, call)
+        })
+        .await
+    }
+
+    /// Call a function of a loaded library, like a script.
+    async fn fcall(&mut self, read_only: bool) -> Resp {
+        let name = self.args.next()?.clone();
+        let (interpreter, function_read_only) = {
+            let functions = self.state.functions.read().await;
+            match functions.get(&name) {
+                Some((library, function)) => {
+                    (Arc::clone(&library.interpreter), function.is_read_only())
+                }
+                None => bail!("Function not found"),
+            }
+        };
+        if read_only && !function_read_only {
+            bail!("Can not execute a script with write flag using *_ro command.");
+        }
+        let (keys, argv) = self.keys_and_args()?;
+
+        self.run_script(read_only || function_read_only, |killed, call| {
+            scripting::run_function(&interpreter, &name, keys, argv, killed, call)
+        })
+        .await
+    }
+
+    /// The `numkeys key [key ...] arg [arg ...]` arguments of scripts.
+    fn keys_and_args(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let numkeys = match self.args.next()?.parse::<i64>() {
+            Ok(n) => n,
+            Err(_) => bail!("value is not an integer or out of range"),
+        };
+        if numkeys < 0 {
+            bail!("Number of keys can't be negative");
+        }
+        let mut argv = self.rest();
+        if numkeys as usize > argv.len() {
+            bail!("Number of keys can't be greater than number of args");
+        }
+        let keys = argv.drain(..numkeys as usize).collect();
+        Ok((keys, argv))
+    }
+
+    /// Run a script while holding the data lock, giving it a way to call commands.
+    async fn run_script(
+        &mut self,
+        read_only: bool,
+        run: impl FnOnce(Arc<AtomicBool>, &mut dyn FnMut(Vec<String>) -> RespOut) -> Result<RespOut>,
+    ) -> Resp {
+        // in a transaction the data set is already locked
+        let locked;
+        let data = match self.exec_data {
+            Some(data) => data,
+            None => {
+                locked = Mutex::new(Arc::clone(&self.state.data).write_owned().await);
+                &locked
+            }
+        };
+        let in_transaction = self.exec_data.is_some();
+
+        let state = self.state;
+        let client = &mut *self.client;
+        let mut multi_sent = false;
+
+        let killed = state.scripts.start(client.id);
+        // scripts are synchronous, so redis.call blocks this thread on the command handlers
+        let res = tokio::task::block_in_place(|| {
+            let runtime = tokio::runtime::Handle::current();
+            let mut call = |args: Vec<String>| {
+                let name = args[0].to_uppercase();
+                if state.commands.flags(&name) & flags::NOSCRIPT != 0 {
+                    return error_reply(Error::Err(
+                        "This Redis command is not allowed from script".to_string(),
+                    ));
+                }
+                if state.commands.flags(&name) & flags::WRITE != 0 {
+                    if read_only {
+                        return error_reply(Error::Err(
+                            "Write commands are not allowed from read-only scripts.".to_string(),
+                        ));
+                    }
+                    // replicas apply the writes of a script atomically as well
+                    if !in_transaction && !multi_sent {
+                        runtime.block_on(propagate_command(state, "MULTI"));
+                        multi_sent = true;
+                    }
+                    state.scripts.mark_write();
+                }
+
+                let mut handler = Handler::new(state, Args::new(&args), client);
+                handler.exec_data = Some(data);
+                handler.in_script = true;
+                match runtime.block_on(Box::pin(handler.handle())) {
+                    Ok(mut replies) if replies.len() == 1 => replies.remove(0),
+                    Ok(replies) => RespOut::Array(replies),
+                    Err(e) => error_reply(e),
+                }
+            };
+            run(killed, &mut call)
+        });
+        state.scripts.finish();
+
+        if multi_sent {
+            propagate_command(state, "EXEC").await;
+        }
+
+        Ok(vec![res?])
+    }
+
+    fn script(&self) -> Resp {
+        let subcommand = self.args.next()?;
+
+        let res = match subcommand.to_uppercase().as_str() {
+            "LOAD" => RespOut::BulkString(self.state.scripts.load(self.args.next()?)),
+            "EXISTS" => RespOut::Array(
+                self.rest_at_least_one()?
+                    .iter()
+                    .map(|sha| RespOut::Integer(self.state.scripts.exists(sha) as i64))
+                    .collect(),
+            ),
+            "FLUSH" => {
+                // scripts are dropped right away either way
+                if self.args.has_next() {
+                    match self.args.next()?.to_uppercase().as_str() {
+                        "ASYNC" | "SYNC" => {}
+                        _ => bail!("SCRIPT FLUSH only support SYNC|ASYNC option"),
+                    }
+                }
+                self.state.scripts.flush();
+                RespOut::SimpleString("OK".to_string())
+            }
+            "KILL" => {
+                self.state.scripts.kill()?;
+                RespOut::SimpleString("OK".to_string())
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+
+        Ok(vec![res])
+    }
+
+    async fn function(&self) -> Resp {
+        let subcommand = self.args.next()?.to_uppercase();
+
+        // these change the libraries, which replicas must have as well
+        if ["LOAD", "DELETE", "FLUSH", "RESTORE"].contains(&subcommand.as_str()) {
+            self.check_writable().await?;
+        }
+
+        let res = match subcommand.as_str() {
+            "LOAD" => {
+                let mut replace = false;
+                let mut code = self.args.next()?;
+                if code.to_uppercase() == "REPLACE" {
+                    replace = true;
+                    code = self.args.next()?;
+                }
+                let library = scripting::load_library(code)?;
+                let name = library.name.clone();
+                self.state.functions.write().await.load(library, replace)?;
+                self.propagate().await;
+                RespOut::BulkString(name)
+            }
+            "DELETE" => {
+                self.state
+                    .functions
+                    .write()
+                    .await
+                    .delete(self.args.next()?)?;
+                self.propagate().await;
+                RespOut::SimpleString("OK".to_string())
+            }
+            "FLUSH" => {
+                // libraries are dropped right away either way
+                if self.args.has_next() {
+                    match self.args.next()?.to_uppercase().as_str() {
+                        "ASYNC" | "SYNC" => {}
+                        _ => bail!("FUNCTION FLUSH only supports SYNC|ASYNC option"),
+                    }
+                }
+                self.state.functions.write().await.flush();
+                self.propagate().await;
+                RespOut::SimpleString("OK".to_string())
+            }
+            "LIST" => self.function_list().await?,
+            "DUMP" => RespOut::BulkBytes(file::dump_functions(&*self.state.functions.read().await)),
+            "RESTORE" => {
+                let payload = self.args.next_bytes()?;
+                let policy = match self.args.has_next() {
+                    true => self.args.next()?.to_uppercase(),
+                    false => "APPEND".to_string(),
+                };
+                let libraries = file::restore_functions(&payload)?
+                    .iter()
+                    .map(|code| Ok(scripting::load_library(code)?))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let mut functions = self.state.functions.write().await;
+                match policy.as_str() {
+                    "FLUSH" => functions.replace(libraries),
+                    "APPEND" | "REPLACE" => {
+                        let replace = policy == "REPLACE";
+                        // check everything first so a failed restore changes nothing
+                        for library in &libraries {
+                            if !replace && functions.libraries().any(|l| l.name == library.name) {
+                                bail!("Library {} already exists", library.name);
+                            }
+                            for name in library.functions.keys() {
+                                if let Some((other, _)) = functions.get(name) {
+                                    if other.name != library.name {
+                                        bail!("Function {} already exists", name);
+                                    }
+                                }
+                            }
+                        }
+                        for library in libraries {
+                            functions.load(library, replace)?;
+                        }
+                    }
+                    _ => bail!("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE."),
+                }
+                drop(functions);
+                self.propagate().await;
+                RespOut::SimpleString("OK".to_string())
+            }
+            "KILL" => {
+                self.state.scripts.kill()?;
+                RespOut::SimpleString("OK".to_string())
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+
+        Ok(vec![res])
+    }
+
+    /// FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE]
+    async fn function_list(&self) -> Result<RespOut> {
+        let mut pattern = None;
+        let mut with_code = false;
+        while self.args.has_next() {
+            match self.args.next()?.to_uppercase().as_str() {
+                "WITHCODE" => with_code = true,
+                "LIBRARYNAME" => pattern = Some(self.args.next()?.clone()),
+                s => bail!("Unknown argument {}", s),
+            }
+        }
+
+        let functions = self.state.functions.read().await;
+        let mut res = Vec::new();
+        for library in functions.libraries() {
+            if !pattern
+                .as_ref()
+                .is_none_or(|p| glob_match(p, &library.name))
+            {
+                continue;
+            }
+
+            let mut entry = vec![
+                RespOut::BulkString("library_name".to_string()),
+                RespOut::BulkString(library.name.clone()),
+                RespOut::BulkString("engine".to_string()),
+                RespOut::BulkString("LUA".to_string()),
+                RespOut::BulkString("functions".to_string()),
+                RespOut::Array(
+                    library
+                        .functions
+                        .values()
+                        .map(|function| {
+                            RespOut::Array(vec![
+                                RespOut::BulkString("name".to_string()),
+                                RespOut::BulkString(function.name.clone()),
+                                RespOut::BulkString("description".to_string()),
+                                match &function.description {
+                                    Some(description) => RespOut::BulkString(description.clone()),
+                                    None => RespOut::Null,
+                                },
+                                RespOut::BulkString("flags".to_string()),
+                                RespOut::Array(
+                                    function
+                                        .flags
+                                        .iter()
+                                        .cloned()
+                                        .map(RespOut::BulkString)
+                                        .collect(),
+                                ),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ];
+            if with_code {
+                entry.push(RespOut::BulkString("library_code".to_string()));
+                entry.push(RespOut::BulkString(library.code.clone()));
+            }
+            res.push(RespOut::Array(entry));
+        }
+
+        Ok(RespOut::Array(res))
+    }
+
+    fn discard(&mut self) -> Resp {
+        if self.client.multi.take().is_none() {
+            bail!("DISCARD without MULTI");
+        }
+        self.client.watched.clear();
+        Ok(vec![RespOut::SimpleString("OK".to_string())])
+    }
+
+    async fn watch(&mut self) -> Resp {
+        if self.client.multi.is_some() {
+            bail!("WATCH inside MULTI is not allowed");
+        }
+
+        let keys = self.rest_at_least_one()?;
+        let data = self.state.data.read().await;
+        for key in keys {
+            let watched = WatchedKey::new(&*data, &key);
+            // watching again keeps the original state
+            self.client.watched.entry(key).or_insert(watched);
+        }
+
+        Ok(vec![RespOut::SimpleString("OK".to_string())])
+    }
+
+    fn unwatch(&mut self) -> Resp {
+        self.client.watched.clear();
+        Ok(vec![RespOut::SimpleString("OK".to_string())])
+    }
+
+    /// Reset the connection to its initial state.
+    async fn reset(&mut self) -> Resp {
+        self.client.multi = None;
+        self.client.watched.clear();
+        self.state.pubsub.write().await.unsubscribe_all(self.client);
+        self.client.reply = ReplyMode::On;
+        self.client.name = None;
+        self.client.no_evict = false;
+        self.client.no_touch = false;
+        self.client.user = DEFAULT_USER.to_string();
+        self.client.authenticated = !self.state.acl.read().await.requires_auth();
+        Ok(vec![RespOut::SimpleString("RESET".to_string())])
+    }
+
+    /// Run a command added by a module while holding the data lock.
+    async fn module_command(&self, command: &dyn ModuleCommand) -> Resp {
+        let args = self.rest();
+        let mut data = self.data_write().await;
+
+        let (res, replicate, events) = {
+            let mut ctx = Context::new(&mut *data);
+            let res = command.call(&mut ctx, &args);
+            (res, ctx.should_replicate(), ctx.take_events())
+        };
+
+        {
+            let pubsub = self.state.pubsub.read().await;
+            for (event, key) in events {
+                pubsub.notify_keyspace_event(notify::MODULE, &event, &key);
+            }
+        }
+        // propagate while holding the data lock so replicas see writes in the same order
+        if replicate {
+            self.propagate().await;
+        }
+
+        Ok(vec![res?])
+    }
+
+    fn module(&self) -> Resp {
+        let subcommand = self.args.next()?;
+        match subcommand.to_uppercase().as_str() {
+            "LIST" => Ok(vec![self.module_list()]),
+            s => bail!("unknown subcommand '{}'", s),
+        }
+    }
+
+    /// The loaded modules, as in MODULE LIST and HELLO.
+    fn module_list(&self) -> RespOut {
+        let res = self
+            .state
+            .modules
+            .names()
+            .iter()
+            .map(|name| {
+                RespOut::Array(vec![
+                    RespOut::BulkString("name".to_string()),
+                    RespOut::BulkString(name.clone()),
+                    RespOut::BulkString("ver".to_string()),
+                    RespOut::Integer(1),
+                ])
+            })
+            .collect();
+        RespOut::Array(res)
+    }
+
+    /// Introspection of the command table, which client libraries use when connecting.
+    fn command(&self) -> Resp {
+        let commands = &self.state.commands;
+        if !self.args.has_next() {
+            let res = commands.sorted().into_iter().map(Command::info).collect();
+            return Ok(vec![RespOut::Array(res)]);
+        }
+
+        let subcommand = self.args.next()?;
+        let res = match subcommand.to_uppercase().as_str() {
+            "COUNT" => RespOut::Integer(commands.len() as i64),
+            "INFO" => {
+                let names = self.rest();
+                let res = match names.is_empty() {
+                    true => commands.sorted().into_iter().map(Command::info).collect(),
+                    false => names
+                        .iter()
+                        .map(|name| match commands.get(&name.to_uppercase()) {
+                            Some(command) => command.info(),
+                            None => RespOut::Null,
+                        })
+                        .collect(),
+                };
+                RespOut::Array(res)
+            }
+            "DOCS" => {
+                let names = self.rest();
+                let selected = match names.is_empty() {
+                    true => commands.sorted(),
+                    false => names
+                        .iter()
+                        .filter_map(|name| commands.get(&name.to_uppercase()))
+                        .collect(),
+                };
+                let mut res = Vec::new();
+                for command in selected {
+                    res.push(RespOut::BulkString(command.name.clone()));
+                    res.push(command.docs());
+                }
+                RespOut::Array(res)
+            }
+            "GETKEYS" => {
+                let args = self.rest_at_least_one()?;
+                let Some(command) = commands.get(&args[0].to_uppercase()) else {
+                    bail!("Invalid command specified");
+                };
+                if !command.check_arity(args.len()) {
+                    bail!("Invalid number of arguments specified for command");
+                }
+                let keys = command.keys(&args)?;
+                if keys.is_empty() {
+                    bail!("The command has no key arguments");
+                }
+                RespOut::Array(keys.into_iter().map(RespOut::BulkString).collect())
+            }
+            "LIST" => {
+                let mut selected = commands.sorted();
+                if self.args.has_next() {
+                    if !self.args.next()?.eq_ignore_ascii_case("FILTERBY") {
+                        bail!("syntax error");
+                    }
+                    let filter = self.args.next()?.to_uppercase();
+                    let value = self.args.next()?;
+                    match filter.as_str() {
+                        "MODULE" => {
+                            selected.retain(|command| command.module.as_ref() == Some(value))
+                        }
+                        "ACLCAT" => {
+                            let category = category::from_name(value).unwrap_or(0);
+                            selected.retain(|command| command.categories & category != 0)
+                        }
+                        "PATTERN" => {
+                            let pattern = value.to_lowercase();
+                            selected.retain(|command| glob_match(&pattern, &command.name))
+                        }
+                        _ => bail!("syntax error"),
+                    }
+                }
+                let res = selected
+                    .into_iter()
+                    .map(|command| RespOut::BulkString(command.name.clone()))
+                    .collect();
+                RespOut::Array(res)
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+        Ok(vec![res])
+    }
+
+    /// Users, their permissions and the log of denied commands.
+    async fn acl(&self) -> Resp {
+        let bulk = |s: &str| RespOut::BulkString(s.to_string());
+        let ok = || RespOut::SimpleString("OK".to_string());
+        let subcommand = self.args.next()?;
+        let res = match subcommand.to_uppercase().as_str() {
+            "SETUSER" => {
+                let name = self.args.next()?;
+                let rules = self.rest();
+                let mut acl = self.state.acl.write().await;
+                acl.set_user(name, &rules, &self.state.commands)?;
+                ok()
+            }
+            "GETUSER" => {
+                let acl = self.state.acl.read().await;
+                match acl.user(self.args.next()?) {
+                    Some(user) => RespOut::Array(vec![
+                        bulk("flags"),
+                        RespOut::Array(user.flags().into_iter().map(bulk).collect()),
+                        bulk("passwords"),
+                        RespOut::Array(user.passwords().iter().map(|p| bulk(p)).collect()),
+                        bulk("commands"),
+                        bulk(&user.describe_commands()),
+                        bulk("keys"),
+                        bulk(&user.describe_keys()),
+                        bulk("channels"),
+                        bulk(&user.describe_channels()),
+                        bulk("selectors"),
+                        RespOut::Array(Vec::new()),
+                    ]),
+                    None => RespOut::Null,
+                }
+            }
+            "DELUSER" => {
+                let names = self.rest_at_least_one()?;
+                let mut acl = self.state.acl.write().await;
+                let mut deleted = 0;
+                for name in &names {
+                    deleted += acl.del_user(name)? as i64;
+                }
+                drop(acl);
+                // their connections would go on as users that do not exist anymore
+                let clients = self.state.clients.read().await;
+                for listing in clients
+                    .iter()
+                    .filter(|listing| names.contains(&listing.user))
+                {
+                    clients.kill(listing.id);
+                }
+                RespOut::Integer(deleted)
+            }
+            "LIST" => {
+                let acl = self.state.acl.read().await;
+                RespOut::Array(acl.users().map(|user| bulk(&user.describe())).collect())
+            }
+            "USERS" => {
+                let acl = self.state.acl.read().await;
+                RespOut::Array(acl.users().map(|user| bulk(user.name())).collect())
+            }
+            "WHOAMI" => bulk(&self.client.user),
+            "CAT" => match self.args.has_next() {
+                false => {
+                    RespOut::Array(category::NAMES.iter().map(|(_, name)| bulk(name)).collect())
+                }
+                true => {
+                    let name = self.args.next()?;
+                    let Some(category) = category::from_name(name) else {
+                        bail!("Unknown category '{}'", name);
+                    };
+                    RespOut::Array(
+                        self.state
+                            .commands
+                            .in_categories(category)
+                            .map(|name| bulk(&name.to_lowercase()))
+                            .collect(),
+                    )
+                }
+            },
+            "GENPASS" => {
+                let bits = match self.args.has_next() {
+                    true => self.args.next()?.parse::<i64>().unwrap_or(0),
+                    false => 256,
+                };
+                if !(1..=4096).contains(&bits) {
+                    bail!("ACL GENPASS argument must be the number of bits for the output password, a positive number up to 4096");
+                }
+                let chars = (bits as usize).div_ceil(4);
+                let bytes = (0..chars.div_ceil(2))
+                    .map(|_| rand::random::<u8>())
+                    .collect::<Vec<_>>();
+                bulk(&hex_encode(&bytes)[..chars])
+            }
+            "LOG" => {
+                let count = match self.args.has_next() {
+                    false => 10,
+                    true => {
+                        let arg = self.args.next()?;
+                        if arg.eq_ignore_ascii_case("RESET") {
+                            self.state.acl.write().await.reset_log();
+                            return Ok(vec![ok()]);
+                        }
+                        match arg.parse::<usize>() {
+                            Ok(count) => count,
+                            Err(_) => bail!("value is out of range, must be positive"),
+                        }
+                    }
+                };
+                let now = now_ms();
+                let acl = self.state.acl.read().await;
+                let res = acl
+                    .log_entries()
+                    .take(count)
+                    .map(|entry| {
+                        let age = now.saturating_sub(entry.created_ms) as f64 / 1000.0;
+                        RespOut::Array(vec![
+                            bulk("count"),
+                            RespOut::Integer(entry.count as i64),
+                            bulk("reason"),
+                            bulk(entry.reason),
+                            bulk("context"),
+                            bulk(entry.context),
+                            bulk("object"),
+                            bulk(&entry.object),
+                            bulk("username"),
+                            bulk(&entry.username),
+                            bulk("age-seconds"),
+                            bulk(&format!("{:.3}", age)),
+                            bulk("client-info"),
+                            bulk(&entry.client_info),
+                            bulk("entry-id"),
+                            RespOut::Integer(entry.id as i64),
+                            bulk("timestamp-created"),
+                            RespOut::Integer(entry.created_ms as i64),
+                            bulk("timestamp-last-updated"),
+                            RespOut::Integer(entry.updated_ms as i64),
+                        ])
+                    })
+                    .collect();
+                RespOut::Array(res)
+            }
+            "DRYRUN" => {
+                let name = self.args.next()?;
+                let args = self.rest_at_least_one()?;
+                let acl = self.state.acl.read().await;
+                let Some(user) = acl.user(name) else {
+                    bail!("User '{}' not found", name);
+                };
+                let command_name = args[0].to_uppercase();
+                let Some(command) = self.state.commands.get(&command_name) else {
+                    bail!("Command '{}' not found", args[0]);
+                };
+                if !command.check_arity(args.len()) {
+                    bail!("wrong number of arguments for '{}' command", command.name);
+                }
+                let keys = match command.has_flag(flags::PUBSUB) {
+                    true => Vec::new(),
+                    false => command.keys(&args)?,
+                };
+                let (read, write) = command.key_access();
+                match user.check(&command_name, &args, &keys, read, write) {
+                    Ok(()) => ok(),
+                    Err(denied) => bulk(&denied.message(name)),
+                }
+            }
+            "LOAD" => {
+                let mut acl = self.state.acl.write().await;
+                acl.load(&self.state.commands)?;
+                ok()
+            }
+            "SAVE" => {
+                self.state.acl.read().await.save()?;
+                ok()
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+        Ok(vec![res])
+    }
+
+    async fn client_command(&mut self) -> Resp {
+        let bulk = |s: &str| RespOut::BulkString(s.to_string());
+        let ok = || RespOut::SimpleString("OK".to_string());
+        let on_off = |arg: &str| match arg.to_uppercase().as_str() {
+            "ON" => Ok(true),
+            "OFF" => Ok(false),
+            _ => bail!("syntax error"),
+        };
+        let subcommand = self.args.next()?.to_uppercase();
+        let res = match subcommand.as_str() {
+            "ID" => RespOut::Integer(self.client.id as i64),
+            "INFO" => bulk(&format!("{}\n", self.client.info())),
+            "LIST" => {
+                let mut kind = None;
+                let mut ids = None;
+                while self.args.has_next() {
+                    let option = self.args.next()?;
+                    match option.to_uppercase().as_str() {
+                        "TYPE" => {
+                            let name = self.args.next()?;
+                            match ClientType::from_name(name) {
+                                Some(k) => kind = Some(k),
+                                None => bail!("Unknown client type '{}'", name),
+                            }
+                        }
+                        "ID" => {
+                            let mut list = Vec::new();
+                            for id in self.rest_at_least_one()? {
+                                match id.parse::<u64>() {
+                                    Ok(id) if id > 0 => list.push(id),
+                                    _ => bail!("Invalid client ID"),
+                                }
+                            }
+                            ids = Some(list);
+                        }
+                        _ => bail!("syntax error"),
+                    }
+                }
+                let mut clients = self.state.clients.write().await;
+                // the registry has this client as of its previous command
+                clients.update(self.client);
+                let now = now_ms();
+                let list = clients
+                    .iter()
+                    .filter(|listing| kind.is_none_or(|kind| listing.kind == kind))
+                    .filter(|listing| ids.as_ref().is_none_or(|ids| ids.contains(&listing.id)))
+                    .map(|listing| format!("{}\n", listing.line(now)))
+                    .collect::<String>();
+                bulk(&list)
+            }
+            "SETNAME" => {
+                let name = self.args.next()?.clone();
+                if self.args.has_next() {
+                    bail!("wrong number of arguments for 'client|setname' command");
+                }
+                self.set_client_name(name)?;
+                ok()
+            }
+            "GETNAME" => match &self.client.name {
+                Some(name) => bulk(name),
+                None => RespOut::Null,
+            },
+            "SETINFO" => {
+                let attr = self.args.next()?.to_lowercase();
+                let value = self.args.next()?.clone();
+                if self.args.has_next() {
+                    bail!("wrong number of arguments for 'client|setinfo' command");
+                }
+                let field = match attr.as_str() {
+                    "lib-name" => &mut self.client.lib_name,
+                    "lib-ver" => &mut self.client.lib_ver,
+                    _ => bail!("Unrecognized option '{}'", attr),
+                };
+                if !is_valid_client_name(&value) {
+                    bail!(
+                        "{} cannot contain spaces, newlines or special characters.",
+                        attr
+                    );
+                }
+                *field = Some(value).filter(|value| !value.is_empty());
+                ok()
+            }
+            "KILL" => return self.client_kill().await,
+            "PAUSE" => {
+                let timeout = match self.args.next()?.parse::<i64>() {
+                    Ok(timeout) if timeout < 0 => bail!("timeout is negative"),
+                    Ok(timeout) => Duration::from_millis(timeout as u64),
+                    Err(_) => bail!("timeout is not an integer or out of range"),
+                };
+                let all = match self.args.has_next() {
+                    false => true,
+                    true => match self.args.next()?.to_uppercase().as_str() {
+                        "ALL" => true,
+                        "WRITE" => false,
+                        _ => bail!("syntax error"),
+                    },
+                };
+                if self.args.has_next() {
+                    bail!("syntax error");
+                }
+                let until = time::Instant::now() + timeout;
+                self.state.clients.read().await.pause(Pause { until, all });
+                ok()
+            }
+            "UNPAUSE" => {
+                self.state.clients.read().await.unpause();
+                ok()
+            }
+            // accepted for clients that set them, but nothing is evicted or touched differently,
+            // see Client::no_evict and Client::no_touch
+            "NO-EVICT" => {
+                self.client.no_evict = on_off(self.args.next()?)?;
+                ok()
+            }
+            "NO-TOUCH" => {
+                self.client.no_touch = on_off(self.args.next()?)?;
+                ok()
+            }
+            "REPLY" => {
+                self.client.reply = match self.args.next()?.to_uppercase().as_str() {
+                    "ON" => ReplyMode::On,
+                    "OFF" => ReplyMode::Off,
+                    "SKIP" => ReplyMode::SkipThis,
+                    _ => bail!("syntax error"),
+                };
+                // dropped unless replies were turned on, see Client::skip_reply
+                ok()
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+        Ok(vec![res])
+    }
+
+    /// CLIENT KILL addr, or CLIENT KILL with filters like `ID id` and `TYPE type`.
+    async fn client_kill(&mut self) -> Resp {
+        let args = self.rest_at_least_one()?;
+        let clients = self.state.clients.read().await;
+
+        // the old form kills a single client by address
+        if let [addr] = args.as_slice() {
+            let Some(listing) = clients
+                .iter()
+                .find(|listing| listing.addr.is_some_and(|a| a.to_string() == *addr))
+            else {
+                bail!("No such client");
+            };
+            clients.kill(listing.id);
+            return Ok(vec![RespOut::SimpleString("OK".to_string())]);
+        }
+
+        if args.len() % 2 != 0 {
+            bail!("syntax error");
+        }
+        let mut id = None;
+        let mut kind = None;
+        let mut user = None;
+        let mut addr = None;
+        let mut local_addr = None;
+        let mut skip_me = true;
+        let mut max_age = None;
+        for pair in args.chunks(2) {
+            let value = &pair[1];
+            match pair[0].to_uppercase().as_str() {
+                "ID" => match value.parse::<u64>() {
+                    Ok(value) if value > 0 => id = Some(value),
+                    _ => bail!("client-id should be greater than 0"),
+                },
+                "TYPE" => match ClientType::from_name(value) {
+                    Some(value) => kind = Some(value),
+                    None => bail!("Unknown client type '{}'", value),
+                },
+                "USER" => {
+                    if self.state.acl.read().await.user(value).is_none() {
+                        bail!("No such user '{}'", value);
+                    }
+                    user = Some(value);
+                }
+                "ADDR" => addr = Some(value),
+                "LADDR" => local_addr = Some(value),
+                "SKIPME" => match value.to_lowercase().as_str() {
+                    "yes" => skip_me = true,
+                    "no" => skip_me = false,
+                    _ => bail!("syntax error"),
+                },
+                "MAXAGE" => max_age = Some(value.parse::<u128>()?),
+                _ => bail!("syntax error"),
+            }
+        }
+
+        let matches_addr = |filter: Option<&String>, addr: Option<std::net::SocketAddr>| {
+            filter.is_none_or(|filter| addr.is_some_and(|addr| addr.to_string() == *filter))
+        };
+        let now = now_ms();
+        let killed = clients
+            .iter()
+            .filter(|listing| id.is_none_or(|id| listing.id == id))
+            .filter(|listing| kind.is_none_or(|kind| listing.kind == kind))
+            .filter(|listing| user.is_none_or(|user| listing.user == *user))
+            .filter(|listing| matches_addr(addr, listing.addr))
+            .filter(|listing| matches_addr(local_addr, listing.local_addr))
+            .filter(|listing| max_age.is_none_or(|max_age| listing.age(now) >= max_age))
+            .filter(|listing| !skip_me || listing.id != self.client.id)
+            .map(|listing| listing.id)
+            .collect::<Vec<_>>();
+        for id in &killed {
+            clients.kill(*id);
+        }
+        Ok(vec![RespOut::Integer(killed.len() as i64)])
+    }
+
+    /// Name the connection, or remove its name if `name` is empty.
+    fn set_client_name(&mut self, name: String) -> Result<()> {
+        if !is_valid_client_name(&name) {
+            bail!("Client names cannot contain spaces, newlines or special characters.");
+        }
+        self.client.name = Some(name).filter(|name| !name.is_empty());
+        Ok(())
+    }
+
+    async fn config(&self) -> Resp {
+        let bulk = |s: &str| RespOut::BulkString(s.to_string());
+        let ok = || RespOut::SimpleString("OK".to_string());
+        let subcommand = self.args.next()?;
+        let res = match subcommand.to_uppercase().as_str() {
+            "GET" => {
+                let patterns = self.rest_at_least_one()?;
+                let config = self.state.config.read().await;
+                let res = config
+                    .get(&patterns)
+                    .into_iter()
+                    .flat_map(|(name, value)| [bulk(name), bulk(value)])
+                    .collect();
+                RespOut::Array(res)
+            }
+            "SET" => {
+                let args = self.rest();
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    bail!("wrong number of arguments for 'config|set' command");
+                }
+                let pairs = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_lowercase(), pair[1].clone()))
+                    .collect::<Vec<_>>();
+                let mut config = self.state.config.write().await;
+                config.set(self.state, &pairs).await?;
+                ok()
+            }
+            "RESETSTAT" => {
+                self.state.info.read().await.stats.reset();
+                ok()
+            }
+            "REWRITE" => {
+                self.state.config.read().await.rewrite()?;
+                ok()
+            }
+            s => bail!("unknown subcommand '{}'", s),
+        };
+        Ok(vec![res])
+    }
+
+    /// All remaining arguments.
+    fn rest(&self) -> Vec<String> {
+        let mut res = Vec::new();
+        while let Ok(arg) = self.args.next() {
+            res.push(arg.clone());
+        }
+        res
+    }
+
+    fn rest_at_least_one(&self) -> Result<Vec<String>> {
+        let mut res = vec![self.args.next()?.clone()];
+        res.extend(self.rest());
+        Ok(res)
+    }
+
+    fn push_sender(&self) -> Result<tokio::sync::mpsc::UnboundedSender<RespOut>> {
+        match &self.client.push {
+            Some(tx) => Ok(tx.clone()),
+            None => bail!("this connection cannot receive messages"),
+        }
+    }
+
+    /// Delete keys as maxmemory-policy says until the data fits in maxmemory, and tell the replicas.
+    /// Commands that may use more memory, `denyoom`, are refused if it still does not fit.
+    async fn evict_if_needed(&self, denyoom: bool) -> Result<()> {
+        let (maxmemory, policy) = {
+            let info = self.state.info.read().await;
+            if info.server.maxmemory() == 0 || info.replication.role() != ReplicaRole::MASTER {
+                return Ok(());
+            }
+            (info.server.maxmemory(), info.server.maxmemory_policy())
+        };
+        if self.data_read().await.used_memory() <= maxmemory {
+            return Ok(());
+        }
+        // evicting writes, which CLIENT PAUSE holds back
+        let paused = self.state.clients.read().await.is_paused();
+
+        let mut data = self.data_write().await;
+        let mut evicted = Vec::new();
+        let mut res = Ok(());
+        while data.used_memory() > maxmemory {
+            let Some(key) = data.eviction_candidate(policy).filter(|_| !paused) else {
+                if denyoom {
+                    res = Err(Error::Oom);
+                }
+                break;
+            };
+            data.del(&key);
+            evicted.push(key);
+        }
+        if evicted.is_empty() {
+            return res;
+        }
+
+        let pubsub = self.state.pubsub.read().await;
+        for key in &evicted {
+            pubsub.notify_keyspace_event(notify::EVICTED, "evicted", key);
+        }
+        // propagate while holding the data lock so replicas see deletes in order
+        let mut info = self.state.info.write().await;
+        for key in evicted {
+            Stats::incr(&info.stats.evicted_keys);
+            info.replication.propagate(&crate::replication::del(key));
+        }
+        res
+    }
+
+    /// Delete `key` on the master if it has expired and tell the replicas.
+    /// Replicas never delete keys by themselves, they hide expired keys
+    /// from reads and wait for the DEL from their master.
+    async fn expire_if_needed(&self, key: &str) {
+        if !self.data_read().await.is_expired(key) {
+            return;
+        }
+        if self.state.info.read().await.replication.role() != ReplicaRole::MASTER {
+            return;
+        }
+        // deleting would write while clients are paused, reads hide the key anyway
+        if self.state.clients.read().await.is_paused() {
+            return;
+        }
+
+        let mut data = self.data_write().await;
+        // it may have been deleted while we did not hold the lock
+        if data.is_expired(key) {
+            data.del(key);
+            self.state
+                .pubsub
+                .read()
+                .await
+                .notify_keyspace_event(notify::EXPIRED, "expired", key);
+            self.state
+                .info
+                .write()
+                .await
+                .replication
+                .propagate(&crate::replication::del(key.to_string()));
+        }
+    }
+
+    /// Send the current command to the replicas.
+    async fn propagate(&self) {
+        let cmd = self.request();
+        self.state.info.write().await.replication.propagate(&cmd);
+    }
+
+    /// The current command as received.
+    fn request(&self) -> RespIn {
+        match self.args.raw {
+            Some(raw) => RespIn::Binary(raw.clone()),
+            None => RespIn::Array(self.args.items.clone()),
+        }
+    }
+
+    async fn data_read(&self) -> DataGuard<'_, RwLockReadGuard<'_, DynData>> {
+        match self.exec_data {
+            Some(data) => DataGuard::Exec(data.lock().await),
+            None => DataGuard::Shared(self.state.data.read().await),
+        }
+    }
+
+    async fn data_write(&self) -> DataGuard<'_, RwLockWriteGuard<'_, DynData>> {
+        match self.exec_data {
+            Some(data) => DataGuard::Exec(data.lock().await),
+            None => DataGuard::Shared(self.state.data.write().await),
+        }
+    }
+}
+
+/// Send a command without arguments to the replicas.
+async fn propagate_command(state: &State, name: &str) {
+    let cmd = RespIn::Array(vec![name.to_string()]);
+    state.info.write().await.replication.propagate(&cmd);
+}
+
+/// Reply to (P|S)SUBSCRIBE and (P|S)UNSUBSCRIBE for a single channel or pattern.
+fn subscription_reply(kind: &str, name: Option<String>, count: usize) -> RespOut {
+    RespOut::Array(vec![
+        RespOut::BulkString(kind.to_string()),
+        match name {
+            Some(name) => RespOut::BulkString(name),
+            None => RespOut::Null,
+        },
+        RespOut::Integer(count as i64),
+    ])
 }
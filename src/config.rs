@@ -0,0 +1,680 @@
+use crate::acl::Acl;
+use crate::info::Info;
+use crate::notify;
+use crate::pubsub::PubSub;
+use crate::scripting::Scripts;
+use crate::state::State;
+use crate::utils::glob_match;
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use clap::error::ErrorKind;
+use clap::{ArgMatches, CommandFactory, Parser, ValueEnum};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+// Reading redis.conf: one `directive arg [arg ...]` per line, `#` comments,
+// quoted arguments and `include` of other files.
+// See https://redis.io/docs/management/config-file/
+//
+// Every command line option is a setting of CONFIG GET, with the same name as the directive.
+// Settings in `MUTABLE` can be changed at runtime with CONFIG SET.
+
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+/// Deepest nesting of `include` directives, so that a file including itself fails.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+pub struct Directive {
+    /// Lower case.
+    pub name: String,
+    pub args: Vec<String>,
+    /// Where it was read, like `redis.conf:12`.
+    pub location: String,
+}
+
+/// The directives of a config file and the files it includes, in order.
+pub fn read(path: &Path) -> Result<Vec<Directive>> {
+    let mut res = Vec::new();
+    read_into(path, 0, &mut res)?;
+    Ok(res)
+}
+
+fn read_into(path: &Path, depth: usize, res: &mut Vec<Directive>) -> Result<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        bail!("Too many nested includes at {}", path.display());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Fatal error, can't open config file '{}'", path.display()))?;
+
+    for (i, line) in content.lines().enumerate() {
+        let location = format!("{}:{}", path.display(), i + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some(mut args) = split_args(line) else {
+            bail!("{}: Unbalanced quotes in configuration line", location);
+        };
+        let name = args.remove(0).to_lowercase();
+        if name == "include" {
+            let [file] = args.as_slice() else {
+                bail!("{}: include takes a single file", location);
+            };
+            read_into(Path::new(file), depth + 1, res)?;
+            continue;
+        }
+        res.push(Directive {
+            name,
+            args,
+            location,
+        });
+    }
+    Ok(())
+}
+
+/// Split a line into arguments like Redis does: separated by spaces, either bare or quoted.
+/// Double quotes support escapes like `\n` and `\x41`, single quotes only `\'`.
+/// `None` if quotes are unbalanced or a closing quote is not followed by a space.
+pub fn split_args(line: &str) -> Option<Vec<String>> {
+    let mut res = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Some(res);
+        };
+
+        let mut arg = String::new();
+        match first {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => arg.push('\n'),
+                            'r' => arg.push('\r'),
+                            't' => arg.push('\t'),
+                            'b' => arg.push('\u{8}'),
+                            'a' => arg.push('\u{7}'),
+                            'x' => {
+                                let hex = [chars.next()?, chars.next()?].iter().collect::<String>();
+                                match u8::from_str_radix(&hex, 16) {
+                                    Ok(byte) => arg.push(byte as char),
+                                    // not an escape after all
+                                    Err(_) => arg.push_str(&format!("x{}", hex)),
+                                }
+                            }
+                            c => arg.push(c),
+                        },
+                        c => arg.push(c),
+                    }
+                }
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        '\\' if chars.peek() == Some(&'\'') => arg.push(chars.next()?),
+                        c => arg.push(c),
+                    }
+                }
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            }
+        }
+        // a quoted argument must be followed by a space or the end of the line
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            return None;
+        }
+        res.push(arg);
+    }
+}
+
+/// Parse the `<host> <port>` of the master to replicate from.
+pub fn parse_master(s: &str) -> Result<(String, u16)> {
+    let mut parts = s.split_whitespace();
+    let (Some(host), Some(port), None) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("expected '<host> <port>', got '{}'", s);
+    };
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("invalid port '{}'", port))?;
+    Ok((host.to_string(), port))
+}
+
+/// Parse a memory size with an optional unit, like `1gb`: k, m and g are powers of 1000,
+/// kb, mb and gb powers of 1024.
+pub fn parse_memory(s: &str) -> Result<usize> {
+    let lower = s.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => bail!("invalid memory unit in '{}'", s),
+    };
+    let number = number
+        .parse::<usize>()
+        .with_context(|| format!("invalid memory size '{}'", s))?;
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("memory size '{}' is too large", s))
+}
+
+/// Settings that CONFIG SET can change, see [`apply`].
+const MUTABLE: &[&str] = &[
+    "requirepass",
+    "masteruser",
+    "masterauth",
+    "protected-mode",
+    "dir",
+    "dbfilename",
+    "repl-backlog-size",
+    "replica-serve-stale-data",
+    "replica-read-only",
+    "repl-diskless-sync",
+    "repl-diskless-sync-delay",
+    "repl-diskless-load",
+    "repl-timeout",
+    "repl-ping-replica-period",
+    "min-replicas-to-write",
+    "min-replicas-max-lag",
+    "notify-keyspace-events",
+    "busy-reply-threshold",
+    "maxmemory",
+    "maxmemory-policy",
+];
+
+/// Settings whose value is several arguments, written without quotes by CONFIG REWRITE.
+const MULTI_ARG: &[&str] = &["bind", "replicaof"];
+
+/// Options that are not settings: given several times, or only on the command line.
+const NOT_SETTINGS: &[&str] = &["help", "version", "loadmodule"];
+
+/// Marks the settings CONFIG REWRITE appends to the config file.
+const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+/// The option setting `name` to `value` on the command line.
+pub fn option(name: &str, value: &str) -> String {
+    // `--name=value` also takes values starting with `-`
+    format!("--{}={}", name, value)
+}
+
+/// Parse a setting like the command line option, to check the value and get it typed.
+/// The error is the reason it is invalid.
+pub fn parse_setting(name: &str, value: &str) -> std::result::Result<Args, String> {
+    Args::try_parse_from(["redis", &option(name, value)]).map_err(|e| match e.kind() {
+        ErrorKind::UnknownArgument => "Bad directive or wrong number of arguments".to_string(),
+        _ => e
+            .to_string()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches("error: ")
+            .to_string(),
+    })
+}
+
+/// The current value of each setting, as it would be written in redis.conf.
+pub struct Config {
+    values: BTreeMap<String, String>,
+    defaults: BTreeMap<String, String>,
+    /// The config file the server was started with, see CONFIG REWRITE.
+    file: Option<PathBuf>,
+}
+
+impl Config {
+    /// The settings as given on the command line and in the config file.
+    pub fn new(matches: &ArgMatches, file: Option<PathBuf>) -> Self {
+        let mut values = BTreeMap::new();
+        let mut defaults = BTreeMap::new();
+        for arg in Args::command().get_arguments() {
+            let Some(name) = arg.get_long().filter(|name| !NOT_SETTINGS.contains(name)) else {
+                continue;
+            };
+            let join = |values: Vec<String>| values.join(" ");
+            let value = matches
+                .get_raw(arg.get_id().as_str())
+                .map(|raw| join(raw.map(|v| v.to_string_lossy().into_owned()).collect()));
+            let default = join(
+                arg.get_default_values()
+                    .iter()
+                    .map(|v| v.to_string_lossy().into_owned())
+                    .collect(),
+            );
+            let value = canonical(name, value.as_deref().unwrap_or(&default));
+            values.insert(name.to_string(), value);
+            defaults.insert(name.to_string(), canonical(name, &default));
+        }
+        Self {
+            values,
+            defaults,
+            file,
+        }
+    }
+
+    /// The settings with a name matching any of `patterns`, ordered by name.
+    pub fn get(&self, patterns: &[String]) -> Vec<(&str, &str)> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| pattern.to_lowercase())
+            .collect::<Vec<_>>();
+        self.values
+            .iter()
+            .filter(|(name, _)| patterns.iter().any(|pattern| glob_match(pattern, name)))
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    fn value(&self, name: &str) -> &str {
+        self.values.get(name).map_or("", String::as_str)
+    }
+
+    /// Change settings at runtime, all or none: `pairs` of lower case names and values
+    /// are all checked before any is applied.
+    pub async fn set(&mut self, state: &State, pairs: &[(String, String)]) -> Result<()> {
+        let failed = |name: &str, reason| {
+            anyhow::anyhow!(
+                "CONFIG SET failed (possibly related to argument '{}') - {}",
+                name,
+                reason
+            )
+        };
+
+        let mut seen = HashSet::new();
+        let mut parsed = Vec::new();
+        for (name, value) in pairs {
+            if !self.values.contains_key(name) {
+                bail!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                );
+            }
+            if !MUTABLE.contains(&name.as_str()) {
+                return Err(failed(name, "can't set immutable config".to_string()));
+            }
+            if !seen.insert(name) {
+                return Err(failed(name, "duplicate parameter".to_string()));
+            }
+            let args = parse_setting(name, value).map_err(|reason| failed(name, reason))?;
+            validate(name, &args).map_err(|e| failed(name, e.to_string()))?;
+            parsed.push((name, value, args));
+        }
+
+        // dir and dbfilename make up one path, which takes the new value of either
+        let pending = |setting: &str| {
+            parsed
+                .iter()
+                .find(|(name, _, _)| *name == setting)
+                .map_or(self.value(setting), |(_, value, _)| value.as_str())
+        };
+        let rdb_path = Path::new(pending("dir")).join(pending("dbfilename"));
+
+        // in the order the other tasks take them
+        let mut pubsub = state.pubsub.write().await;
+        let mut info = state.info.write().await;
+        let mut acl = state.acl.write().await;
+        for (name, _, args) in &parsed {
+            match name.as_str() {
+                "dir" | "dbfilename" => info.server.set_rdb_path(rdb_path.clone()),
+                _ => apply(name, args, &mut pubsub, &mut info, &mut acl, &state.scripts),
+            }
+        }
+        drop((pubsub, info, acl));
+
+        for (name, _, args) in parsed {
+            self.values
+                .insert(name.clone(), format_setting(name, &args));
+        }
+        Ok(())
+    }
+
+    /// Record the master REPLICAOF switched to, or that it made us a master with `None`.
+    pub fn set_master(&mut self, master: Option<&(String, u16)>) {
+        let value = master.map_or(String::new(), |(host, port)| format!("{} {}", host, port));
+        self.values.insert("replicaof".to_string(), value);
+    }
+
+    /// Whether `name` is an optional setting that is not set, which has no line in the file.
+    fn is_unset(&self, name: &str) -> bool {
+        self.value(name).is_empty() && self.defaults.get(name).is_some_and(String::is_empty)
+    }
+
+    /// Write the current settings to the config file, keeping its comments and order:
+    /// lines of settings get the current value, settings not in the file yet are appended
+    /// if they differ from the default.
+    pub fn rewrite(&self) -> Result<()> {
+        let Some(path) = &self.file else {
+            bail!("The server is running without a config file");
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Rewriting config file: {}", path.display()))?;
+
+        let mut lines = Vec::new();
+        let mut written = HashSet::new();
+        for line in content.lines() {
+            let name = split_args(line.trim())
+                .and_then(|args| args.into_iter().next())
+                .map(|name| name.to_lowercase())
+                .filter(|name| !line.trim().starts_with('#') && self.values.contains_key(name));
+            match name {
+                // only the first line of a setting is kept
+                Some(name) if written.insert(name.clone()) => {
+                    if !self.is_unset(&name) {
+                        lines.push(self.directive(&name));
+                    }
+                }
+                Some(_) => {}
+                None => lines.push(line.to_string()),
+            }
+        }
+
+        let missing = self
+            .values
+            .iter()
+            .filter(|(name, value)| {
+                !written.contains(*name) && self.defaults.get(*name) != Some(value)
+            })
+            .map(|(name, _)| self.directive(name))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() && !lines.iter().any(|line| line == REWRITE_SIGNATURE) {
+            lines.push(REWRITE_SIGNATURE.to_string());
+        }
+        lines.extend(missing);
+
+        // never leave a half written file behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, lines.join("\n") + "\n")?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The line setting `name` to its current value.
+    fn directive(&self, name: &str) -> String {
+        let value = self.value(name);
+        match MULTI_ARG.contains(&name) {
+            true => format!("{} {}", name, value),
+            false => format!("{} {}", name, quote(value)),
+        }
+    }
+}
+
+/// Quote an argument if it would not read back as it is.
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && !arg
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '\\'));
+    if plain {
+        return arg.to_string();
+    }
+    let mut res = String::from('"');
+    for c in arg.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\x{:02x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// `value` of the setting `name` as [`format_setting`] shows it, empty if it is not set.
+fn canonical(name: &str, value: &str) -> String {
+    if value.is_empty() {
+        return String::new();
+    }
+    // values that made it here were checked when they were given
+    parse_setting(name, value)
+        .map_or_else(|_| value.to_string(), |args| format_setting(name, &args))
+}
+
+/// The value of the setting `name` in `args` as CONFIG GET and CONFIG REWRITE show it,
+/// whichever way it was given: memory sizes in bytes, booleans as yes or no,
+/// enums and event classes by their canonical names.
+fn format_setting(name: &str, args: &Args) -> String {
+    let yes_no = |value: bool| if value { "yes" } else { "no" }.to_string();
+    let path = |value: &Option<PathBuf>| {
+        value
+            .as_ref()
+            .map_or(String::new(), |path| path.display().to_string())
+    };
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    match name {
+        "port" => args.port.to_string(),
+        "bind" => args.bind.split_whitespace().collect::<Vec<_>>().join(" "),
+        "protected-mode" => yes_no(args.protected_mode),
+        "unixsocket" => path(&args.unixsocket),
+        "unixsocketperm" => args
+            .unixsocketperm
+            .map_or(String::new(), |perm| format!("{:o}", perm)),
+        "tls-port" => args.tls_port.map_or(String::new(), |port| port.to_string()),
+        "tls-cert-file" => path(&args.tls_cert_file),
+        "tls-key-file" => path(&args.tls_key_file),
+        "tls-ca-cert-file" => path(&args.tls_ca_cert_file),
+        "tls-auth-clients" => value_name(&args.tls_auth_clients),
+        "tls-replication" => yes_no(args.tls_replication),
+        "replicaof" => args
+            .replicaof
+            .as_ref()
+            .map_or(String::new(), |(host, port)| format!("{} {}", host, port)),
+        "repl-backlog-size" => args.repl_backlog_size.to_string(),
+        "replica-serve-stale-data" => yes_no(args.replica_serve_stale_data),
+        "replica-read-only" => yes_no(args.replica_read_only),
+        "repl-diskless-sync" => yes_no(args.repl_diskless_sync),
+        "repl-diskless-sync-delay" => args.repl_diskless_sync_delay.to_string(),
+        "repl-diskless-load" => value_name(&args.repl_diskless_load),
+        "repl-timeout" => args.repl_timeout.to_string(),
+        "repl-ping-replica-period" => args.repl_ping_replica_period.to_string(),
+        "min-replicas-to-write" => args.min_replicas_to_write.to_string(),
+        "min-replicas-max-lag" => args.min_replicas_max_lag.to_string(),
+        "requirepass" => text(&args.requirepass),
+        "aclfile" => path(&args.aclfile),
+        "masteruser" => text(&args.masteruser),
+        "masterauth" => text(&args.masterauth),
+        "notify-keyspace-events" => notify::parse_flags(&args.notify_keyspace_events).map_or_else(
+            |_| args.notify_keyspace_events.clone(),
+            notify::flags_to_string,
+        ),
+        "maxmemory" => args.maxmemory.to_string(),
+        "maxmemory-policy" => value_name(&args.maxmemory_policy),
+        "busy-reply-threshold" => args.busy_reply_threshold.to_string(),
+        "dir" => args.dir.display().to_string(),
+        "dbfilename" => args.dbfilename.clone(),
+        _ => unreachable!("{} is not a setting", name),
+    }
+}
+
+/// The name an enum value is given by on the command line.
+fn value_name(value: &impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or(String::new(), |value| value.get_name().to_string())
+}
+
+/// Check what `apply` can not take, which the parsing of `args` lets through.
+fn validate(name: &str, args: &Args) -> Result<()> {
+    match name {
+        "dir" if !args.dir.is_dir() => bail!("No such file or directory"),
+        "dbfilename" if args.dbfilename.contains('/') => {
+            bail!("dbfilename can't be a path, just a filename")
+        }
+        "notify-keyspace-events" => {
+            notify::parse_flags(&args.notify_keyspace_events)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Apply the value of the setting `name` in `args`, checked by `validate`, to the running server.
+/// dir and dbfilename are applied together by the caller.
+fn apply(
+    name: &str,
+    args: &Args,
+    pubsub: &mut PubSub,
+    info: &mut Info,
+    acl: &mut Acl,
+    scripts: &Scripts,
+) {
+    let non_empty = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+    let replication = &mut info.replication.config;
+    match name {
+        "requirepass" => acl.set_requirepass(args.requirepass.as_deref()),
+        "masteruser" => replication.masteruser = non_empty(&args.masteruser),
+        "masterauth" => replication.masterauth = non_empty(&args.masterauth),
+        "protected-mode" => info.server.set_protected_mode(args.protected_mode),
+        "repl-backlog-size" => info.replication.set_backlog_size(args.repl_backlog_size),
+        "replica-serve-stale-data" => replication.serve_stale_data = args.replica_serve_stale_data,
+        "replica-read-only" => replication.read_only = args.replica_read_only,
+        "repl-diskless-sync" => replication.diskless_sync = args.repl_diskless_sync,
+        "repl-diskless-sync-delay" => {
+            replication.diskless_sync_delay = args.repl_diskless_sync_delay
+        }
+        "repl-diskless-load" => replication.diskless_load = args.repl_diskless_load,
+        "repl-timeout" => replication.timeout = Duration::from_secs(args.repl_timeout),
+        "repl-ping-replica-period" => {
+            replication.ping_replica_period = Duration::from_secs(args.repl_ping_replica_period)
+        }
+        "min-replicas-to-write" => replication.min_replicas_to_write = args.min_replicas_to_write,
+        "min-replicas-max-lag" => replication.min_replicas_max_lag = args.min_replicas_max_lag,
+        "notify-keyspace-events" => pubsub.set_notify_flags(
+            notify::parse_flags(&args.notify_keyspace_events).expect("checked by validate"),
+        ),
+        "maxmemory" => info.server.set_maxmemory(args.maxmemory),
+        "maxmemory-policy" => info.server.set_maxmemory_policy(args.maxmemory_policy),
+        "busy-reply-threshold" => {
+            scripts.set_busy_reply_threshold(Duration::from_millis(args.busy_reply_threshold))
+        }
+        _ => unreachable!("{} is not in MUTABLE", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        split_args(line).expect("balanced quotes")
+    }
+
+    #[test]
+    fn split_args_bare_and_quoted() {
+        assert_eq!(args("  set  key   value "), ["set", "key", "value"]);
+        assert_eq!(args(r#"set "a b" 'c d'"#), ["set", "a b", "c d"]);
+        assert_eq!(args(r#""line\nbreak" "\x41\x4a""#), ["line\nbreak", "AJ"]);
+        assert_eq!(args(r"'it\'s' 'a\nb'"), ["it's", "a\\nb"]);
+        assert_eq!(args(r#""""#), [""]);
+        assert!(args("").is_empty());
+    }
+
+    #[test]
+    fn split_args_rejects_unbalanced_quotes() {
+        assert_eq!(split_args(r#"set "key"#), None);
+        assert_eq!(split_args("set 'key"), None);
+        // a closing quote must end the argument
+        assert_eq!(split_args(r#""key"value"#), None);
+    }
+
+    #[test]
+    fn parse_memory_units() {
+        assert_eq!(parse_memory("0").unwrap(), 0);
+        assert_eq!(parse_memory("100").unwrap(), 100);
+        assert_eq!(parse_memory("100b").unwrap(), 100);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("1kb").unwrap(), 1024);
+        assert_eq!(parse_memory("2m").unwrap(), 2_000_000);
+        assert_eq!(parse_memory("2MB").unwrap(), 2 * 1024 * 1024);
+        assert_eq!(parse_memory("1g").unwrap(), 1_000_000_000);
+        assert_eq!(parse_memory("1Gb").unwrap(), 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    fn parse_memory_rejects_bad_sizes() {
+        assert!(parse_memory("").is_err());
+        assert!(parse_memory("mb").is_err());
+        assert!(parse_memory("1tb").is_err());
+        assert!(parse_memory("-1").is_err());
+        assert!(parse_memory("99999999999999999999gb").is_err());
+    }
+
+    #[test]
+    fn canonical_values() {
+        assert_eq!(canonical("maxmemory", "1gb"), "1073741824");
+        assert_eq!(canonical("protected-mode", "true"), "yes");
+        assert_eq!(canonical("replica-read-only", "0"), "no");
+        assert_eq!(
+            canonical("maxmemory-policy", "ALLKEYS-RANDOM"),
+            "allkeys-random"
+        );
+        assert_eq!(canonical("notify-keyspace-events", "KEl"), "lKE");
+        assert_eq!(canonical("replicaof", "localhost   6380"), "localhost 6380");
+        assert_eq!(canonical("requirepass", ""), "");
+    }
+
+    #[test]
+    fn rewrite_keeps_comments_and_updates_settings() {
+        let path = std::env::temp_dir().join(format!("rewrite-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            "# a comment\nport 7000\nmaxmemory 1gb\nmaxmemory 2gb\nreplicaof 10.0.0.1 6379\n",
+        )
+        .unwrap();
+        let matches = Args::command().get_matches_from([
+            "redis",
+            "--port=7000",
+            "--maxmemory=2gb",
+            "--replicaof=10.0.0.1 6379",
+            "--requirepass=a b",
+        ]);
+        let mut config = Config::new(&matches, Some(path.clone()));
+
+        config.set_master(None);
+        config.rewrite().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# a comment\nport 7000\nmaxmemory 2147483648\n\
+             # Generated by CONFIG REWRITE\nrequirepass \"a b\"\n"
+        );
+
+        config.set_master(Some(&("10.0.0.2".to_string(), 6380)));
+        config.rewrite().unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(content.ends_with("requirepass \"a b\"\nreplicaof 10.0.0.2 6380\n"));
+        // the file reads back to the same settings
+        let directives = content
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(args)
+            .collect::<Vec<_>>();
+        assert!(directives.contains(&vec!["requirepass".to_string(), "a b".to_string()]));
+        assert_eq!(
+            directives
+                .iter()
+                .filter(|args| args[0] == "replicaof")
+                .count(),
+            1
+        );
+    }
+}
@@ -1,72 +1,233 @@
+use crate::module::ModuleValue;
+use crate::utils::now_ms;
+use rand::seq::IteratorRandom;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Instant;
 use tokio::sync::RwLock;
 
 pub type SharedData = Arc<RwLock<dyn Data + Send + Sync>>;
 
-#[derive(Clone)]
+pub enum Value {
+    String(String),
+    /// A value of a type added by a module.
+    Module(Box<dyn ModuleValue>),
+}
+
+impl Value {
+    /// Bytes taken by the value, roughly.
+    fn memory_usage(&self) -> usize {
+        match self {
+            Value::String(s) => s.len(),
+            Value::Module(value) => value.memory_usage(),
+        }
+    }
+}
+
+/// Which keys are deleted to stay within maxmemory.
+#[derive(clap::ValueEnum, PartialEq, Clone, Copy, Default)]
+pub enum EvictionPolicy {
+    /// Nothing is deleted, commands that use more memory are refused instead.
+    #[default]
+    Noeviction,
+    AllkeysRandom,
+    /// Random keys with an expiry.
+    VolatileRandom,
+    /// The keys with an expiry that expire first.
+    VolatileTtl,
+}
+
+/// Memory taken by a key on top of its name and value, roughly what Redis needs for it.
+const KEY_OVERHEAD: usize = 48;
+
+fn item_size(key: &str, value: &Value) -> usize {
+    KEY_OVERHEAD + key.len() + value.memory_usage()
+}
+
 pub struct DataItem {
-    value: String,
-    created_at: Instant,
-    px: Option<u128>,
+    value: Value,
+    /// Unix time in milliseconds.
+    /// Absolute so that replicas expire the key at the same moment as the master.
+    expires_at: Option<u128>,
+    /// Changes on every write to the key, see [`Data::version`].
+    version: u64,
+    /// Bytes counted for the key in [`Data::used_memory`].
+    size: usize,
 }
 
 impl DataItem {
+    pub fn new(value: Value, expires_at: Option<u128>) -> Self {
+        Self {
+            value,
+            expires_at,
+            version: 0,
+            size: 0,
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    pub fn expires_at(&self) -> Option<u128> {
+        self.expires_at
+    }
+
     pub fn is_expired(&self) -> bool {
-        self.px.is_some() && self.created_at.elapsed().as_millis() > self.px.unwrap()
+        self.expires_at.is_some_and(|t| now_ms() > t)
     }
 }
 
 pub trait Data {
-    fn get(&self, key: &str) -> Option<String>;
+    fn get(&self, key: &str) -> Option<&Value>;
+
+    /// Access to change the value in place, which counts as a write to the key.
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value>;
+
+    fn set(&mut self, key: String, value: Value, expires_at: Option<u128>);
+
+    /// Returns whether the key existed, expired or not.
+    fn del(&mut self, key: &str) -> bool;
+
+    /// Whether the key exists but has expired.
+    fn is_expired(&self, key: &str) -> bool;
+
+    /// Version of the key, `None` if it does not exist.
+    /// Every write gives the key a version it never had before, which is how WATCH detects changes.
+    fn version(&self, key: &str) -> Option<u64>;
+
+    /// Number of keys deleted so far. WATCH compares it for keys that do not exist,
+    /// which have no version to tell that they were created and deleted again.
+    fn deletions(&self) -> u64;
 
-    fn set(&mut self, key: String, value: String, px: Option<u128>);
+    /// Delete expired keys, returning them.
+    fn expire_keys(&mut self) -> Vec<String>;
 
-    fn del(&mut self, key: &str);
+    fn is_empty(&self) -> bool;
 
-    fn expire_keys(&mut self);
+    /// All keys, including expired ones that have not been deleted yet.
+    fn entries(&self) -> Box<dyn Iterator<Item = (&String, &DataItem)> + '_>;
+
+    /// Swap in a whole new data set, e.g. after a full resync.
+    fn replace(&mut self, other: InMemoryData);
+
+    /// Bytes taken by the keys and values, roughly, to compare with maxmemory.
+    fn used_memory(&self) -> usize;
+
+    /// The key to delete next to free memory, `None` if `policy` allows none.
+    fn eviction_candidate(&self, policy: EvictionPolicy) -> Option<String>;
 }
 
 pub struct InMemoryData {
     data: HashMap<String, DataItem>,
+    /// The version of the next write.
+    next_version: u64,
+    /// Keys deleted so far, see [`Data::deletions`].
+    deletions: u64,
+    /// Sum of the sizes of the items.
+    used_memory: usize,
+    /// A key whose value was handed out by `get_mut`, so its size may have changed.
+    resized: Option<String>,
 }
 
 impl InMemoryData {
     pub fn new() -> Self {
         Self {
             data: HashMap::new(),
+            next_version: 1,
+            deletions: 0,
+            used_memory: 0,
+            resized: None,
+        }
+    }
+
+    pub fn insert(&mut self, key: String, mut item: DataItem) {
+        self.measure_resized();
+        item.version = self.next_version;
+        self.next_version += 1;
+        item.size = item_size(&key, &item.value);
+        self.used_memory += item.size;
+        if let Some(old) = self.data.insert(key, item) {
+            self.used_memory -= old.size;
         }
     }
+
+    /// Count the new size of the value last changed in place.
+    fn measure_resized(&mut self) {
+        let Some(key) = self.resized.take() else {
+            return;
+        };
+        if let Some(item) = self.data.get_mut(&key) {
+            let size = item_size(&key, &item.value);
+            self.used_memory = self.used_memory - item.size + size;
+            item.size = size;
+        }
+    }
+}
+
+impl Default for InMemoryData {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Data for InMemoryData {
-    fn get(&self, key: &str) -> Option<String> {
+    fn get(&self, key: &str) -> Option<&Value> {
         let item = self.data.get(key)?;
 
         if item.is_expired() {
             None
         } else {
-            Some(item.value.clone())
+            Some(&item.value)
+        }
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        let item = self.data.get_mut(key)?;
+
+        if item.is_expired() {
+            None
+        } else {
+            item.version = self.next_version;
+            self.next_version += 1;
+            // measured once the change is done
+            if self.resized.as_deref() != Some(key) {
+                self.measure_resized();
+                self.resized = Some(key.to_string());
+            }
+            let item = self.data.get_mut(key)?;
+            Some(&mut item.value)
+        }
+    }
+
+    fn set(&mut self, key: String, value: Value, expires_at: Option<u128>) {
+        self.insert(key, DataItem::new(value, expires_at));
+    }
+
+    fn del(&mut self, key: &str) -> bool {
+        self.measure_resized();
+        match self.data.remove(key) {
+            Some(item) => {
+                self.used_memory -= item.size;
+                self.deletions += 1;
+                true
+            }
+            None => false,
         }
     }
 
-    fn set(&mut self, key: String, value: String, px: Option<u128>) {
-        self.data.insert(
-            key,
-            DataItem {
-                value,
-                created_at: Instant::now(),
-                px,
-            },
-        );
+    fn is_expired(&self, key: &str) -> bool {
+        self.data.get(key).is_some_and(|item| item.is_expired())
     }
 
-    fn del(&mut self, key: &str) {
-        self.data.remove(key);
+    fn version(&self, key: &str) -> Option<u64> {
+        self.data.get(key).map(|item| item.version)
     }
 
-    fn expire_keys(&mut self) {
+    fn deletions(&self) -> u64 {
+        self.deletions
+    }
+
+    fn expire_keys(&mut self) -> Vec<String> {
         // can we do this without cloning?
         let keys = self
             .data
@@ -79,8 +240,56 @@ impl Data for InMemoryData {
                 }
             })
             .collect::<Vec<String>>();
-        for key in keys {
+        for key in &keys {
             self.del(key.as_str());
         }
+        keys
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = (&String, &DataItem)> + '_> {
+        Box::new(self.data.iter())
+    }
+
+    fn replace(&mut self, other: InMemoryData) {
+        // versions keep increasing so that watchers see every key as changed
+        self.data.clear();
+        self.used_memory = 0;
+        self.resized = None;
+        for (key, item) in other.data {
+            self.insert(key, item);
+        }
+    }
+
+    fn used_memory(&self) -> usize {
+        // the value last changed in place is not measured until another one is
+        let resized = self
+            .resized
+            .as_ref()
+            .and_then(|key| Some((key, self.data.get(key)?)));
+        match resized {
+            Some((key, item)) => self.used_memory - item.size + item_size(key, &item.value),
+            None => self.used_memory,
+        }
+    }
+
+    fn eviction_candidate(&self, policy: EvictionPolicy) -> Option<String> {
+        let mut rng = rand::thread_rng();
+        let volatile = self
+            .data
+            .iter()
+            .filter(|(_, item)| item.expires_at.is_some());
+        let key = match policy {
+            EvictionPolicy::Noeviction => None,
+            EvictionPolicy::AllkeysRandom => self.data.keys().choose(&mut rng),
+            EvictionPolicy::VolatileRandom => volatile.map(|(key, _)| key).choose(&mut rng),
+            EvictionPolicy::VolatileTtl => volatile
+                .min_by_key(|(_, item)| item.expires_at)
+                .map(|(key, _)| key),
+        };
+        key.cloned()
     }
 }
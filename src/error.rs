@@ -0,0 +1,128 @@
+use std::fmt;
+use std::num::ParseIntError;
+
+// Errors of commands, replied to clients.
+// The first word of the reply is the error code, which client libraries match on,
+// so the codes and messages are exactly those of Redis.
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A generic error: `ERR <message>`.
+    Err(String),
+    /// An error reply with its own code, passed on as it is, e.g. from a script.
+    Reply(String),
+    WrongType,
+    /// The key lives on another node of the cluster.
+    #[allow(dead_code)] // there is no cluster mode yet
+    Moved {
+        slot: u16,
+        addr: String,
+    },
+    /// The key is being migrated to another node of the cluster.
+    #[allow(dead_code)] // there is no cluster mode yet
+    Ask {
+        slot: u16,
+        addr: String,
+    },
+    NoScript,
+    ReadOnly,
+    Busy,
+    Oom,
+    NoAuth,
+    WrongPass,
+    /// The user may not run the command, with a message saying why.
+    NoPerm(String),
+    /// A client from outside refused in protected mode.
+    ProtectedMode,
+    NoProto,
+    ExecAbort,
+    MasterDown,
+    NoMasterLink,
+    NoReplicas,
+    /// Keys of one command live in different slots of the cluster.
+    #[allow(dead_code)] // there is no cluster mode yet
+    CrossSlot,
+    NotBusy,
+    Unkillable,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Err(msg) => write!(f, "ERR {}", msg),
+            Error::Reply(reply) => write!(f, "{}", reply),
+            Error::WrongType => write!(
+                f,
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            ),
+            Error::Moved { slot, addr } => write!(f, "MOVED {} {}", slot, addr),
+            Error::Ask { slot, addr } => write!(f, "ASK {} {}", slot, addr),
+            Error::NoScript => write!(f, "NOSCRIPT No matching script. Please use EVAL."),
+            Error::ReadOnly => write!(f, "READONLY You can't write against a read only replica."),
+            Error::Busy => write!(
+                f,
+                "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."
+            ),
+            Error::Oom => write!(f, "OOM command not allowed when used memory > 'maxmemory'."),
+            Error::NoAuth => write!(f, "NOAUTH Authentication required."),
+            Error::WrongPass => write!(
+                f,
+                "WRONGPASS invalid username-password pair or user is disabled."
+            ),
+            Error::NoPerm(msg) => write!(f, "NOPERM {}", msg),
+            Error::ProtectedMode => write!(
+                f,
+                "DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the default user. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' option. 4) Set up an authentication password for the default user. NOTE: You only need to do one of the above things in order for the server to start accepting connections from the outside."
+            ),
+            Error::NoProto => write!(f, "NOPROTO unsupported protocol version"),
+            Error::ExecAbort => write!(
+                f,
+                "EXECABORT Transaction discarded because of previous errors."
+            ),
+            Error::MasterDown => write!(
+                f,
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+            ),
+            Error::NoMasterLink => write!(
+                f,
+                "NOMASTERLINK Can't SYNC while not connected with my master"
+            ),
+            Error::NoReplicas => write!(f, "NOREPLICAS Not enough good replicas to write."),
+            Error::CrossSlot => write!(
+                f,
+                "CROSSSLOT Keys in request don't hash to the same slot"
+            ),
+            Error::NotBusy => write!(f, "NOTBUSY No scripts in execution right now."),
+            Error::Unkillable => write!(
+                f,
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseIntError> for Error {
+    fn from(_: ParseIntError) -> Self {
+        Error::Err("value is not an integer or out of range".to_string())
+    }
+}
+
+/// Errors of the layers below commands, like loading libraries, are generic errors.
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error::Err(e.to_string())
+    }
+}
+
+/// Return early with a generic `ERR` error, formatted like with `format!`.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::Error::Err(format!($($arg)*)))
+    };
+}
+
+pub(crate) use bail;
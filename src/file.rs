@@ -1,12 +1,469 @@
-use crate::data::SharedData;
-use crate::resp::RespOut;
+use crate::data::{Data, DataItem, InMemoryData, Value};
+use crate::functions::Functions;
+use crate::module::Modules;
+use crate::utils::now_ms;
+use anyhow::{bail, Result};
+use std::cell::Cell;
 
-// NOTE: the redis protocol expects the RDB file response to be on the form as a bulk string
-//       except that it should not finish with a CRLF.
-//       Which is bullshit, so we that is not done here and instead just return a normal bulk string.
+// The subset of the RDB format needed for our data: string and module values
+// with optional expire times, and function libraries.
+// See https://rdb.fnordig.de/file_format.html
 
-const EMPTY_RDB: &str = "UkVESVMwMDEx+glyZWRpcy12ZXIFNy4yLjD6CnJlZGlzLWJpdHPAQPoFY3RpbWXCbQi8ZfoIdXNlZC1tZW3CsMQQAPoIYW9mLWJhc2XAAP/wbjv+wP9aog==";
+const RDB_VERSION: &[u8] = b"REDIS0011";
+/// The version as written at the end of DUMP payloads.
+const RDB_VERSION_NUMBER: u16 = 11;
 
-pub fn construct_rdb_file(_data: &SharedData) -> RespOut {
-    RespOut::BulkString(EMPTY_RDB.to_string())
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0x00;
+const TYPE_MODULE_2: u8 = 0x07;
+
+// Module values are written as a sequence of opcodes; ours are a single string.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+/// Characters of module type names, in the order their 6 bit codes in module ids refer to.
+const MODULE_TYPE_CHARSET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// The contents of an RDB file.
+pub struct Rdb {
+    pub data: InMemoryData,
+    /// Code of the function libraries.
+    pub libraries: Vec<String>,
+}
+
+/// Serialize the data set and the function libraries,
+/// recording the replication id and offset they correspond to.
+pub fn write_rdb(data: &dyn Data, functions: &Functions, replid: &str, offset: u64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(RDB_VERSION);
+
+    for (key, value) in [
+        ("redis-ver", "7.2.0".to_string()),
+        ("redis-bits", "64".to_string()),
+        ("ctime", (now_ms() / 1000).to_string()),
+        ("repl-id", replid.to_string()),
+        ("repl-offset", offset.to_string()),
+    ] {
+        buf.push(OPCODE_AUX);
+        write_string(&mut buf, key.as_bytes());
+        write_string(&mut buf, value.as_bytes());
+    }
+
+    write_functions(&mut buf, functions);
+
+    let entries = data.entries().collect::<Vec<_>>();
+    let expires = entries
+        .iter()
+        .filter(|(_, item)| item.expires_at().is_some())
+        .count();
+
+    buf.push(OPCODE_SELECTDB);
+    write_length(&mut buf, 0);
+    buf.push(OPCODE_RESIZEDB);
+    write_length(&mut buf, entries.len() as u64);
+    write_length(&mut buf, expires as u64);
+
+    for (key, item) in entries {
+        if let Some(expires_at) = item.expires_at() {
+            buf.push(OPCODE_EXPIRETIME_MS);
+            buf.extend((expires_at as u64).to_le_bytes());
+        }
+        match item.value() {
+            Value::String(value) => {
+                buf.push(TYPE_STRING);
+                write_string(&mut buf, key.as_bytes());
+                write_string(&mut buf, value.as_bytes());
+            }
+            Value::Module(value) => {
+                buf.push(TYPE_MODULE_2);
+                write_string(&mut buf, key.as_bytes());
+                write_length(&mut buf, module_id(value.type_name()));
+                write_length(&mut buf, MODULE_OPCODE_STRING);
+                write_string(&mut buf, &value.save());
+                write_length(&mut buf, MODULE_OPCODE_EOF);
+            }
+        }
+    }
+
+    buf.push(OPCODE_EOF);
+    let checksum = crc64(0, &buf);
+    buf.extend(checksum.to_le_bytes());
+    buf
+}
+
+/// Parse an RDB file, loading module values with the types `modules` registered.
+pub fn read_rdb(buf: &[u8], modules: &Modules) -> Result<Rdb> {
+    RdbParser::new(buf).parse(modules)
+}
+
+/// Serialize the function libraries for FUNCTION DUMP:
+/// the libraries as in an RDB file, followed by the RDB version and a checksum.
+pub fn dump_functions(functions: &Functions) -> Vec<u8> {
+    let mut buf = Vec::new();
+    write_functions(&mut buf, functions);
+    buf.extend(RDB_VERSION_NUMBER.to_le_bytes());
+    let checksum = crc64(0, &buf);
+    buf.extend(checksum.to_le_bytes());
+    buf
+}
+
+/// Parse a FUNCTION DUMP payload into the code of its libraries.
+pub fn restore_functions(payload: &[u8]) -> Result<Vec<String>> {
+    if payload.len() < 10 {
+        bail!("payload version or checksum are wrong");
+    }
+    let (body, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes(footer[..2].try_into()?);
+    let checksum = u64::from_le_bytes(footer[2..].try_into()?);
+    if version > RDB_VERSION_NUMBER || checksum != crc64(0, &payload[..payload.len() - 8]) {
+        bail!("payload version or checksum are wrong");
+    }
+
+    let parser = RdbParser::new(body);
+    let mut libraries = Vec::new();
+    while parser.pos.get() < body.len() {
+        match parser.next()? {
+            OPCODE_FUNCTION2 => libraries.push(String::from_utf8(parser.next_string()?)?),
+            t => bail!("given type is not a function: {:#x}", t),
+        }
+    }
+    Ok(libraries)
+}
+
+fn write_functions(buf: &mut Vec<u8>, functions: &Functions) {
+    for library in functions.libraries() {
+        buf.push(OPCODE_FUNCTION2);
+        write_string(buf, library.code.as_bytes());
+    }
+}
+
+/// The 64 bit id of a module type: its 9 character name and a 10 bit encoding version, always 0.
+fn module_id(name: &str) -> u64 {
+    let mut id = 0;
+    for c in name.bytes() {
+        let code = MODULE_TYPE_CHARSET
+            .iter()
+            .position(|&x| x == c)
+            .unwrap_or(0);
+        id = (id << 6) | code as u64;
+    }
+    id << 10
+}
+
+fn module_type_name(id: u64) -> String {
+    (0..9)
+        .rev()
+        .map(|i| MODULE_TYPE_CHARSET[((id >> (10 + i * 6)) & 0x3F) as usize] as char)
+        .collect()
+}
+
+fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.push(0x40 | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend((len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend(len.to_be_bytes());
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_length(buf, s.len() as u64);
+    buf.extend(s);
+}
+
+/// A length, or the encoding of a specially encoded string.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct RdbParser<'a> {
+    buf: &'a [u8],
+    pos: Cell<usize>,
+}
+
+impl RdbParser<'_> {
+    fn new(buf: &[u8]) -> RdbParser<'_> {
+        RdbParser {
+            buf,
+            pos: Cell::new(0),
+        }
+    }
+
+    fn parse(&self, modules: &Modules) -> Result<Rdb> {
+        let header = self.take(RDB_VERSION.len())?;
+        if !header.starts_with(b"REDIS") {
+            bail!("not an RDB file");
+        }
+
+        let mut data = InMemoryData::new();
+        let mut libraries = Vec::new();
+        let mut expires_at = None;
+
+        loop {
+            match self.next()? {
+                OPCODE_AUX => {
+                    self.next_string()?;
+                    self.next_string()?;
+                }
+                OPCODE_FUNCTION2 => {
+                    libraries.push(String::from_utf8(self.next_string()?)?);
+                }
+                OPCODE_SELECTDB => {
+                    self.next_length()?;
+                }
+                OPCODE_RESIZEDB => {
+                    self.next_length()?;
+                    self.next_length()?;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    let bytes = self.take(8)?.try_into()?;
+                    expires_at = Some(u64::from_le_bytes(bytes) as u128);
+                }
+                OPCODE_EXPIRETIME => {
+                    let bytes = self.take(4)?.try_into()?;
+                    expires_at = Some(u32::from_le_bytes(bytes) as u128 * 1000);
+                }
+                OPCODE_EOF => break,
+                TYPE_STRING => {
+                    let key = String::from_utf8(self.next_string()?)?;
+                    let value = String::from_utf8(self.next_string()?)?;
+                    data.insert(key, DataItem::new(Value::String(value), expires_at.take()));
+                }
+                TYPE_MODULE_2 => {
+                    let key = String::from_utf8(self.next_string()?)?;
+                    let name = module_type_name(self.next_length()?);
+                    let Some(module_type) = modules.get_type(&name) else {
+                        bail!("unknown module type {}", name);
+                    };
+                    if self.next_length()? != MODULE_OPCODE_STRING {
+                        bail!("unsupported value of module type {}", name);
+                    }
+                    let value = module_type.load(&self.next_string()?)?;
+                    if self.next_length()? != MODULE_OPCODE_EOF {
+                        bail!("unsupported value of module type {}", name);
+                    }
+                    data.insert(key, DataItem::new(Value::Module(value), expires_at.take()));
+                }
+                t => bail!("unsupported RDB value type {:#x}", t),
+            }
+        }
+
+        // a zero checksum means checksums were disabled when the file was written
+        let end = self.pos.get();
+        if let Ok(bytes) = self.take(8) {
+            let checksum = u64::from_le_bytes(bytes.try_into()?);
+            if checksum != 0 && checksum != crc64(0, &self.buf[..end]) {
+                bail!("RDB checksum mismatch");
+            }
+        }
+
+        Ok(Rdb { data, libraries })
+    }
+
+    fn next(&self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn take(&self, n: usize) -> Result<&[u8]> {
+        let pos = self.pos.get();
+        if self.buf.len() < pos + n {
+            bail!("unexpected end of RDB file");
+        }
+        self.pos.set(pos + n);
+        Ok(&self.buf[pos..pos + n])
+    }
+
+    fn next_length_or_encoding(&self) -> Result<Length> {
+        let first = self.next()?;
+        let len = match first >> 6 {
+            0b00 => (first & 0x3F) as u64,
+            0b01 => (((first & 0x3F) as u64) << 8) | self.next()? as u64,
+            0b10 => match first {
+                0x80 => u32::from_be_bytes(self.take(4)?.try_into()?) as u64,
+                0x81 => u64::from_be_bytes(self.take(8)?.try_into()?),
+                _ => bail!("invalid length encoding {:#x}", first),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3F)),
+        };
+        Ok(Length::Len(len))
+    }
+
+    fn next_length(&self) -> Result<u64> {
+        match self.next_length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => bail!("expected a length"),
+        }
+    }
+
+    fn next_string(&self) -> Result<Vec<u8>> {
+        let res = match self.next_length_or_encoding()? {
+            Length::Len(len) => self.take(len as usize)?.to_vec(),
+            Length::Encoded(ENC_INT8) => (self.next()? as i8).to_string().into_bytes(),
+            Length::Encoded(ENC_INT16) => i16::from_le_bytes(self.take(2)?.try_into()?)
+                .to_string()
+                .into_bytes(),
+            Length::Encoded(ENC_INT32) => i32::from_le_bytes(self.take(4)?.try_into()?)
+                .to_string()
+                .into_bytes(),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.next_length()? as usize;
+                let len = self.next_length()? as usize;
+                lzf_decompress(self.take(compressed_len)?, len)?
+            }
+            Length::Encoded(enc) => bail!("unsupported string encoding {}", enc),
+        };
+        Ok(res)
+    }
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // literal run
+            let n = ctrl + 1;
+            if i + n > input.len() {
+                bail!("invalid LZF data");
+            }
+            out.extend(&input[i..i + n]);
+            i += n;
+        } else {
+            // back reference
+            let mut n = ctrl >> 5;
+            if n == 7 {
+                n += *input
+                    .get(i)
+                    .ok_or_else(|| anyhow::anyhow!("invalid LZF data"))?
+                    as usize;
+                i += 1;
+            }
+            let low = *input
+                .get(i)
+                .ok_or_else(|| anyhow::anyhow!("invalid LZF data"))? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1F) << 8) + low + 1;
+            if back > out.len() {
+                bail!("invalid LZF data");
+            }
+            let start = out.len() - back;
+            for j in 0..n + 2 {
+                out.push(out[start + j]);
+            }
+        }
+    }
+
+    if out.len() != len {
+        bail!("invalid LZF data");
+    }
+    Ok(out)
+}
+
+/// CRC-64/Jones as used by Redis.
+fn crc64(mut crc: u64, buf: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC9329AC4BC9B5;
+    for &byte in buf {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(item: &DataItem) -> &str {
+        match item.value() {
+            Value::String(s) => s,
+            Value::Module(_) => panic!("not a string"),
+        }
+    }
+
+    #[test]
+    fn rdb_round_trip() {
+        let expires_at = now_ms() + 60_000;
+        let mut data = InMemoryData::new();
+        data.set(
+            "plain".to_string(),
+            Value::String("value".to_string()),
+            None,
+        );
+        data.set(
+            "number".to_string(),
+            Value::String("12345".to_string()),
+            None,
+        );
+        // long and repetitive, which Redis would compress
+        data.set(
+            "long".to_string(),
+            Value::String("ab".repeat(1000)),
+            Some(expires_at),
+        );
+        data.set("empty".to_string(), Value::String(String::new()), None);
+
+        let code = "#!lua name=lib\nredis.register_function('f', function() return 1 end)";
+        let mut functions = Functions::new();
+        functions
+            .load(crate::scripting::load_library(code).unwrap(), false)
+            .unwrap();
+
+        let rdb = write_rdb(&data, &functions, "replid", 42);
+        let loaded = read_rdb(&rdb, &Modules::new()).unwrap();
+
+        let entries = loaded.data.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 4);
+        for (key, item) in entries {
+            let expected = data.entries().find(|(k, _)| *k == key).unwrap().1;
+            assert_eq!(string(item), string(expected));
+            assert_eq!(item.expires_at(), expected.expires_at());
+        }
+        assert_eq!(loaded.libraries, [code]);
+    }
+
+    #[test]
+    fn rdb_without_data() {
+        let rdb = write_rdb(&InMemoryData::new(), &Functions::new(), "replid", 0);
+        let loaded = read_rdb(&rdb, &Modules::new()).unwrap();
+        assert!(loaded.data.is_empty());
+        assert!(loaded.libraries.is_empty());
+    }
+
+    #[test]
+    fn rdb_with_a_bad_checksum_is_rejected() {
+        let mut rdb = write_rdb(&InMemoryData::new(), &Functions::new(), "replid", 0);
+        let last = rdb.len() - 1;
+        rdb[last] ^= 0xff;
+        assert!(read_rdb(&rdb, &Modules::new()).is_err());
+        assert!(read_rdb(b"NOTREDIS", &Modules::new()).is_err());
+    }
 }
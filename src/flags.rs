@@ -0,0 +1,79 @@
+use anyhow::{bail, Result};
+
+// Flags of commands, as reported by COMMAND INFO.
+// See https://redis.io/docs/reference/command-tips/ and the `command_flags` of COMMAND INFO.
+
+/// May modify data.
+pub const WRITE: u32 = 1 << 0;
+/// Never modifies data.
+pub const READONLY: u32 = 1 << 1;
+/// May increase memory usage, so it is refused once out of memory.
+pub const DENYOOM: u32 = 1 << 2;
+/// An administrative command.
+pub const ADMIN: u32 = 1 << 3;
+/// Related to pub/sub.
+pub const PUBSUB: u32 = 1 << 4;
+/// Can not be called from scripts.
+pub const NOSCRIPT: u32 = 1 << 5;
+/// Allowed while the data set is loading.
+pub const LOADING: u32 = 1 << 6;
+/// Allowed on a replica that may not serve data, see replica-serve-stale-data.
+pub const STALE: u32 = 1 << 7;
+/// Runs in constant or logarithmic time.
+pub const FAST: u32 = 1 << 8;
+/// Allowed while a script is busy, so it can be killed.
+pub const ALLOW_BUSY: u32 = 1 << 9;
+/// Can not be part of a transaction.
+pub const NO_MULTI: u32 = 1 << 10;
+/// The keys are not at fixed positions, see COMMAND GETKEYS.
+pub const MOVABLEKEYS: u32 = 1 << 11;
+/// Allowed before the connection authenticated.
+pub const NO_AUTH: u32 = 1 << 12;
+
+const NAMES: &[(u32, &str)] = &[
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (PUBSUB, "pubsub"),
+    (NOSCRIPT, "noscript"),
+    (LOADING, "loading"),
+    (STALE, "stale"),
+    (FAST, "fast"),
+    (ALLOW_BUSY, "allow_busy"),
+    (NO_MULTI, "no_multi"),
+    (MOVABLEKEYS, "movablekeys"),
+    (NO_AUTH, "no_auth"),
+];
+
+/// The names of the flags set in `flags`.
+pub fn names(flags: u32) -> Vec<&'static str> {
+    NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Parse flags given by modules, like `write deny-oom`.
+pub fn parse_module_flags(flags: &str) -> Result<u32> {
+    let mut res = 0;
+    for flag in flags.split_whitespace() {
+        res |= match flag {
+            "write" => WRITE,
+            "readonly" => READONLY,
+            "deny-oom" => DENYOOM,
+            "admin" => ADMIN,
+            "pubsub" => PUBSUB,
+            "noscript" => NOSCRIPT,
+            "loading" => LOADING,
+            "stale" => STALE,
+            "fast" => FAST,
+            "allow-busy" => ALLOW_BUSY,
+            "no-multi" => NO_MULTI,
+            "no-auth" => NO_AUTH,
+            flag => bail!("Invalid command flag {}", flag),
+        };
+    }
+    Ok(res)
+}
@@ -0,0 +1,102 @@
+use crate::scripting::Interpreter;
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub type SharedFunctions = Arc<RwLock<Functions>>;
+
+/// A function registered by a library with `redis.register_function`.
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl Function {
+    /// Whether the function declared that it does not write, see FCALL_RO.
+    pub fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "no-writes")
+    }
+}
+
+/// A library loaded with FUNCTION LOAD.
+pub struct Library {
+    pub name: String,
+    /// The source, including the `#!lua name=<library>` header.
+    pub code: String,
+    pub functions: BTreeMap<String, Function>,
+    /// Where the code ran when the library was loaded, holding the registered callbacks.
+    pub interpreter: Arc<Interpreter>,
+}
+
+/// The loaded libraries. Functions are named globally, not per library.
+#[derive(Default)]
+pub struct Functions {
+    libraries: BTreeMap<String, Library>,
+    /// Library of each function.
+    by_function: HashMap<String, String>,
+}
+
+impl Functions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a library, replacing the one with the same name only if `replace` is set.
+    pub fn load(&mut self, library: Library, replace: bool) -> Result<()> {
+        if self.libraries.contains_key(&library.name) && !replace {
+            bail!("Library '{}' already exists", library.name);
+        }
+        for name in library.functions.keys() {
+            match self.by_function.get(name) {
+                Some(other) if *other != library.name => {
+                    bail!("Function {} already exists", name)
+                }
+                _ => {}
+            }
+        }
+
+        // functions the new version no longer has go away
+        let _ = self.delete(&library.name);
+        for name in library.functions.keys() {
+            self.by_function.insert(name.clone(), library.name.clone());
+        }
+        self.libraries.insert(library.name.clone(), library);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<()> {
+        let Some(library) = self.libraries.remove(name) else {
+            bail!("Library not found");
+        };
+        for name in library.functions.keys() {
+            self.by_function.remove(name);
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+        self.by_function.clear();
+    }
+
+    /// Swap in a whole new set of libraries, e.g. after a full resync.
+    pub fn replace(&mut self, libraries: Vec<Library>) {
+        self.flush();
+        for library in libraries {
+            // they were consistent on the master
+            let _ = self.load(library, true);
+        }
+    }
+
+    /// The function and the library it belongs to.
+    pub fn get(&self, name: &str) -> Option<(&Library, &Function)> {
+        let library = self.libraries.get(self.by_function.get(name)?)?;
+        Some((library, library.functions.get(name)?))
+    }
+
+    pub fn libraries(&self) -> impl Iterator<Item = &Library> {
+        self.libraries.values()
+    }
+}
@@ -1,7 +1,16 @@
+use crate::data::EvictionPolicy;
+use crate::replication::{Backlog, MasterLink, ReplicaHandle, ReplicaState};
+use crate::resp::RespIn;
 use rand::{distributions::Alphanumeric, Rng};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
+use tokio_rustls::TlsConnector;
 
-pub type SharedInfo = Arc<Info>;
+pub type SharedInfo = Arc<RwLock<Info>>;
 
 #[derive(PartialEq, Clone, Copy)]
 pub enum ReplicaRole {
@@ -9,34 +18,161 @@ pub enum ReplicaRole {
     SLAVE,
 }
 
-impl ReplicaRole {
-    fn to_string(&self) -> String {
+impl fmt::Display for ReplicaRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ReplicaRole::MASTER => "master".to_string(),
-            ReplicaRole::SLAVE => "slave".to_string(),
+            ReplicaRole::MASTER => write!(f, "master"),
+            ReplicaRole::SLAVE => write!(f, "slave"),
         }
     }
 }
 
 pub struct Server {
     tcp_port: u16,
+    rdb_path: PathBuf,
+    /// Only accept clients on the loopback interface while the default user has no password.
+    protected_mode: bool,
+    /// Bytes the data may take before keys are evicted, 0 for no limit.
+    maxmemory: usize,
+    maxmemory_policy: EvictionPolicy,
 }
 
 impl Server {
     pub fn port(&self) -> u16 {
         self.tcp_port
     }
+
+    pub fn protected_mode(&self) -> bool {
+        self.protected_mode
+    }
+
+    pub fn set_protected_mode(&mut self, protected_mode: bool) {
+        self.protected_mode = protected_mode;
+    }
+
+    pub fn maxmemory(&self) -> usize {
+        self.maxmemory
+    }
+
+    pub fn set_maxmemory(&mut self, maxmemory: usize) {
+        self.maxmemory = maxmemory;
+    }
+
+    pub fn maxmemory_policy(&self) -> EvictionPolicy {
+        self.maxmemory_policy
+    }
+
+    pub fn set_maxmemory_policy(&mut self, policy: EvictionPolicy) {
+        self.maxmemory_policy = policy;
+    }
+
+    pub fn rdb_path(&self) -> &PathBuf {
+        &self.rdb_path
+    }
+
+    pub fn set_rdb_path(&mut self, rdb_path: PathBuf) {
+        self.rdb_path = rdb_path;
+    }
+}
+
+/// Counters of the stats section, reset by CONFIG RESETSTAT.
+/// Atomic so that counting only needs a read lock.
+#[derive(Default)]
+pub struct Stats {
+    pub total_connections_received: AtomicU64,
+    pub total_commands_processed: AtomicU64,
+    pub total_error_replies: AtomicU64,
+    pub evicted_keys: AtomicU64,
+}
+
+impl Stats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.total_connections_received.store(0, Ordering::Relaxed);
+        self.total_commands_processed.store(0, Ordering::Relaxed);
+        self.total_error_replies.store(0, Ordering::Relaxed);
+        self.evicted_keys.store(0, Ordering::Relaxed);
+    }
+}
+
+/// How a replica loads the snapshot it receives on a full resync.
+#[derive(clap::ValueEnum, PartialEq, Clone, Copy)]
+pub enum DisklessLoad {
+    /// Save it to disk first, then load the file.
+    Disabled,
+    /// Load it straight from the socket, but only when we have no data.
+    OnEmptyDb,
+    /// Load it straight from the socket into a temporary data set that is then swapped in.
+    Swapdb,
+}
+
+pub struct ReplicationConfig {
+    pub repl_backlog_size: usize,
+    /// Keep answering reads with possibly stale data while the link is down.
+    pub serve_stale_data: bool,
+    /// Reject writes from clients other than the master when we are a replica.
+    pub read_only: bool,
+    /// Stream snapshots to replicas without writing them to disk.
+    pub diskless_sync: bool,
+    /// Seconds to wait for more replicas before starting a diskless transfer.
+    pub diskless_sync_delay: u64,
+    pub diskless_load: DisklessLoad,
+    /// How long a replica waits for data from its master before reconnecting.
+    pub timeout: Duration,
+    /// How often a master PINGs its replicas.
+    pub ping_replica_period: Duration,
+    /// Refuse writes unless this many replicas are connected with a small enough lag, 0 to disable.
+    pub min_replicas_to_write: usize,
+    /// Maximum lag in seconds for a replica to count towards `min_replicas_to_write`.
+    pub min_replicas_max_lag: u64,
+    /// User to authenticate as with the master, the default user if not set.
+    pub masteruser: Option<String>,
+    /// Password to authenticate with the master.
+    pub masterauth: Option<String>,
+    /// Connects to the master over TLS, see tls-replication.
+    pub tls: Option<TlsConnector>,
 }
 
 pub struct Replication {
     role: ReplicaRole,
     master_replid: Option<String>,
     master_repl_offset: Option<u64>,
+    /// Replication id we had before the last promotion, accepted by PSYNC
+    /// up to `second_repl_offset` so our former siblings can continue.
+    master_replid2: Option<String>,
+    second_repl_offset: Option<u64>,
     master_host: Option<String>,
     master_port: Option<u16>,
+    backlog: Backlog,
+    replicas: Vec<ReplicaHandle>,
+    next_replica_id: u64,
+    /// Notified whenever a replica acknowledges an offset.
+    acks: Arc<Notify>,
+    master_link_up: bool,
+    master_link_down_since: Option<Instant>,
+    master_last_io: Option<Instant>,
+    master_sync_in_progress: bool,
+    /// A transfer to the replicas waiting for a full resync has been scheduled.
+    full_sync_scheduled: bool,
+    link: Option<MasterLink>,
+    pub config: ReplicationConfig,
 }
 
 impl Replication {
+    pub fn role(&self) -> ReplicaRole {
+        self.role
+    }
+    pub fn master_host(&self) -> Option<&String> {
+        self.master_host.as_ref()
+    }
+
+    pub fn master_port(&self) -> Option<u16> {
+        self.master_port
+    }
+
     pub fn master_addr(&self) -> String {
         match (&self.master_host, self.master_port) {
             (Some(host), Some(port)) => format!("{}:{}", host, port),
@@ -53,11 +189,295 @@ impl Replication {
             .as_ref()
             .expect("master_repl_offset must be set")
     }
+
+    pub fn replicas(&self) -> &[ReplicaHandle] {
+        &self.replicas
+    }
+
+    /// State of the link to the master as reported by ROLE.
+    pub fn link_state(&self) -> &'static str {
+        if self.master_link_up {
+            "connected"
+        } else if self.master_sync_in_progress {
+            "sync"
+        } else {
+            "connect"
+        }
+    }
+
+    pub fn set_link(&mut self, link: MasterLink) {
+        self.link = Some(link);
+    }
+
+    pub fn take_link(&mut self) -> Option<MasterLink> {
+        self.link.take()
+    }
+
+    /// Become a master. A new replication id is generated, but the old one is kept
+    /// so replicas of our former master can continue from us with a partial resync.
+    pub fn promote(&mut self) {
+        if self.role == ReplicaRole::MASTER {
+            return;
+        }
+        let offset = self.master_repl_offset.unwrap_or(0);
+        self.master_replid2 = self.master_replid.take();
+        self.second_repl_offset = self.master_replid2.as_ref().map(|_| offset + 1);
+        self.master_replid = Some(new_replid());
+        self.master_repl_offset = Some(offset);
+        self.role = ReplicaRole::MASTER;
+        // our replicas reconnect to learn the new replication id and continue with it
+        self.replicas.clear();
+        self.master_host = None;
+        self.master_port = None;
+        self.master_link_up = false;
+        self.master_link_down_since = None;
+        self.master_last_io = None;
+        self.master_sync_in_progress = false;
+    }
+
+    /// Become a replica of another master.
+    /// Our replication id and offset are kept to try a partial resync with it.
+    pub fn follow(&mut self, host: String, port: u16) {
+        // our replicas reconnect and learn about the new master through us
+        self.replicas.clear();
+        self.role = ReplicaRole::SLAVE;
+        self.master_host = Some(host);
+        self.master_port = Some(port);
+        self.master_link_up = false;
+        self.master_link_down_since = None;
+        self.master_last_io = None;
+    }
+
+    pub fn set_link_up(&mut self, up: bool) {
+        if self.master_link_up && !up {
+            self.master_link_down_since = Some(Instant::now());
+        }
+        self.master_link_up = up;
+        if up {
+            self.master_last_io = Some(Instant::now());
+        }
+    }
+
+    pub fn set_sync_in_progress(&mut self, in_progress: bool) {
+        self.master_sync_in_progress = in_progress;
+    }
+
+    /// Record that data was received from the master.
+    pub fn touch_master_io(&mut self) {
+        self.master_last_io = Some(Instant::now());
+    }
+
+    /// Whether we may answer with data that might not be in sync with the master.
+    pub fn can_serve_data(&self) -> bool {
+        self.role == ReplicaRole::MASTER || self.master_link_up || self.config.serve_stale_data
+    }
+
+    pub fn accepts_writes(&self) -> bool {
+        self.role == ReplicaRole::MASTER || !self.config.read_only
+    }
+
+    /// Replication id and offset we can ask the master to continue from, if any.
+    pub fn psync_state(&self) -> Option<(&String, u64)> {
+        Some((self.master_replid.as_ref()?, self.master_repl_offset?))
+    }
+
+    /// Start over from a full resync.
+    pub fn reset(&mut self, replid: String, offset: u64) {
+        self.master_replid = Some(replid);
+        self.master_repl_offset = Some(offset);
+        self.master_replid2 = None;
+        self.second_repl_offset = None;
+        self.backlog.clear();
+        // our history was replaced, so our replicas need a full resync as well
+        self.replicas.clear();
+    }
+
+    /// Take over a new replication id from our master, e.g. after it was promoted.
+    /// Like on a promotion the old id is kept so our replicas can continue with us.
+    pub fn set_replid(&mut self, replid: String) {
+        if self.master_replid.as_ref() == Some(&replid) {
+            return;
+        }
+        let offset = self.master_repl_offset.unwrap_or(0);
+        self.master_replid2 = self.master_replid.replace(replid);
+        self.second_repl_offset = Some(offset + 1);
+        self.replicas.clear();
+    }
+
+    /// Whether we have data in sync with a master that replicas can sync from.
+    pub fn can_serve_replicas(&self) -> bool {
+        match self.role {
+            ReplicaRole::MASTER => true,
+            ReplicaRole::SLAVE => self.master_link_up && self.psync_state().is_some(),
+        }
+    }
+
+    /// Propagate a write command to the replicas.
+    pub fn propagate(&mut self, cmd: &RespIn) {
+        if self.role == ReplicaRole::MASTER {
+            self.feed(&cmd.serialize());
+        }
+    }
+
+    /// Append bytes to the replication stream: advance the offset,
+    /// record them in the backlog and forward them to the replicas.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let offset = self.master_repl_offset.get_or_insert(0);
+        *offset += bytes.len() as u64;
+        self.backlog.push(bytes);
+        self.replicas.retain_mut(|replica| match replica.state {
+            // the snapshot they will get already includes this
+            ReplicaState::WaitBgsave => replica.is_connected(),
+            // the snapshot they will get does not
+            ReplicaState::WaitBgsaveEnd => {
+                replica.buffer.extend_from_slice(bytes);
+                replica.is_connected()
+            }
+            ReplicaState::Online => replica.send(bytes),
+        });
+    }
+
+    /// Returns true if the caller should start a full resync for the waiting replicas,
+    /// false if one is already scheduled.
+    pub fn schedule_full_sync(&mut self) -> bool {
+        !std::mem::replace(&mut self.full_sync_scheduled, true)
+    }
+
+    /// A snapshot is being taken for the replicas waiting for one.
+    /// Returns the replication id and offset it is taken at.
+    pub fn start_full_sync(&mut self) -> (String, u64) {
+        for replica in &mut self.replicas {
+            if replica.state == ReplicaState::WaitBgsave {
+                replica.state = ReplicaState::WaitBgsaveEnd;
+            }
+        }
+        (self.master_replid().clone(), *self.master_repl_offset())
+    }
+
+    /// Send the snapshot taken at `replid` and `offset` to the replicas it was taken for,
+    /// followed by the writes made since, and start streaming to them.
+    /// Returns true if other replicas are waiting, in which case the caller takes another snapshot.
+    pub fn finish_full_sync(
+        &mut self,
+        rdb: &[u8],
+        diskless: bool,
+        replid: &str,
+        offset: u64,
+    ) -> bool {
+        let header = format!("+FULLRESYNC {} {}\r\n", replid, offset);
+        for replica in &mut self.replicas {
+            if replica.state != ReplicaState::WaitBgsaveEnd {
+                continue;
+            }
+            let eof_mark = (diskless && replica.capa_eof).then(new_replid);
+            let payload = crate::replication::rdb_payload(rdb, eof_mark.as_deref());
+            let buffer = std::mem::take(&mut replica.buffer);
+            replica.send(&[header.as_bytes(), &payload, &buffer].concat());
+            replica.state = ReplicaState::Online;
+        }
+        self.full_sync_scheduled = self
+            .replicas
+            .iter()
+            .any(|replica| replica.state == ReplicaState::WaitBgsave);
+        self.full_sync_scheduled
+    }
+
+    pub fn drop_waiting_replicas(&mut self) {
+        self.full_sync_scheduled = false;
+        self.replicas.retain(|replica| {
+            !matches!(
+                replica.state,
+                ReplicaState::WaitBgsave | ReplicaState::WaitBgsaveEnd
+            )
+        });
+    }
+
+    pub fn next_replica_id(&mut self) -> u64 {
+        self.next_replica_id += 1;
+        self.next_replica_id
+    }
+
+    pub fn add_replica(&mut self, replica: ReplicaHandle) {
+        self.replicas.push(replica);
+    }
+
+    pub fn remove_replica(&mut self, id: u64) {
+        self.replicas.retain(|replica| replica.id != id);
+    }
+
+    pub fn num_replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Record the offset a replica has processed.
+    pub fn ack(&mut self, id: u64, offset: u64) {
+        if let Some(replica) = self.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = offset;
+            replica.last_ack = Some(Instant::now());
+            self.acks.notify_waiters();
+        }
+    }
+
+    /// Number of replicas that have acknowledged at least `offset`.
+    pub fn num_acked(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    /// Number of online replicas that acknowledged within `min_replicas_max_lag`.
+    pub fn num_good_replicas(&self) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| {
+                replica.state == ReplicaState::Online
+                    && replica.last_ack.is_some_and(|last_ack| {
+                        last_ack.elapsed().as_secs() <= self.config.min_replicas_max_lag
+                    })
+            })
+            .count()
+    }
+
+    /// Whether enough replicas are in sync to accept writes, see `min_replicas_to_write`.
+    pub fn has_enough_good_replicas(&self) -> bool {
+        self.role != ReplicaRole::MASTER
+            || self.config.min_replicas_to_write == 0
+            || self.num_good_replicas() >= self.config.min_replicas_to_write
+    }
+
+    pub fn acks(&self) -> Arc<Notify> {
+        Arc::clone(&self.acks)
+    }
+
+    /// Resize the backlog, which drops what it holds.
+    pub fn set_backlog_size(&mut self, size: usize) {
+        if size != self.backlog.size() {
+            self.backlog = Backlog::new(size);
+        }
+        self.config.repl_backlog_size = size;
+    }
+
+    /// The part of the stream a replica is missing when asking to continue from `offset`,
+    /// or `None` if it cannot be served from the backlog and a full resync is needed.
+    pub fn partial_resync(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let (master_replid, master_repl_offset) = self.psync_state()?;
+        let known = match (&self.master_replid2, self.second_repl_offset) {
+            (Some(replid2), Some(second_offset)) if replid2 == replid => offset <= second_offset,
+            _ => master_replid == replid,
+        };
+        if !known || offset == 0 || offset > master_repl_offset + 1 {
+            return None;
+        }
+        self.backlog
+            .tail((master_repl_offset + 1 - offset) as usize)
+    }
 }
 
 pub struct Info {
     pub server: Server,
     pub replication: Replication,
+    pub stats: Stats,
 }
 
 impl Info {
@@ -65,27 +485,122 @@ impl Info {
         Info {
             server,
             replication,
+            stats: Stats::default(),
         }
     }
 
     pub fn get_section(&self, name: &str) -> Option<String> {
         let mut res = Vec::new();
         match name {
+            "stats" => {
+                let stats = &self.stats;
+                res.push(format!("# {}\n", name));
+                res.push(format!(
+                    "total_connections_received:{}\n",
+                    stats.total_connections_received.load(Ordering::Relaxed)
+                ));
+                res.push(format!(
+                    "total_commands_processed:{}\n",
+                    stats.total_commands_processed.load(Ordering::Relaxed)
+                ));
+                res.push(format!(
+                    "total_error_replies:{}\n",
+                    stats.total_error_replies.load(Ordering::Relaxed)
+                ));
+                res.push(format!(
+                    "evicted_keys:{}\n",
+                    stats.evicted_keys.load(Ordering::Relaxed)
+                ));
+                Some(res.join(""))
+            }
             "replication" => {
                 res.push(format!("# {}\n", name));
-                res.push(format!("role:{}\n", self.replication.role.to_string()));
+                res.push(format!("role:{}\n", self.replication.role));
+                res.push(format!(
+                    "connected_slaves:{}\n",
+                    self.replication.replicas.len()
+                ));
+                for (i, replica) in self.replication.replicas.iter().enumerate() {
+                    res.push(format!(
+                        "slave{}:ip={},port={},state={},offset={},lag={}\n",
+                        i,
+                        replica.ip,
+                        replica.port,
+                        replica.state,
+                        replica.ack_offset,
+                        replica
+                            .last_ack
+                            .map_or(0, |last_ack| last_ack.elapsed().as_secs())
+                    ));
+                }
+                if self.replication.role == ReplicaRole::MASTER
+                    && self.replication.config.min_replicas_to_write > 0
+                {
+                    res.push(format!(
+                        "min_slaves_good_slaves:{}\n",
+                        self.replication.num_good_replicas()
+                    ));
+                }
                 if let Some(master_replid) = &self.replication.master_replid {
                     res.push(format!("master_replid:{}\n", master_replid));
                 }
                 if let Some(master_repl_offset) = &self.replication.master_repl_offset {
                     res.push(format!("master_repl_offset:{}\n", master_repl_offset));
                 }
+                if let (Some(replid2), Some(second_offset)) = (
+                    &self.replication.master_replid2,
+                    &self.replication.second_repl_offset,
+                ) {
+                    res.push(format!("master_replid2:{}\n", replid2));
+                    res.push(format!("second_repl_offset:{}\n", second_offset));
+                }
                 if let Some(master_host) = &self.replication.master_host {
                     res.push(format!("master_host:{}\n", master_host));
                 }
                 if let Some(master_port) = &self.replication.master_port {
                     res.push(format!("master_port:{}\n", master_port));
                 }
+                if self.replication.role == ReplicaRole::SLAVE {
+                    let replication = &self.replication;
+                    let status = if replication.master_link_up {
+                        "up"
+                    } else {
+                        "down"
+                    };
+                    let last_io = match replication.master_last_io {
+                        Some(t) => t.elapsed().as_secs() as i64,
+                        None => -1,
+                    };
+                    res.push(format!("master_link_status:{}\n", status));
+                    res.push(format!("master_last_io_seconds_ago:{}\n", last_io));
+                    res.push(format!(
+                        "master_sync_in_progress:{}\n",
+                        replication.master_sync_in_progress as u8
+                    ));
+                    res.push(format!(
+                        "slave_read_only:{}\n",
+                        replication.config.read_only as u8
+                    ));
+                    if !replication.master_link_up {
+                        let down_since = match replication.master_link_down_since {
+                            Some(t) => t.elapsed().as_secs() as i64,
+                            None => -1,
+                        };
+                        res.push(format!("master_link_down_since_seconds:{}\n", down_since));
+                    }
+                }
+                let backlog = &self.replication.backlog;
+                let offset = self.replication.master_repl_offset.unwrap_or(0);
+                res.push(format!(
+                    "repl_backlog_active:{}\n",
+                    backlog.is_active() as u8
+                ));
+                res.push(format!("repl_backlog_size:{}\n", backlog.size()));
+                res.push(format!(
+                    "repl_backlog_first_byte_offset:{}\n",
+                    offset + 1 - backlog.histlen() as u64
+                ));
+                res.push(format!("repl_backlog_histlen:{}\n", backlog.histlen()));
                 Some(res.join(""))
             }
             _ => None,
@@ -95,7 +610,7 @@ impl Info {
     pub fn get_all(&self) -> String {
         let mut res = Vec::new();
 
-        let sections = vec!["replication"];
+        let sections = ["stats", "replication"];
 
         for section in sections {
             if let Some(s) = self.get_section(section) {
@@ -109,18 +624,15 @@ impl Info {
 
 pub fn create_info(
     port: u16,
+    rdb_path: PathBuf,
+    protected_mode: bool,
     role: ReplicaRole,
     master_host: Option<String>,
     master_port: Option<u16>,
+    config: ReplicationConfig,
 ) -> Info {
     let master_replid = match role {
-        ReplicaRole::MASTER => Some(
-            rand::thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(40)
-                .map(char::from)
-                .collect(),
-        ),
+        ReplicaRole::MASTER => Some(new_replid()),
         ReplicaRole::SLAVE => None,
     };
     let master_repl_offset = match role {
@@ -129,13 +641,130 @@ pub fn create_info(
     };
 
     Info::new(
-        Server { tcp_port: port },
+        Server {
+            tcp_port: port,
+            rdb_path,
+            protected_mode,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+        },
         Replication {
             role,
             master_replid,
             master_repl_offset,
+            master_replid2: None,
+            second_repl_offset: None,
             master_host,
             master_port,
+            backlog: Backlog::new(config.repl_backlog_size),
+            replicas: Vec::new(),
+            next_replica_id: 0,
+            acks: Arc::new(Notify::new()),
+            master_link_up: false,
+            master_link_down_since: None,
+            master_last_io: None,
+            master_sync_in_progress: false,
+            full_sync_scheduled: false,
+            link: None,
+            config,
         },
     )
 }
+
+fn new_replid() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master(repl_backlog_size: usize) -> Info {
+        create_info(
+            6379,
+            PathBuf::from("dump.rdb"),
+            true,
+            ReplicaRole::MASTER,
+            None,
+            None,
+            ReplicationConfig {
+                repl_backlog_size,
+                serve_stale_data: true,
+                read_only: true,
+                diskless_sync: true,
+                diskless_sync_delay: 5,
+                diskless_load: DisklessLoad::Disabled,
+                timeout: Duration::from_secs(60),
+                ping_replica_period: Duration::from_secs(10),
+                min_replicas_to_write: 0,
+                min_replicas_max_lag: 10,
+                masteruser: None,
+                masterauth: None,
+                tls: None,
+            },
+        )
+    }
+
+    #[test]
+    fn partial_resync_sends_what_the_replica_missed() {
+        let mut info = master(16);
+        let replication = &mut info.replication;
+        replication.feed(b"0123456789");
+        let replid = replication.psync_state().unwrap().0.clone();
+
+        // offsets are those of the next byte wanted, counting from 1
+        assert_eq!(
+            replication.partial_resync(&replid, 1),
+            Some(b"0123456789".to_vec())
+        );
+        assert_eq!(
+            replication.partial_resync(&replid, 5),
+            Some(b"456789".to_vec())
+        );
+        assert_eq!(replication.partial_resync(&replid, 11), Some(Vec::new()));
+        assert_eq!(replication.partial_resync(&replid, 12), None);
+        assert_eq!(replication.partial_resync(&replid, 0), None);
+        assert_eq!(replication.partial_resync("unknown", 5), None);
+
+        // the backlog keeps the last 16 bytes, from offset 5 on
+        replication.feed(b"abcdefghij");
+        assert_eq!(
+            replication.partial_resync(&replid, 5),
+            Some(b"456789abcdefghij".to_vec())
+        );
+        assert_eq!(replication.partial_resync(&replid, 4), None);
+    }
+
+    #[test]
+    fn partial_resync_with_the_id_before_a_promotion() {
+        let mut info = master(16);
+        let replication = &mut info.replication;
+        replication.feed(b"0123456789");
+        let old_replid = replication.psync_state().unwrap().0.clone();
+
+        replication.follow("localhost".to_string(), 6380);
+        replication.promote();
+        replication.feed(b"abc");
+        let new_replid = replication.psync_state().unwrap().0.clone();
+        assert_ne!(old_replid, new_replid);
+
+        // the old id is good up to where it ended
+        assert_eq!(
+            replication.partial_resync(&old_replid, 9),
+            Some(b"89abc".to_vec())
+        );
+        assert_eq!(
+            replication.partial_resync(&old_replid, 11),
+            Some(b"abc".to_vec())
+        );
+        assert_eq!(replication.partial_resync(&old_replid, 12), None);
+        assert_eq!(
+            replication.partial_resync(&new_replid, 12),
+            Some(b"bc".to_vec())
+        );
+    }
+}
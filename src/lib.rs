@@ -0,0 +1,588 @@
+use anyhow::{bail, Result};
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use std::ffi::OsString;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+pub mod acl;
+pub mod background;
+pub mod category;
+pub mod client;
+pub mod command;
+pub mod config;
+pub mod data;
+pub mod error;
+pub mod file;
+pub mod flags;
+pub mod functions;
+pub mod info;
+pub mod module;
+pub mod net;
+pub mod notify;
+pub mod pubsub;
+pub mod replication;
+pub mod resp;
+pub mod scripting;
+pub mod state;
+pub mod tls;
+pub mod utils;
+
+/// How long to wait before accepting again after it failed, e.g. when out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Accept connections on `listener`, over TLS if there is an acceptor.
+/// Failing to accept one connection does not stop the listener.
+async fn accept_connections(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    state: state::State,
+) -> Result<()> {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("(ERROR) Accepting a connection failed: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let local_addr = match stream.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => {
+                eprintln!(
+                    "(ERROR) Connection from {} has no local address: {}",
+                    addr, e
+                );
+                continue;
+            }
+        };
+        let client = client::Client::with_addr(addr, local_addr);
+        let tls = tls.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => net::split(stream),
+                    Err(e) => {
+                        eprintln!("(ERROR) TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                },
+                None => net::split(stream),
+            };
+            let _ = handle_connection(reader, writer, client, state).await;
+        });
+    }
+}
+
+/// Accept connections on a unix socket. Their clients have no address.
+async fn accept_unix_connections(listener: UnixListener, state: state::State) -> Result<()> {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("(ERROR) Accepting a unix socket connection failed: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let (reader, writer) = net::split(stream);
+        tokio::spawn(handle_connection(
+            reader,
+            writer,
+            client::Client::new(),
+            state.clone(),
+        ));
+    }
+}
+
+async fn handle_connection(
+    reader: net::Reader,
+    mut writer: net::Writer,
+    mut client: client::Client,
+    state: state::State,
+) -> Result<()> {
+    println!("(INFO) Accepted new connection");
+    info::Stats::incr(&state.info.read().await.stats.total_connections_received);
+
+    if is_protected(&client, &state).await {
+        let denied = resp::RespOut::Error(error::Error::ProtectedMode.to_string());
+        writer.write_all(&denied.serialize()).await?;
+        return Ok(());
+    }
+
+    let (push_tx, mut push_rx) = mpsc::unbounded_channel();
+    client.push = Some(push_tx);
+    client.authenticated = !state.acl.read().await.requires_auth();
+    state.clients.write().await.update(&client);
+
+    let mut reader = resp::RespReader::new(reader);
+
+    let res = serve_client(&mut reader, &mut writer, &mut push_rx, &state, &mut client).await;
+
+    // subscriptions outlive the connection otherwise
+    state.pubsub.write().await.unsubscribe_all(&mut client);
+
+    let res = match client.replication_stream.take() {
+        Some(stream) if res.is_ok() => {
+            serve_replica(reader, writer, stream, &state, &mut client).await
+        }
+        _ => res,
+    };
+
+    state.clients.write().await.remove(client.id);
+    res
+}
+
+/// Whether the client must be refused because of protected mode: it comes from outside
+/// while the default user needs no password. Unix socket clients are local.
+async fn is_protected(client: &client::Client, state: &state::State) -> bool {
+    let Some(addr) = client.addr else {
+        return false;
+    };
+    state.info.read().await.server.protected_mode()
+        && !addr.ip().to_canonical().is_loopback()
+        && !state.acl.read().await.default_user_has_password()
+}
+
+/// Answer requests and deliver pushed messages until the connection closes or becomes a replica.
+async fn serve_client(
+    reader: &mut resp::RespReader<net::Reader>,
+    writer: &mut net::Writer,
+    push_rx: &mut UnboundedReceiver<resp::RespOut>,
+    state: &state::State,
+    client: &mut client::Client,
+) -> Result<()> {
+    let kill = Arc::clone(&client.kill);
+    loop {
+        reader.set_limits(if client.authenticated {
+            resp::Limits::DEFAULT
+        } else {
+            resp::Limits::UNAUTHENTICATED
+        });
+        let res = tokio::select! {
+            req = reader.read_request() => match req {
+                Ok(Some((req, _))) => {
+                    let res = command::handle(req, state, client).await;
+                    client.query_buffer = reader.buffered();
+                    state.clients.write().await.update(client);
+                    if client.skip_reply() {
+                        continue;
+                    }
+                    res
+                }
+                Ok(None) => break,
+                Err(e) if e.is::<resp::LimitExceeded>() => {
+                    let res = resp::RespOut::Error(format!("ERR Protocol error: {}", e));
+                    writer.write_all(&res.serialize()).await?;
+                    break;
+                }
+                Err(e) => vec![resp::RespOut::Error(format!("ERR Protocol error: {}", e))],
+            },
+            Some(msg) = push_rx.recv() => vec![msg],
+            _ = kill.notified() => break,
+        };
+
+        for res in res {
+            writer.write_all(&res.serialize()).await?;
+        }
+
+        if client.replication_stream.is_some() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forward the replication stream to a connection that has become a replica.
+async fn serve_replica(
+    mut reader: resp::RespReader<net::Reader>,
+    mut writer: net::Writer,
+    mut stream: UnboundedReceiver<Vec<u8>>,
+    state: &state::State,
+    client: &mut client::Client,
+) -> Result<()> {
+    println!("(INFO) Connection became a replica");
+    state.clients.write().await.update(client);
+
+    let res =
+        forward_replication_stream(&mut reader, &mut writer, &mut stream, state, client).await;
+
+    if let Some(id) = client.replica_id {
+        state.info.write().await.replication.remove_replica(id);
+    }
+
+    res
+}
+
+async fn forward_replication_stream(
+    reader: &mut resp::RespReader<net::Reader>,
+    writer: &mut net::Writer,
+    stream: &mut UnboundedReceiver<Vec<u8>>,
+    state: &state::State,
+    client: &mut client::Client,
+) -> Result<()> {
+    let kill = Arc::clone(&client.kill);
+    loop {
+        tokio::select! {
+            bytes = stream.recv() => match bytes {
+                Some(bytes) => writer.write_all(&bytes).await?,
+                None => break,
+            },
+            req = reader.read_request() => match req? {
+                // replicas do not get replies
+                Some((req, _)) => {
+                    command::handle(req, state, client).await;
+                    state.clients.write().await.update(client);
+                }
+                None => break,
+            },
+            _ = kill.notified() => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Start a Redis server
+#[derive(Parser)]
+#[command(version, about, long_about = None, args_override_self = true)]
+pub struct Args {
+    /// redis.conf file to read the configuration from, overridden by the other options
+    config_file: Option<PathBuf>,
+
+    /// Port to listen on, 0 to not listen on TCP
+    #[arg(short, long, default_value_t = 6379)]
+    port: u16,
+
+    /// Addresses to listen on, like "127.0.0.1 -::1"; `*` and `::*` mean any address
+    /// and a leading `-` makes an address optional
+    #[arg(long, default_value = "127.0.0.1 -::1", allow_hyphen_values = true)]
+    bind: String,
+
+    /// Only accept clients on the loopback interface while the default user has no password (yes/no)
+    #[arg(long, default_value = "yes", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    protected_mode: bool,
+
+    /// Path of a unix socket to listen on as well
+    #[arg(long)]
+    unixsocket: Option<PathBuf>,
+
+    /// Permissions of the unix socket in octal, like 700, left to the umask if not given
+    #[arg(long, value_parser = parse_octal)]
+    unixsocketperm: Option<u32>,
+
+    /// Port to listen on for TLS connections, none to disable
+    #[arg(long)]
+    tls_port: Option<u16>,
+
+    /// Certificate of the server, also presented to the master, PEM encoded
+    #[arg(long)]
+    tls_cert_file: Option<PathBuf>,
+
+    /// Private key of the certificate, PEM encoded
+    #[arg(long)]
+    tls_key_file: Option<PathBuf>,
+
+    /// CA certificates that certificates of clients and of the master are checked against
+    #[arg(long)]
+    tls_ca_cert_file: Option<PathBuf>,
+
+    /// Whether TLS clients must present a certificate signed by the CA
+    #[arg(long, value_enum, ignore_case = true, default_value_t = tls::AuthClients::Yes)]
+    tls_auth_clients: tls::AuthClients,
+
+    /// Whether a replica connects to its master over TLS (yes/no)
+    #[arg(long, default_value = "no", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    tls_replication: bool,
+
+    /// Config for replication, the host and port of the master
+    #[arg(long, alias = "slaveof", value_parser = config::parse_master)]
+    replicaof: Option<(String, u16)>,
+
+    /// Size of the backlog used for partial resynchronization, like 1mb
+    #[arg(long, default_value = "1mb", value_parser = config::parse_memory)]
+    repl_backlog_size: usize,
+
+    /// Whether a replica answers reads while its link to the master is down (yes/no)
+    #[arg(long, default_value = "yes", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    replica_serve_stale_data: bool,
+
+    /// Whether a replica rejects writes from its clients (yes/no)
+    #[arg(long, default_value = "yes", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    replica_read_only: bool,
+
+    /// Whether full resyncs stream the snapshot to replicas without writing it to disk (yes/no)
+    #[arg(long, default_value = "yes", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    repl_diskless_sync: bool,
+
+    /// Seconds to wait for more replicas before starting a diskless transfer
+    #[arg(long, default_value_t = 5)]
+    repl_diskless_sync_delay: u64,
+
+    /// How a replica loads the snapshot it receives on a full resync
+    #[arg(long, value_enum, ignore_case = true, default_value_t = info::DisklessLoad::Disabled)]
+    repl_diskless_load: info::DisklessLoad,
+
+    /// Seconds without data from the master before a replica drops the link and reconnects
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    repl_timeout: u64,
+
+    /// Seconds between the PINGs a master sends its replicas, so they can tell the link is alive
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    repl_ping_replica_period: u64,
+
+    /// Refuse writes unless this many replicas are connected with a small enough lag, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    min_replicas_to_write: usize,
+
+    /// Maximum lag in seconds for a replica to count towards min-replicas-to-write
+    #[arg(long, default_value_t = 10)]
+    min_replicas_max_lag: u64,
+
+    /// Password clients must authenticate with using AUTH
+    #[arg(long)]
+    requirepass: Option<String>,
+
+    /// File the users are loaded from at startup and by ACL LOAD, and saved to by ACL SAVE
+    #[arg(long)]
+    aclfile: Option<PathBuf>,
+
+    /// User a replica authenticates as with its master
+    #[arg(long)]
+    masteruser: Option<String>,
+
+    /// Password a replica authenticates with to its master
+    #[arg(long)]
+    masterauth: Option<String>,
+
+    /// Classes of keyspace events published to pub/sub, e.g. "KEA", empty to disable
+    #[arg(long, default_value = "")]
+    notify_keyspace_events: String,
+
+    /// Memory limit for the data, like 100mb, 0 for none. See maxmemory-policy for what happens when it is reached
+    #[arg(long, default_value = "0", value_parser = config::parse_memory)]
+    maxmemory: usize,
+
+    /// Which keys are deleted to stay within maxmemory
+    #[arg(long, value_enum, ignore_case = true, default_value_t = data::EvictionPolicy::Noeviction)]
+    maxmemory_policy: data::EvictionPolicy,
+
+    /// Milliseconds a script may run before other clients get BUSY errors and it can be killed
+    #[arg(long, default_value_t = 5000)]
+    busy_reply_threshold: u64,
+
+    /// Name of a module the server was built with to load at startup, can be given several times
+    #[arg(long)]
+    loadmodule: Vec<String>,
+
+    /// Directory of the RDB file
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+
+    /// Name of the RDB file
+    #[arg(long, default_value = "dump.rdb")]
+    dbfilename: String,
+}
+
+fn parse_octal(s: &str) -> Result<u32> {
+    Ok(u32::from_str_radix(s, 8)?)
+}
+
+/// The command line with the directives of the config file, if one is given, put before
+/// the other options so that these override them.
+fn command_line() -> Result<Vec<OsString>> {
+    let mut argv = std::env::args_os().collect::<Vec<_>>();
+    let Some(path) = argv
+        .get(1)
+        .filter(|arg| !arg.to_string_lossy().starts_with('-'))
+    else {
+        return Ok(argv);
+    };
+
+    let mut directives = Vec::new();
+    for directive in config::read(Path::new(path))? {
+        let value = directive.args.join(" ");
+        // check each directive on its own, to tell where the bad one is
+        if let Err(reason) = config::parse_setting(&directive.name, &value) {
+            bail!(
+                "{}: '{} {}': {}",
+                directive.location,
+                directive.name,
+                value,
+                reason
+            );
+        }
+        directives.push(config::option(&directive.name, &value).into());
+    }
+    argv.splice(2..2, directives);
+    Ok(argv)
+}
+
+/// Run the server as the command line of this process says.
+/// `--loadmodule <name>` loads the one of `available` with that name.
+pub async fn run(available: Vec<Box<dyn module::Module>>) -> Result<()> {
+    let matches = Args::command().get_matches_from(command_line()?);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config = config::Config::new(&matches, args.config_file.clone());
+
+    let tls = match (&args.tls_cert_file, &args.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => Some(tls::TlsConfig {
+            cert_file: cert_file.clone(),
+            key_file: key_file.clone(),
+            ca_cert_file: args.tls_ca_cert_file.clone(),
+            auth_clients: args.tls_auth_clients,
+        }),
+        (None, None) => None,
+        _ => bail!("tls-cert-file and tls-key-file must be given together"),
+    };
+    let tls_acceptor = match (&tls, args.tls_port) {
+        (Some(tls), Some(_)) => Some(tls.acceptor()?),
+        (None, Some(_)) => bail!("tls-port requires tls-cert-file and tls-key-file"),
+        (_, None) => None,
+    };
+    let tls_connector = match (&tls, args.tls_replication) {
+        (Some(tls), true) => Some(tls.connector()?),
+        (None, true) => bail!("tls-replication requires tls-cert-file and tls-key-file"),
+        (_, false) => None,
+    };
+
+    let data: data::SharedData = Arc::new(RwLock::new(data::InMemoryData::new()));
+
+    let role;
+    let master_host;
+    let master_port;
+
+    if let Some((host, port)) = &args.replicaof {
+        println!("(INFO) Replicating from {}:{}", host, port);
+        role = info::ReplicaRole::SLAVE;
+        master_host = Some(host.clone());
+        master_port = Some(*port);
+    } else {
+        role = info::ReplicaRole::MASTER;
+        master_host = None;
+        master_port = None;
+    }
+
+    let info = Arc::new(RwLock::new(info::create_info(
+        args.port,
+        args.dir.join(&args.dbfilename),
+        args.protected_mode,
+        role,
+        master_host,
+        master_port,
+        info::ReplicationConfig {
+            repl_backlog_size: args.repl_backlog_size,
+            serve_stale_data: args.replica_serve_stale_data,
+            read_only: args.replica_read_only,
+            diskless_sync: args.repl_diskless_sync,
+            diskless_sync_delay: args.repl_diskless_sync_delay,
+            diskless_load: args.repl_diskless_load,
+            timeout: Duration::from_secs(args.repl_timeout),
+            ping_replica_period: Duration::from_secs(args.repl_ping_replica_period),
+            min_replicas_to_write: args.min_replicas_to_write,
+            min_replicas_max_lag: args.min_replicas_max_lag,
+            masteruser: args.masteruser.clone(),
+            masterauth: args.masterauth.clone(),
+            tls: tls_connector,
+        },
+    )));
+
+    {
+        let mut info = info.write().await;
+        info.server.set_maxmemory(args.maxmemory);
+        info.server.set_maxmemory_policy(args.maxmemory_policy);
+    }
+
+    let mut modules = module::Modules::new();
+    for name in &args.loadmodule {
+        let Some(module) = available.iter().find(|module| module.name() == name) else {
+            bail!("Module {} not found", name);
+        };
+        modules.load(&**module)?;
+        println!("(INFO) Module '{}' loaded", name);
+    }
+    let commands = command::Commands::new(&modules)?;
+
+    let mut acl = acl::Acl::new(&commands, args.requirepass.as_deref(), args.aclfile.clone());
+    if args.aclfile.is_some() {
+        acl.load(&commands)?;
+    }
+
+    let mut pubsub = pubsub::PubSub::new();
+    pubsub.set_notify_flags(notify::parse_flags(&args.notify_keyspace_events)?);
+
+    let state = state::State {
+        data,
+        info,
+        pubsub: Arc::new(RwLock::new(pubsub)),
+        scripts: Arc::new(scripting::Scripts::new(Duration::from_millis(
+            args.busy_reply_threshold,
+        ))),
+        functions: Arc::new(RwLock::new(functions::Functions::new())),
+        modules: Arc::new(modules),
+        commands: Arc::new(commands),
+        acl: Arc::new(RwLock::new(acl)),
+        config: Arc::new(RwLock::new(config)),
+        clients: Arc::new(RwLock::new(client::Clients::new())),
+    };
+
+    // Start background task
+    tokio::spawn(background::delete_expired(state.clone()));
+    tokio::spawn(background::ping_replicas(state.clone()));
+
+    // Replica task
+    if role == info::ReplicaRole::SLAVE {
+        let link = replication::MasterLink::start(state.clone());
+        state.info.write().await.replication.set_link(link);
+    }
+
+    let mut listeners = JoinSet::new();
+    if args.port != 0 {
+        for listener in net::bind(&args.bind, args.port)? {
+            println!("(INFO) Listening on {}", listener.local_addr()?);
+            listeners.spawn(accept_connections(listener, None, state.clone()));
+        }
+    }
+    if let Some(port) = args.tls_port {
+        for listener in net::bind(&args.bind, port)? {
+            println!("(INFO) Listening for TLS on {}", listener.local_addr()?);
+            listeners.spawn(accept_connections(
+                listener,
+                tls_acceptor.clone(),
+                state.clone(),
+            ));
+        }
+    }
+    if let Some(path) = &args.unixsocket {
+        println!("(INFO) Listening on unix socket {}", path.display());
+        // a socket left behind by a previous run would make binding fail
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = args.unixsocketperm {
+            std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+        }
+        listeners.spawn(accept_unix_connections(listener, state.clone()));
+    }
+
+    if listeners.is_empty() {
+        bail!("Configured to not listen anywhere, see bind, port, tls-port and unixsocket");
+    }
+
+    // listeners only stop when accepting fails
+    while let Some(res) = listeners.join_next().await {
+        res??;
+    }
+    Ok(())
+}
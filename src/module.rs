@@ -0,0 +1,176 @@
+use crate::data::Data;
+use crate::error;
+use crate::resp::RespOut;
+use anyhow::{bail, Result};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// The API for extending the server with native commands and value types.
+// A module is registered at startup and its commands are dispatched like the built-in ones.
+
+pub type SharedModules = Arc<Modules>;
+
+/// A set of commands and value types registered together.
+pub trait Module: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn commands(&self) -> Vec<Arc<dyn ModuleCommand>>;
+
+    fn types(&self) -> Vec<Arc<dyn ModuleType>> {
+        Vec::new()
+    }
+}
+
+/// A command added by a module.
+pub trait ModuleCommand: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Number of arguments including the command name. Negative means at least that many.
+    fn arity(&self) -> i64;
+
+    /// Space separated flags like in Redis, e.g. `write deny-oom` or `readonly fast`.
+    /// Write commands are refused on read-only replicas.
+    fn flags(&self) -> &str;
+
+    /// Positions of the first and last key and the step between keys, like in Redis.
+    /// A negative last key counts from the end, `(0, 0, 0)` means no keys.
+    fn key_positions(&self) -> (i64, i64, i64);
+
+    /// Run the command, `args` excluding the command name.
+    /// It runs while holding the data lock, so it must not block.
+    fn call(&self, ctx: &mut Context, args: &[String]) -> error::Result<RespOut>;
+}
+
+/// A value type added by a module, stored under keys like strings are.
+pub trait ModuleType: Send + Sync {
+    /// Name in RDB files, see [`ModuleValue::type_name`].
+    /// Like in Redis it must be 9 characters: letters, digits, `-` or `_`.
+    fn name(&self) -> &str;
+
+    /// Recreate a value from what [`ModuleValue::save`] returned.
+    fn load(&self, bytes: &[u8]) -> Result<Box<dyn ModuleValue>>;
+}
+
+/// A value of a module type.
+pub trait ModuleValue: Any + Send + Sync {
+    /// The name of its [`ModuleType`].
+    fn type_name(&self) -> &str;
+
+    /// Serialize the value for RDB files and full resyncs.
+    fn save(&self) -> Vec<u8>;
+
+    /// Bytes taken by the value, counted against maxmemory. The size of what it saves by default.
+    fn memory_usage(&self) -> usize {
+        self.save().len()
+    }
+
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// What a module command gets to work with.
+pub struct Context<'a> {
+    data: &'a mut dyn Data,
+    replicate: bool,
+    events: Vec<(String, String)>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(data: &'a mut dyn Data) -> Self {
+        Self {
+            data,
+            replicate: false,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn data(&mut self) -> &mut dyn Data {
+        self.data
+    }
+
+    /// Send the command as it was called to the replicas once it is done.
+    pub fn replicate_verbatim(&mut self) {
+        self.replicate = true;
+    }
+
+    pub fn should_replicate(&self) -> bool {
+        self.replicate
+    }
+
+    /// Publish a keyspace event of the module class, once the command is done.
+    pub fn notify_keyspace_event(&mut self, event: &str, key: &str) {
+        self.events.push((event.to_string(), key.to_string()));
+    }
+
+    pub fn take_events(&mut self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// The registered modules, their commands and their types.
+#[derive(Default)]
+pub struct Modules {
+    names: Vec<String>,
+    /// The commands by upper case name, with the name of their module.
+    commands: HashMap<String, (String, Arc<dyn ModuleCommand>)>,
+    types: HashMap<String, Arc<dyn ModuleType>>,
+}
+
+impl Modules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(&mut self, module: &dyn Module) -> Result<()> {
+        if self.names.iter().any(|name| name == module.name()) {
+            bail!("Module {} already loaded", module.name());
+        }
+
+        let commands = module.commands();
+        let types = module.types();
+        for command in &commands {
+            if self.commands.contains_key(&command.name().to_uppercase()) {
+                bail!("Command {} already exists", command.name());
+            }
+        }
+        for t in &types {
+            let name = t.name();
+            let valid = name.len() == 9
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                bail!("Invalid module type name {}", name);
+            }
+            if self.types.contains_key(name) {
+                bail!("Module type {} already exists", name);
+            }
+        }
+
+        self.names.push(module.name().to_string());
+        for command in commands {
+            let module = module.name().to_string();
+            self.commands
+                .insert(command.name().to_uppercase(), (module, command));
+        }
+        for t in types {
+            self.types.insert(t.name().to_string(), t);
+        }
+        Ok(())
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The commands with the name of their module.
+    pub fn commands(&self) -> impl Iterator<Item = &(String, Arc<dyn ModuleCommand>)> {
+        self.commands.values()
+    }
+
+    pub fn get_type(&self, name: &str) -> Option<&Arc<dyn ModuleType>> {
+        self.types.get(name)
+    }
+}
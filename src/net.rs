@@ -0,0 +1,67 @@
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+// Connections are plain TCP, TLS or unix sockets, so both ends of the protocol work on boxed halves.
+
+pub type Reader = Box<dyn AsyncRead + Unpin + Send>;
+pub type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Split a connection into halves that can be used independently.
+pub fn split<S>(stream: S) -> (Reader, Writer)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    (Box::new(reader), Box::new(writer))
+}
+
+/// Listen on `port` of each address of `bind`, like `127.0.0.1 -::1`.
+/// `*` and `::*` stand for any IPv4 and IPv6 address, and addresses starting with `-`
+/// are optional: they are skipped if the host does not have them.
+pub fn bind(bind: &str, port: u16) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for addr in bind.split_whitespace() {
+        let (optional, addr) = match addr.strip_prefix('-') {
+            Some(addr) => (true, addr),
+            None => (false, addr),
+        };
+        let ip = match addr {
+            "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            "::*" => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            addr => addr
+                .parse()
+                .with_context(|| format!("Invalid bind address '{}'", addr))?,
+        };
+        match listen(SocketAddr::new(ip, port)) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) if optional && UNAVAILABLE.contains(&e.kind()) => {
+                println!("(INFO) Skipping optional bind address {}: {}", addr, e);
+            }
+            Err(e) => bail!("Could not bind to {}:{}: {}", addr, port, e),
+        }
+    }
+    Ok(listeners)
+}
+
+/// Connections waiting to be accepted, tcp-backlog in Redis.
+const BACKLOG: i32 = 511;
+
+/// Errors binding to an address the host does not have, or without IPv6 support.
+const UNAVAILABLE: &[ErrorKind] = &[ErrorKind::AddrNotAvailable, ErrorKind::Unsupported];
+
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // IPv6 sockets would take the IPv4 addresses as well otherwise, so `* ::*` could not bind
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
@@ -0,0 +1,97 @@
+use anyhow::{bail, Result};
+
+// Classes of keyspace events, selected with notify-keyspace-events.
+// See https://redis.io/docs/manual/keyspace-notifications/
+
+/// `K`: publish to `__keyspace@<db>__:<key>`.
+pub const KEYSPACE: u32 = 1 << 0;
+/// `E`: publish to `__keyevent@<db>__:<event>`.
+pub const KEYEVENT: u32 = 1 << 1;
+/// `g`: generic commands like DEL and EXPIRE.
+pub const GENERIC: u32 = 1 << 2;
+/// `$`: string commands.
+pub const STRING: u32 = 1 << 3;
+// `l`, `s`, `h`, `z` and `t` are accepted like in Redis, but raise nothing as there are no
+// such types yet.
+
+/// `l`: list commands.
+pub const LIST: u32 = 1 << 4;
+/// `s`: set commands.
+pub const SET: u32 = 1 << 5;
+/// `h`: hash commands.
+pub const HASH: u32 = 1 << 6;
+/// `z`: sorted set commands.
+pub const ZSET: u32 = 1 << 7;
+/// `x`: keys deleted because they expired.
+pub const EXPIRED: u32 = 1 << 8;
+/// `e`: keys deleted because of maxmemory.
+pub const EVICTED: u32 = 1 << 9;
+/// `t`: stream commands.
+pub const STREAM: u32 = 1 << 10;
+/// `m`: key misses, not included in `A`.
+pub const KEY_MISS: u32 = 1 << 11;
+/// `d`: module key type events.
+pub const MODULE: u32 = 1 << 12;
+/// `n`: new keys, not included in `A`.
+pub const NEW: u32 = 1 << 13;
+
+/// `A`: alias for `g$lshzxetd`.
+pub const ALL: u32 =
+    GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM | MODULE;
+
+const CLASSES: &[(char, u32)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('d', MODULE),
+];
+
+/// Parse a notify-keyspace-events flags string like `KEA` or `Kx`.
+pub fn parse_flags(flags: &str) -> Result<u32> {
+    let mut res = 0;
+    for c in flags.chars() {
+        res |= match c {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            c => match CLASSES.iter().find(|(flag, _)| *flag == c) {
+                Some((_, class)) => *class,
+                None => bail!("Invalid event class character. Use 'Ag$lshzxeKEtmdn'."),
+            },
+        };
+    }
+    Ok(res)
+}
+
+/// The flags string for `flags`, the inverse of [`parse_flags`].
+pub fn flags_to_string(flags: u32) -> String {
+    let mut res = String::new();
+    if flags & ALL == ALL {
+        res.push('A');
+    } else {
+        for (c, class) in CLASSES {
+            if flags & class != 0 {
+                res.push(*c);
+            }
+        }
+    }
+    for (c, class) in [
+        ('K', KEYSPACE),
+        ('E', KEYEVENT),
+        ('m', KEY_MISS),
+        ('n', NEW),
+    ] {
+        if flags & class != 0 {
+            res.push(c);
+        }
+    }
+    res
+}
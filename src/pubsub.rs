@@ -0,0 +1,218 @@
+use crate::client::Client;
+use crate::notify;
+use crate::resp::RespOut;
+use crate::utils::glob_match;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::RwLock;
+
+pub type SharedPubSub = Arc<RwLock<PubSub>>;
+
+/// Where messages for a client are pushed, keyed by client id.
+type Subscribers = HashMap<u64, UnboundedSender<RespOut>>;
+
+/// Subscribers by channel or pattern.
+#[derive(Default)]
+struct Subscriptions {
+    subscribers: HashMap<String, Subscribers>,
+}
+
+impl Subscriptions {
+    fn add(&mut self, name: &str, id: u64, tx: UnboundedSender<RespOut>) {
+        self.subscribers
+            .entry(name.to_string())
+            .or_default()
+            .insert(id, tx);
+    }
+
+    fn remove(&mut self, name: &str, id: u64) {
+        if let Some(subscribers) = self.subscribers.get_mut(name) {
+            subscribers.remove(&id);
+            // only channels with subscribers exist
+            if subscribers.is_empty() {
+                self.subscribers.remove(name);
+            }
+        }
+    }
+
+    fn count(&self, name: &str) -> usize {
+        self.subscribers.get(name).map_or(0, |s| s.len())
+    }
+
+    fn names(&self) -> impl Iterator<Item = &String> {
+        self.subscribers.keys()
+    }
+
+    fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    channels: Subscriptions,
+    patterns: Subscriptions,
+    /// Sharded channels, which like keys belong to a hash slot.
+    shard_channels: Subscriptions,
+    /// Keyspace event classes to publish, see [`notify`].
+    notify_flags: u32,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, channel: &str, id: u64, tx: UnboundedSender<RespOut>) {
+        self.channels.add(channel, id, tx);
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str, id: u64) {
+        self.channels.remove(channel, id);
+    }
+
+    pub fn psubscribe(&mut self, pattern: &str, id: u64, tx: UnboundedSender<RespOut>) {
+        self.patterns.add(pattern, id, tx);
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &str, id: u64) {
+        self.patterns.remove(pattern, id);
+    }
+
+    pub fn ssubscribe(&mut self, channel: &str, id: u64, tx: UnboundedSender<RespOut>) {
+        self.shard_channels.add(channel, id, tx);
+    }
+
+    pub fn sunsubscribe(&mut self, channel: &str, id: u64) {
+        self.shard_channels.remove(channel, id);
+    }
+
+    /// Remove all subscriptions of a client, e.g. when it disconnects.
+    pub fn unsubscribe_all(&mut self, client: &mut Client) {
+        for channel in client.channels.drain() {
+            self.channels.remove(&channel, client.id);
+        }
+        for pattern in client.patterns.drain() {
+            self.patterns.remove(&pattern, client.id);
+        }
+        for channel in client.shard_channels.drain() {
+            self.shard_channels.remove(&channel, client.id);
+        }
+    }
+
+    /// Send a message to the subscribers of the channel and of matching patterns.
+    /// Returns the number of clients that received it.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+
+        if let Some(subscribers) = self.channels.subscribers.get(channel) {
+            for tx in subscribers.values() {
+                let msg = RespOut::Array(vec![
+                    RespOut::BulkString("message".to_string()),
+                    RespOut::BulkString(channel.to_string()),
+                    RespOut::BulkString(message.to_string()),
+                ]);
+                if tx.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        for (pattern, subscribers) in &self.patterns.subscribers {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for tx in subscribers.values() {
+                let msg = RespOut::Array(vec![
+                    RespOut::BulkString("pmessage".to_string()),
+                    RespOut::BulkString(pattern.clone()),
+                    RespOut::BulkString(channel.to_string()),
+                    RespOut::BulkString(message.to_string()),
+                ]);
+                if tx.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        receivers
+    }
+
+    /// Send a message to the subscribers of a sharded channel.
+    /// Patterns never match sharded channels.
+    /// Returns the number of clients that received it.
+    pub fn spublish(&self, channel: &str, message: &str) -> usize {
+        let mut receivers = 0;
+
+        if let Some(subscribers) = self.shard_channels.subscribers.get(channel) {
+            for tx in subscribers.values() {
+                let msg = RespOut::Array(vec![
+                    RespOut::BulkString("smessage".to_string()),
+                    RespOut::BulkString(channel.to_string()),
+                    RespOut::BulkString(message.to_string()),
+                ]);
+                if tx.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+
+        receivers
+    }
+
+    pub fn notify_flags(&self) -> u32 {
+        self.notify_flags
+    }
+
+    pub fn set_notify_flags(&mut self, flags: u32) {
+        self.notify_flags = flags;
+    }
+
+    /// Publish a keyspace event for `key` if its class is enabled.
+    pub fn notify_keyspace_event(&self, class: u32, event: &str, key: &str) {
+        if self.notify_flags & class == 0 {
+            return;
+        }
+
+        // there is only a single database
+        let db = 0;
+        if self.notify_flags & notify::KEYSPACE != 0 {
+            self.publish(&format!("__keyspace@{}__:{}", db, key), event);
+        }
+        if self.notify_flags & notify::KEYEVENT != 0 {
+            self.publish(&format!("__keyevent@{}__:{}", db, event), key);
+        }
+    }
+
+    /// Active channels, optionally only those matching a pattern.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        Self::matching(&self.channels, pattern)
+    }
+
+    /// Active sharded channels, optionally only those matching a pattern.
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        Self::matching(&self.shard_channels, pattern)
+    }
+
+    fn matching(subscriptions: &Subscriptions, pattern: Option<&str>) -> Vec<String> {
+        subscriptions
+            .names()
+            .filter(|channel| pattern.is_none_or(|p| glob_match(p, channel)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.count(channel)
+    }
+
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        self.shard_channels.count(channel)
+    }
+
+    /// Number of distinct patterns subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+}
@@ -1,95 +1,629 @@
-use crate::resp::{RespIn, RespOut};
-use crate::{data::SharedData, info::SharedInfo};
+use crate::client::Client;
+use crate::info::DisklessLoad;
+use crate::net::{self, Reader, Writer};
+use crate::resp::{RespIn, RespOut, RespReader};
+use crate::state::State;
+use crate::{
+    data::SharedData, functions::SharedFunctions, info::SharedInfo, module::SharedModules,
+};
 use anyhow::{bail, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_rustls::rustls::pki_types::ServerName;
 
-pub async fn replication_task(data: SharedData, info: SharedInfo) {
-    match handshake(data, info).await {
-        Ok(_) => {
-            println!("(INFO) Handshake completed");
+/// Circular buffer holding the most recent part of the replication stream,
+/// so replicas that reconnect can continue where they left off.
+pub struct Backlog {
+    buf: Vec<u8>,
+    size: usize,
+    /// Next write position in `buf`.
+    idx: usize,
+    /// Number of valid bytes in `buf`.
+    histlen: usize,
+}
+
+impl Backlog {
+    pub fn new(size: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            size,
+            idx: 0,
+            histlen: 0,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.histlen
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.idx = 0;
+        self.histlen = 0;
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        if self.size == 0 {
+            return;
+        }
+        // only allocate once there is something to keep
+        if self.buf.is_empty() {
+            self.buf = vec![0; self.size];
+        }
+
+        if bytes.len() >= self.size {
+            self.buf.copy_from_slice(&bytes[bytes.len() - self.size..]);
+            self.idx = 0;
+            self.histlen = self.size;
+            return;
+        }
+
+        let first = bytes.len().min(self.size - self.idx);
+        self.buf[self.idx..self.idx + first].copy_from_slice(&bytes[..first]);
+        self.buf[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.idx = (self.idx + bytes.len()) % self.size;
+        self.histlen = (self.histlen + bytes.len()).min(self.size);
+    }
+
+    /// The last `n` bytes written, if they are still in the buffer.
+    pub fn tail(&self, n: usize) -> Option<Vec<u8>> {
+        if n > self.histlen {
+            return None;
+        }
+        if n == 0 {
+            return Some(Vec::new());
+        }
+        let start = (self.idx + self.size - n) % self.size;
+        if start + n <= self.size {
+            Some(self.buf[start..start + n].to_vec())
+        } else {
+            Some([&self.buf[start..], &self.buf[..n - (self.size - start)]].concat())
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum ReplicaState {
+    /// Waiting for the snapshot of a full resync.
+    WaitBgsave,
+    /// Its snapshot has been taken and is being sent, later writes are buffered.
+    WaitBgsaveEnd,
+    /// Receiving the replication stream.
+    Online,
+}
+
+impl fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplicaState::WaitBgsave | ReplicaState::WaitBgsaveEnd => write!(f, "wait_bgsave"),
+            ReplicaState::Online => write!(f, "online"),
+        }
+    }
+}
+
+/// The master's end of a connected replica.
+pub struct ReplicaHandle {
+    pub id: u64,
+    pub ip: String,
+    pub port: u16,
+    pub state: ReplicaState,
+    /// The replica accepts snapshots of unknown length, see REPLCONF capa eof.
+    pub capa_eof: bool,
+    /// Offset the replica last acknowledged with REPLCONF ACK.
+    pub ack_offset: u64,
+    /// When it last acknowledged, `None` until the first REPLCONF ACK.
+    pub last_ack: Option<Instant>,
+    /// Writes made after its snapshot was taken, sent right after it.
+    pub buffer: Vec<u8>,
+    tx: UnboundedSender<Vec<u8>>,
+}
+
+impl ReplicaHandle {
+    /// Create a handle and the receiving end of its replication stream.
+    pub fn new(id: u64, ip: String, port: u16) -> (Self, UnboundedReceiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let replica = Self {
+            id,
+            ip,
+            port,
+            state: ReplicaState::Online,
+            capa_eof: false,
+            ack_offset: 0,
+            last_ack: None,
+            buffer: Vec::new(),
+            tx,
+        };
+        (replica, rx)
+    }
+
+    /// Queue bytes for the replica. Returns false if its connection is gone.
+    pub fn send(&self, bytes: &[u8]) -> bool {
+        self.tx.send(bytes.to_vec()).is_ok()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.tx.is_closed()
+    }
+}
+
+/// Frame a snapshot for sending to a replica.
+/// Without a known length, the payload is followed by a random marker announced up front.
+pub fn rdb_payload(rdb: &[u8], eof_mark: Option<&str>) -> Vec<u8> {
+    match eof_mark {
+        Some(mark) => [
+            format!("$EOF:{}\r\n", mark).as_bytes(),
+            rdb,
+            mark.as_bytes(),
+        ]
+        .concat(),
+        None => [format!("${}\r\n", rdb.len()).as_bytes(), rdb].concat(),
+    }
+}
+
+/// Send a snapshot to the replicas waiting for a full resync.
+/// A diskless transfer waits a bit first, so replicas connecting at about the same time share it.
+pub async fn full_sync(data: SharedData, functions: SharedFunctions, info: SharedInfo) {
+    let (diskless, delay) = {
+        let info = info.read().await;
+        let config = &info.replication.config;
+        (config.diskless_sync, config.diskless_sync_delay)
+    };
+
+    if diskless {
+        time::sleep(Duration::from_secs(delay)).await;
+    }
+
+    loop {
+        // writes after the snapshot are buffered for the replicas that get it
+        let (rdb, path, replid, offset) = {
+            let data = data.read().await;
+            let functions = functions.read().await;
+            let mut info = info.write().await;
+            let (replid, offset) = info.replication.start_full_sync();
+            let rdb = crate::file::write_rdb(&*data, &functions, &replid, offset);
+            (rdb, info.server.rdb_path().clone(), replid, offset)
+        };
+
+        let rdb = if diskless {
+            println!("(INFO) Starting diskless full resync");
+            rdb
+        } else {
+            println!("(INFO) Starting full resync from {}", path.display());
+            let res = match tokio::fs::write(&path, &rdb).await {
+                Ok(_) => tokio::fs::read(&path).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(rdb) => rdb,
+                Err(e) => {
+                    eprintln!("(ERROR) Failed to save RDB file: {}", e);
+                    // they will reconnect and try again
+                    info.write().await.replication.drop_waiting_replicas();
+                    return;
+                }
+            }
+        };
+
+        // replicas that came while saving need a snapshot of their own
+        if !info
+            .write()
+            .await
+            .replication
+            .finish_full_sync(&rdb, diskless, &replid, offset)
+        {
+            return;
         }
-        Err(e) => {
-            eprintln!("(ERROR) Handshake failed: {}", e);
-            std::process::exit(1);
+    }
+}
+
+/// The running replication task of a replica.
+pub struct MasterLink {
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl MasterLink {
+    pub fn start(state: State) -> Self {
+        let (shutdown, rx) = watch::channel(false);
+        let task = tokio::spawn(replication_task(state, rx));
+        Self { shutdown, task }
+    }
+
+    /// Stop the task once the command it is applying, if any, is done.
+    pub async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.task.await;
+    }
+}
+
+/// Resolves once the link is asked to stop.
+async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    // an error means the sender is gone, which is a stop as well
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Keep a link to the master, reconnecting with exponential backoff when it fails.
+async fn replication_task(state: State, mut shutdown: watch::Receiver<bool>) {
+    let State {
+        data,
+        functions,
+        info,
+        modules,
+        ..
+    } = &state;
+    let mut delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        info.write().await.replication.set_sync_in_progress(true);
+        let res = tokio::select! {
+            res = handshake(data, functions, modules, info) => res,
+            _ = stopped(&mut shutdown) => {
+                info.write().await.replication.set_sync_in_progress(false);
+                return;
+            }
+        };
+        info.write().await.replication.set_sync_in_progress(false);
+
+        let (mut reader, mut writer) = match res {
+            Ok(link) => {
+                println!("(INFO) Handshake completed");
+                link
+            }
+            Err(e) => {
+                eprintln!("(ERROR) Handshake failed: {}, retrying in {:?}", e, delay);
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = stopped(&mut shutdown) => return,
+                }
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+        delay = MIN_RECONNECT_DELAY;
+
+        info.write().await.replication.set_link_up(true);
+
+        let res = stream(&mut reader, &mut writer, &state, &mut shutdown).await;
+
+        info.write().await.replication.set_link_up(false);
+
+        match res {
+            Ok(true) => return,
+            Ok(false) => println!("(INFO) Master closed the connection"),
+            Err(e) => eprintln!("(ERROR) Lost connection to master: {}", e),
         }
     }
 }
 
-pub async fn handshake(data: SharedData, info: SharedInfo) -> Result<()> {
-    let mut stream = TcpStream::connect(info.replication.master_addr()).await?;
+pub async fn handshake(
+    data: &SharedData,
+    functions: &SharedFunctions,
+    modules: &SharedModules,
+    info: &SharedInfo,
+) -> Result<(RespReader<Reader>, Writer)> {
+    let (host, addr, port, replid, offset, auth, tls, timeout) = {
+        let info = info.read().await;
+        // ask to continue right after the last byte we processed
+        let (replid, offset) = match info.replication.psync_state() {
+            Some((replid, offset)) => (replid.clone(), (offset + 1).to_string()),
+            None => ("?".to_string(), "-1".to_string()),
+        };
+        let config = &info.replication.config;
+        // AUTH [masteruser] masterauth
+        let auth = config.masterauth.as_ref().map(|masterauth| {
+            let mut auth = vec!["AUTH".to_string()];
+            auth.extend(config.masteruser.clone());
+            auth.push(masterauth.clone());
+            auth
+        });
+        (
+            info.replication.master_host().cloned().unwrap_or_default(),
+            info.replication.master_addr(),
+            info.server.port(),
+            replid,
+            offset,
+            auth,
+            config.tls.clone(),
+            config.timeout,
+        )
+    };
+
+    let stream = time::timeout(timeout, TcpStream::connect(addr)).await??;
+    let (reader, mut writer) = match tls {
+        Some(tls) => {
+            let connect = tls.connect(ServerName::try_from(host)?, stream);
+            net::split(time::timeout(timeout, connect).await??)
+        }
+        None => net::split(stream),
+    };
+    let mut reader = RespReader::new(reader);
+    // the snapshot may take long to transfer, but data must keep coming
+    reader.set_timeout(Some(timeout));
+
+    if let Some(auth) = auth {
+        writer.write_all(&RespIn::Array(auth).serialize()).await?;
+        expect_simple(&mut reader, "OK").await?;
+    }
 
     let ping = RespIn::Array(vec!["PING".to_string()]);
-    stream.write_all(&ping.serialize()).await?;
-    expect_simple(&mut stream, "PONG").await?;
+    writer.write_all(&ping.serialize()).await?;
+    expect_simple(&mut reader, "PONG").await?;
 
     let replconf = RespIn::Array(vec![
         "REPLCONF".to_string(),
         "listening-port".to_string(),
-        info.server.port().to_string(),
+        port.to_string(),
     ]);
-    stream.write_all(&replconf.serialize()).await?;
-    expect_simple(&mut stream, "OK").await?;
+    writer.write_all(&replconf.serialize()).await?;
+    expect_simple(&mut reader, "OK").await?;
 
     let replconf = RespIn::Array(vec![
         "REPLCONF".to_string(),
         "capa".to_string(),
+        "eof".to_string(),
+        "capa".to_string(),
         "psync2".to_string(),
     ]);
-    stream.write_all(&replconf.serialize()).await?;
-    expect_simple(&mut stream, "OK").await?;
+    writer.write_all(&replconf.serialize()).await?;
+    expect_simple(&mut reader, "OK").await?;
 
-    let psync = RespIn::Array(vec!["PSYNC".to_string(), "?".to_string(), "-1".to_string()]);
-    stream.write_all(&psync.serialize()).await?;
-    expect_full_resync(&mut stream, &data).await?;
+    let psync = RespIn::Array(vec!["PSYNC".to_string(), replid, offset]);
+    writer.write_all(&psync.serialize()).await?;
+    expect_resync(&mut reader, data, functions, modules, info).await?;
 
-    Ok(())
+    // `apply_stream` keeps its own deadline, as its reads are restarted every second
+    reader.set_timeout(None);
+    Ok((reader, writer))
+}
+
+/// Apply the commands the master sends until the connection is closed,
+/// passing them on verbatim to our own replicas and
+/// acknowledging the processed offset every second.
+/// Returns true if the link was shut down.
+async fn stream(
+    reader: &mut RespReader<Reader>,
+    writer: &mut Writer,
+    state: &State,
+    shutdown: &mut watch::Receiver<bool>,
+) -> Result<bool> {
+    let mut client = Client::new();
+    client.is_master = true;
+    client.authenticated = true;
+    // listed by CLIENT LIST like other connections
+    state.clients.write().await.update(&client);
+
+    let res = apply_stream(reader, writer, state, shutdown, &mut client).await;
+
+    state.clients.write().await.remove(client.id);
+    res
+}
+
+async fn apply_stream(
+    reader: &mut RespReader<Reader>,
+    writer: &mut Writer,
+    state: &State,
+    shutdown: &mut watch::Receiver<bool>,
+    client: &mut Client,
+) -> Result<bool> {
+    let info = &state.info;
+    let kill = Arc::clone(&client.kill);
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut last_io = time::Instant::now();
+
+    loop {
+        let timeout = info.read().await.replication.config.timeout;
+        tokio::select! {
+            req = reader.read_request() => {
+                let Some((req, raw)) = req? else {
+                    return Ok(false);
+                };
+                last_io = time::Instant::now();
+                let res = crate::command::handle(req, state, client).await;
+                state.clients.write().await.update(client);
+                // replies are not sent back to the master unless it asked for them
+                if client.force_reply {
+                    client.force_reply = false;
+                    for res in res {
+                        writer.write_all(&res.serialize()).await?;
+                    }
+                }
+                let mut info = info.write().await;
+                info.replication.touch_master_io();
+                info.replication.feed(&raw);
+            }
+            _ = interval.tick() => {
+                let offset = *info.read().await.replication.master_repl_offset();
+                writer.write_all(&ack(offset).serialize()).await?;
+            }
+            // the master PINGs every repl-ping-replica-period, so it is gone
+            _ = time::sleep_until(last_io + timeout) => {
+                bail!("no data from master for {:?}, see repl-timeout", timeout)
+            }
+            _ = stopped(shutdown) => return Ok(true),
+            // CLIENT KILL TYPE master, the link is made again
+            _ = kill.notified() => return Ok(false),
+        }
+    }
+}
+
+/// REPLCONF ACK reporting the processed offset to the master.
+pub fn ack(offset: u64) -> RespIn {
+    RespIn::Array(vec![
+        "REPLCONF".to_string(),
+        "ACK".to_string(),
+        offset.to_string(),
+    ])
+}
+
+/// DEL sent to replicas when a key expires on the master.
+pub fn del(key: String) -> RespIn {
+    RespIn::Array(vec!["DEL".to_string(), key])
 }
 
-async fn next_response(stream: &mut TcpStream) -> Result<RespOut> {
-    let mut buf = [0; 512];
-    let n = stream.read(&mut buf).await?;
-    Ok(crate::resp::parse_output(&buf[..n])?)
+/// REPLCONF GETACK asking replicas to report their offset.
+pub fn getack() -> RespIn {
+    RespIn::Array(vec![
+        "REPLCONF".to_string(),
+        "GETACK".to_string(),
+        "*".to_string(),
+    ])
 }
 
-async fn expect_simple(stream: &mut TcpStream, expected: &str) -> Result<()> {
-    let res = next_response(stream).await?;
+async fn expect_simple(reader: &mut RespReader<Reader>, expected: &str) -> Result<()> {
+    let res = reader.read_response().await?;
     match res {
         RespOut::SimpleString(s) if s.to_lowercase() == expected.to_lowercase() => Ok(()),
         _ => bail!("expected '{}'", expected),
     }
 }
 
-async fn expect_full_resync(stream: &mut TcpStream, _data: &SharedData) -> Result<()> {
-    let res = next_response(stream).await?;
-    let (id, offset) = match res {
-        RespOut::SimpleString(s) => {
-            let mut iter = s.split_whitespace();
-            match iter.next() {
-                Some(s) if s.to_uppercase() == "FULLRESYNC" => {}
-                _ => bail!("expected FULLRESYNC"),
+async fn expect_resync(
+    reader: &mut RespReader<Reader>,
+    data: &SharedData,
+    functions: &SharedFunctions,
+    modules: &SharedModules,
+    info: &SharedInfo,
+) -> Result<()> {
+    let res = reader.read_response().await?;
+    let line = match res {
+        RespOut::SimpleString(s) => s,
+        _ => bail!("expected Simple String"),
+    };
+    let mut iter = line.split_whitespace();
+
+    match iter.next() {
+        Some(s) if s.to_uppercase() == "FULLRESYNC" => {}
+        Some(s) if s.to_uppercase() == "CONTINUE" => {
+            // the master may have changed replication id, e.g. after a failover
+            if let Some(id) = iter.next() {
+                info.write().await.replication.set_replid(id.to_string());
             }
-            let id = match iter.next() {
-                Some(id) => id.to_string(),
-                None => bail!("expected id"),
-            };
-            let offset = match iter.next() {
-                Some(offset) => offset.to_string(),
-                None => bail!("expected offset"),
-            };
-            (id, offset)
+            println!("(INFO) CONTINUE partial resync accepted");
+            return Ok(());
         }
-        _ => bail!("expected Simple String"),
+        _ => bail!("expected FULLRESYNC or CONTINUE"),
+    }
+
+    let id = match iter.next() {
+        Some(id) => id.to_string(),
+        None => bail!("expected id"),
+    };
+    let offset = match iter.next() {
+        Some(offset) => offset.parse::<u64>()?,
+        None => bail!("expected offset"),
     };
 
     println!("(INFO) FULLRESYNC id={} offset={}", id, offset);
 
-    match next_response(stream).await? {
-        RespOut::BulkString(_) => {
-            // TODO: parse RDB file and load it into the data store
-        }
-        _ => bail!("expected Bulk String"),
-    }
+    let rdb = read_rdb_payload(reader).await?;
+
+    let (load, path) = {
+        let info = info.read().await;
+        (
+            info.replication.config.diskless_load,
+            info.server.rdb_path().clone(),
+        )
+    };
+    let diskless = match load {
+        DisklessLoad::Disabled => false,
+        DisklessLoad::OnEmptyDb => data.read().await.is_empty(),
+        DisklessLoad::Swapdb => true,
+    };
+
+    // the old data keeps being served until the new one is fully loaded
+    let new_rdb = if diskless {
+        crate::file::read_rdb(&rdb, modules)?
+    } else {
+        tokio::fs::write(&path, &rdb).await?;
+        crate::file::read_rdb(&tokio::fs::read(&path).await?, modules)?
+    };
+    let libraries = new_rdb
+        .libraries
+        .iter()
+        .map(|code| crate::scripting::load_library(code))
+        .collect::<Result<Vec<_>>>()?;
+
+    // take every lock first, so being cancelled can not leave the data and functions out of step
+    let mut data = data.write().await;
+    let mut functions = functions.write().await;
+    let mut info = info.write().await;
+    data.replace(new_rdb.data);
+    functions.replace(libraries);
+    info.replication.reset(id, offset);
+
+    println!("(INFO) Loaded {} bytes RDB", rdb.len());
 
     Ok(())
 }
+
+async fn read_rdb_payload(reader: &mut RespReader<Reader>) -> Result<Vec<u8>> {
+    let line = reader.read_line().await?;
+    let len = match line.strip_prefix('$') {
+        Some(len) => len,
+        None => bail!("expected RDB payload"),
+    };
+    match len.strip_prefix("EOF:") {
+        Some(mark) => reader.read_until(mark.as_bytes()).await,
+        None => reader.read_bytes(len.parse()?).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_wraps_around() {
+        let mut backlog = Backlog::new(8);
+        assert!(!backlog.is_active());
+        backlog.push(b"abcde");
+        assert_eq!(backlog.tail(5), Some(b"abcde".to_vec()));
+        assert_eq!(backlog.tail(6), None);
+
+        backlog.push(b"fghij");
+        assert_eq!(backlog.histlen(), 8);
+        assert_eq!(backlog.tail(8), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.tail(3), Some(b"hij".to_vec()));
+        assert_eq!(backlog.tail(0), Some(Vec::new()));
+        assert_eq!(backlog.tail(9), None);
+    }
+
+    #[test]
+    fn backlog_keeps_the_end_of_large_writes() {
+        let mut backlog = Backlog::new(4);
+        backlog.push(b"ab");
+        backlog.push(b"0123456789");
+        assert_eq!(backlog.tail(4), Some(b"6789".to_vec()));
+        backlog.push(b"x");
+        assert_eq!(backlog.tail(4), Some(b"789x".to_vec()));
+
+        backlog.clear();
+        assert_eq!(backlog.histlen(), 0);
+        assert_eq!(backlog.tail(1), None);
+    }
+
+    #[test]
+    fn backlog_of_size_zero_keeps_nothing() {
+        let mut backlog = Backlog::new(0);
+        backlog.push(b"abc");
+        assert!(!backlog.is_active());
+        assert_eq!(backlog.tail(1), None);
+    }
+}
@@ -1,9 +1,16 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use std::cell::Cell;
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time;
 
 /// Input is always a list of BulkStrings
 pub enum RespIn {
     Array(Vec<String>),
+    /// Some of the strings are not valid UTF-8, like FUNCTION RESTORE payloads.
+    Binary(Vec<Vec<u8>>),
 }
 
 pub enum RespOut {
@@ -11,6 +18,8 @@ pub enum RespOut {
     Error(String),
     Integer(i64),
     BulkString(String),
+    /// A BulkString that is not text, like FUNCTION DUMP payloads.
+    BulkBytes(Vec<u8>),
     Array(Vec<RespOut>),
     Null,
 }
@@ -23,43 +32,263 @@ const ARRAY_BYTE_CODE: u8 = b'*';
 const NULL_BYTE_CODE: u8 = b'_';
 
 pub fn parse_input(buf: &[u8]) -> Result<RespIn> {
-    crate::utils::print_buf(buf, " in req");
     let parser = RespParser::new(buf);
     parser.parse_request()
 }
 
 pub fn parse_output(buf: &[u8]) -> Result<RespOut> {
-    crate::utils::print_buf(buf, "out req");
     let parser = RespParser::new(buf);
     parser.parse_response()
 }
 
+/// Raised by the parser when the buffer ends in the middle of a value.
+#[derive(Debug)]
+struct Incomplete;
+
+impl fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected EOF")
+    }
+}
+
+impl std::error::Error for Incomplete {}
+
+/// Raised when a client sends more than its `Limits` allow. The connection is closed then,
+/// as what follows cannot be parsed anyway.
+#[derive(Debug)]
+pub struct LimitExceeded(&'static str);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// How much a client may send, so that it cannot make the server buffer without bound.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Longest BulkString in a request, like proto-max-bulk-len.
+    pub max_bulk_len: usize,
+    /// Most arguments in a request.
+    pub max_multibulk_len: usize,
+    /// Most bytes buffered for a request not complete yet, like client-query-buffer-limit.
+    pub max_query_buffer: usize,
+}
+
+impl Limits {
+    pub const DEFAULT: Limits = Limits {
+        max_bulk_len: 512 * 1024 * 1024,
+        max_multibulk_len: 1024 * 1024 * 1024,
+        max_query_buffer: 1024 * 1024 * 1024,
+    };
+
+    /// Before AUTH only small requests are needed, the same bounds Redis uses.
+    pub const UNAUTHENTICATED: Limits = Limits {
+        max_bulk_len: 16 * 1024,
+        max_multibulk_len: 10,
+        max_query_buffer: 256 * 1024,
+    };
+}
+
+/// Parse a request from the start of `buf`.
+/// Returns the request and the number of bytes it occupied, or `None` if more data is needed.
+pub fn parse_input_prefix(buf: &[u8], limits: Limits) -> Result<Option<(RespIn, usize)>> {
+    let parser = RespParser::with_limits(buf, limits);
+    match parser.parse_request() {
+        Ok(req) => Ok(Some((req, parser.pos.get()))),
+        Err(e) if e.is::<Incomplete>() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Same as `parse_input_prefix` but for responses.
+pub fn parse_output_prefix(buf: &[u8]) -> Result<Option<(RespOut, usize)>> {
+    let parser = RespParser::new(buf);
+    match parser.parse_response() {
+        Ok(res) => Ok(Some((res, parser.pos.get()))),
+        Err(e) if e.is::<Incomplete>() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Buffers a byte stream and splits it into RESP values.
+/// Reading is cancel safe, so it can be used in `tokio::select!`.
+pub struct RespReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    limits: Limits,
+    /// How long to wait for more data before failing, forever if not set.
+    timeout: Option<Duration>,
+}
+
+impl<R: AsyncRead + Unpin> RespReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            limits: Limits::DEFAULT,
+            timeout: None,
+        }
+    }
+
+    /// Fail reads that get no data for `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Bound the requests read from now on.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Next request and the raw bytes it was parsed from, or `None` when the stream is closed.
+    /// Fails with `LimitExceeded` when the request is bigger than the limits allow.
+    pub async fn read_request(&mut self) -> Result<Option<(RespIn, Vec<u8>)>> {
+        loop {
+            if let Some((req, n)) =
+                parse_input_prefix(&self.buf, self.limits).inspect_err(|_| self.buf.clear())?
+            {
+                let raw = self.buf.drain(..n).collect::<Vec<u8>>();
+                return Ok(Some((req, raw)));
+            }
+            if self.buf.len() > self.limits.max_query_buffer {
+                self.buf.clear();
+                return Err(LimitExceeded("query buffer limit exceeded").into());
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Bytes received but not parsed yet.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Next response, failing if the stream is closed.
+    pub async fn read_response(&mut self) -> Result<RespOut> {
+        loop {
+            if let Some((res, n)) =
+                parse_output_prefix(&self.buf).inspect_err(|_| self.buf.clear())?
+            {
+                self.buf.drain(..n);
+                return Ok(res);
+            }
+            if !self.fill().await? {
+                bail!("connection closed");
+            }
+        }
+    }
+
+    /// Next CRLF terminated line.
+    /// Bare newlines before it are skipped, masters send them as keepalives.
+    pub async fn read_line(&mut self) -> Result<String> {
+        loop {
+            let skip = self.buf.iter().take_while(|b| **b == b'\n').count();
+            self.buf.drain(..skip);
+            if let Some(i) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8(self.buf[..i].to_vec())?;
+                self.buf.drain(..i + 2);
+                return Ok(line);
+            }
+            if !self.fill().await? {
+                bail!("connection closed");
+            }
+        }
+    }
+
+    /// Exactly `n` bytes.
+    pub async fn read_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        while self.buf.len() < n {
+            if !self.fill().await? {
+                bail!("connection closed");
+            }
+        }
+        Ok(self.buf.drain(..n).collect())
+    }
+
+    /// Everything up to `mark`, consuming the mark as well.
+    pub async fn read_until(&mut self, mark: &[u8]) -> Result<Vec<u8>> {
+        let mut searched = 0;
+        loop {
+            if let Some(i) = self.buf[searched..]
+                .windows(mark.len())
+                .position(|w| w == mark)
+            {
+                let res = self.buf.drain(..searched + i).collect();
+                self.buf.drain(..mark.len());
+                return Ok(res);
+            }
+            searched = self.buf.len().saturating_sub(mark.len() - 1);
+            if !self.fill().await? {
+                bail!("connection closed");
+            }
+        }
+    }
+
+    /// Read more data into the buffer. Returns false on EOF.
+    async fn fill(&mut self) -> Result<bool> {
+        let mut buf = [0; 4096];
+        let read = self.inner.read(&mut buf);
+        let res = match self.timeout {
+            Some(timeout) => time::timeout(timeout, read)
+                .await
+                .map_err(|_| anyhow!("no data received for {:?}", timeout))?,
+            None => read.await,
+        };
+        let n = match res {
+            Ok(n) => n,
+            // TLS peers often close without saying goodbye first
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e.into()),
+        };
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n > 0)
+    }
+}
+
 pub struct RespParser<'a> {
     buf: &'a [u8],
     pos: Cell<usize>,
+    limits: Limits,
 }
 
 impl RespParser<'_> {
-    fn new(buf: &[u8]) -> RespParser {
+    fn new(buf: &[u8]) -> RespParser<'_> {
+        Self::with_limits(buf, Limits::DEFAULT)
+    }
+
+    fn with_limits(buf: &[u8], limits: Limits) -> RespParser<'_> {
         RespParser {
             buf,
             pos: Cell::new(0),
+            limits,
         }
     }
 
     fn parse_request(&self) -> Result<RespIn> {
-        let values = self.next_array_of_strings()?;
-        Ok(RespIn::Array(values))
+        let values = self.next_array_of_bulks()?;
+        match values
+            .iter()
+            .map(|value| String::from_utf8(value.clone()))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(values) => Ok(RespIn::Array(values)),
+            Err(_) => Ok(RespIn::Binary(values)),
+        }
     }
 
     fn parse_response(&self) -> Result<RespOut> {
-        Ok(self.next_item()?)
+        self.next_item()
     }
 
     fn next(&self) -> Result<u8> {
         let pos = self.pos.get();
         if self.buf.len() <= pos {
-            bail!("unexpected EOF");
+            return Err(Incomplete.into());
         }
         let res = self.buf[pos];
         self.pos.set(pos + 1);
@@ -100,11 +329,28 @@ impl RespParser<'_> {
     }
 
     fn next_string(&self) -> Result<String> {
+        Ok(String::from_utf8(self.next_bulk()?)?)
+    }
+
+    /// The bytes of a BulkString, which may contain line breaks.
+    fn next_bulk(&self) -> Result<Vec<u8>> {
         let n = self.next_int()?;
         if n < 0 {
             bail!("fuck null strings")
         }
-        self.next_line()
+        if n as usize > self.limits.max_bulk_len {
+            return Err(LimitExceeded("invalid bulk length").into());
+        }
+        let start = self.pos.get();
+        let end = start + n as usize;
+        if self.buf.len() < end + 2 {
+            return Err(Incomplete.into());
+        }
+        if &self.buf[end..end + 2] != b"\r\n" {
+            bail!("expected CRLF");
+        }
+        self.pos.set(end + 2);
+        Ok(self.buf[start..end].to_vec())
     }
 
     fn next_array(&self) -> Result<Vec<RespOut>> {
@@ -116,20 +362,23 @@ impl RespParser<'_> {
         Ok(res)
     }
 
-    fn next_array_of_strings(&self) -> Result<Vec<String>> {
+    fn next_array_of_bulks(&self) -> Result<Vec<Vec<u8>>> {
         self.consume_type(ARRAY_BYTE_CODE)?;
         let n = self.next_int()?;
+        if n > 0 && n as usize > self.limits.max_multibulk_len {
+            return Err(LimitExceeded("invalid multibulk length").into());
+        }
         let mut res = Vec::new();
         for _ in 0..n {
             self.consume_type(BULK_STRING_BYTE_CODE)?;
-            res.push(self.next_string()?);
+            res.push(self.next_bulk()?);
         }
         Ok(res)
     }
 
     fn consume_type(&self, expected: u8) -> Result<()> {
         match self.next()? {
-            s if s == expected => return Ok(()),
+            s if s == expected => Ok(()),
             s => bail!("unexpected data type {:?}", s),
         }
     }
@@ -138,32 +387,50 @@ impl RespParser<'_> {
 impl RespOut {
     pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        serialize(&mut buf, &self);
-
-        crate::utils::print_buf(&buf, "out res");
+        serialize(&mut buf, self);
         buf
     }
 }
 
 impl RespIn {
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+    pub fn into_vec(self) -> Vec<String> {
         match self {
-            RespIn::Array(values) => {
-                buf.push(ARRAY_BYTE_CODE);
-                buf.extend(values.len().to_string().as_bytes());
-                push_crlf(&mut buf);
-                for value in values {
-                    buf.push(BULK_STRING_BYTE_CODE);
-                    buf.extend(value.len().to_string().as_bytes());
-                    push_crlf(&mut buf);
-                    buf.extend(value.as_bytes());
-                    push_crlf(&mut buf);
-                }
-            }
+            RespIn::Array(values) => values,
+            RespIn::Binary(_) => self.to_strings(),
+        }
+    }
+
+    /// The arguments as text, binary ones with their invalid UTF-8 replaced.
+    pub fn to_strings(&self) -> Vec<String> {
+        match self {
+            RespIn::Array(values) => values.clone(),
+            RespIn::Binary(values) => values
+                .iter()
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+                .collect(),
+        }
+    }
+
+    /// The arguments as received, if some are binary.
+    pub fn binary(&self) -> Option<&Vec<Vec<u8>>> {
+        match self {
+            RespIn::Array(_) => None,
+            RespIn::Binary(values) => Some(values),
         }
+    }
 
-        crate::utils::print_buf(&buf, " in res");
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let values: Vec<&[u8]> = match self {
+            RespIn::Array(values) => values.iter().map(|value| value.as_bytes()).collect(),
+            RespIn::Binary(values) => values.iter().map(|value| &value[..]).collect(),
+        };
+        buf.push(ARRAY_BYTE_CODE);
+        buf.extend(values.len().to_string().as_bytes());
+        push_crlf(&mut buf);
+        for value in values {
+            push_bulk(&mut buf, value);
+        }
         buf
     }
 }
@@ -177,8 +444,8 @@ fn serialize(buf: &mut Vec<u8>, value: &RespOut) {
         }
         RespOut::Error(e) => {
             buf.push(ERROR_BYTE_CODE);
-            buf.extend(b"ERR ");
-            buf.extend(e.as_bytes());
+            // a line break would end the error early, Redis replaces them too
+            buf.extend(e.replace(['\r', '\n'], " ").as_bytes());
             push_crlf(buf);
         }
         RespOut::Integer(i) => {
@@ -186,13 +453,8 @@ fn serialize(buf: &mut Vec<u8>, value: &RespOut) {
             buf.extend(i.to_string().as_bytes());
             push_crlf(buf);
         }
-        RespOut::BulkString(s) => {
-            buf.push(BULK_STRING_BYTE_CODE);
-            buf.extend(s.len().to_string().as_bytes());
-            push_crlf(buf);
-            buf.extend(s.as_bytes());
-            push_crlf(buf);
-        }
+        RespOut::BulkString(s) => push_bulk(buf, s.as_bytes()),
+        RespOut::BulkBytes(bytes) => push_bulk(buf, bytes),
         RespOut::Null => {
             buf.push(NULL_BYTE_CODE);
             push_crlf(buf);
@@ -208,6 +470,48 @@ fn serialize(buf: &mut Vec<u8>, value: &RespOut) {
     }
 }
 
+fn push_bulk(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.push(BULK_STRING_BYTE_CODE);
+    buf.extend(bytes.len().to_string().as_bytes());
+    push_crlf(buf);
+    buf.extend(bytes);
+    push_crlf(buf);
+}
+
 fn push_crlf(buf: &mut Vec<u8>) {
     buf.extend(b"\r\n");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buf: &[u8], limits: Limits) -> Result<Option<(Vec<String>, usize)>> {
+        Ok(parse_input_prefix(buf, limits)?.map(|(req, n)| (req.into_vec(), n)))
+    }
+
+    #[test]
+    fn requests_are_parsed_once_complete() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n*1\r\n$4\r\nPING\r\n";
+        assert_eq!(
+            parse(buf, Limits::DEFAULT).unwrap(),
+            Some((vec!["GET".to_string(), "key".to_string()], 22))
+        );
+        for end in 0..22 {
+            assert_eq!(parse(&buf[..end], Limits::DEFAULT).unwrap(), None);
+        }
+        assert!(parse(b"+OK\r\n", Limits::DEFAULT).is_err());
+    }
+
+    #[test]
+    fn requests_over_the_limits_are_refused_early() {
+        let limits = Limits::UNAUTHENTICATED;
+        // the header is enough to tell, without waiting for the data
+        let err = parse(b"*1\r\n$16385\r\n", limits).unwrap_err();
+        assert!(err.is::<LimitExceeded>());
+        let err = parse(b"*11\r\n", limits).unwrap_err();
+        assert!(err.is::<LimitExceeded>());
+        assert_eq!(parse(b"*1\r\n$16384\r\n", limits).unwrap(), None);
+        assert_eq!(parse(b"*1\r\n$16385\r\n", Limits::DEFAULT).unwrap(), None);
+    }
+}
@@ -0,0 +1,512 @@
+use crate::error::{self, Error};
+use crate::functions::{Function, Library};
+use crate::resp::RespOut;
+use anyhow::{bail, Result};
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The script cache and the script that is running, if any.
+/// Uses plain mutexes, as SCRIPT KILL must get through while a script blocks its thread.
+pub type SharedScripts = Arc<Scripts>;
+
+/// Number of Lua instructions between checks whether the script was killed.
+const KILL_CHECK_INTERVAL: u32 = 10_000;
+
+struct RunningScript {
+    client_id: u64,
+    started: Instant,
+    killed: Arc<AtomicBool>,
+    /// Scripts that wrote to the data set can not be killed, as that would leave it half updated.
+    wrote: bool,
+}
+
+pub struct Scripts {
+    /// Script bodies by their SHA1.
+    cache: Mutex<HashMap<String, String>>,
+    running: Mutex<Option<RunningScript>>,
+    /// Milliseconds a script may run before other clients get BUSY errors.
+    busy_reply_threshold: AtomicU64,
+}
+
+impl Scripts {
+    pub fn new(busy_reply_threshold: Duration) -> Self {
+        Self {
+            cache: Mutex::new(HashMap::new()),
+            running: Mutex::new(None),
+            busy_reply_threshold: AtomicU64::new(busy_reply_threshold.as_millis() as u64),
+        }
+    }
+
+    pub fn set_busy_reply_threshold(&self, threshold: Duration) {
+        self.busy_reply_threshold
+            .store(threshold.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Add a script to the cache, returning its SHA1.
+    pub fn load(&self, body: &str) -> String {
+        let sha = sha1_hex(body.as_bytes());
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(sha.clone(), body.to_string());
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Option<String> {
+        self.cache.lock().unwrap().get(&sha.to_lowercase()).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.cache.lock().unwrap().contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Mark a script of `client_id` as running, returning the flag that kills it.
+    pub fn start(&self, client_id: u64) -> Arc<AtomicBool> {
+        let killed = Arc::new(AtomicBool::new(false));
+        *self.running.lock().unwrap() = Some(RunningScript {
+            client_id,
+            started: Instant::now(),
+            killed: Arc::clone(&killed),
+            wrote: false,
+        });
+        killed
+    }
+
+    pub fn finish(&self) {
+        *self.running.lock().unwrap() = None;
+    }
+
+    /// The running script is about to write.
+    pub fn mark_write(&self) {
+        if let Some(running) = self.running.lock().unwrap().as_mut() {
+            running.wrote = true;
+        }
+    }
+
+    /// Whether another client's script has been running for too long to make `client_id` wait.
+    pub fn is_busy(&self, client_id: u64) -> bool {
+        let threshold = Duration::from_millis(self.busy_reply_threshold.load(Ordering::Relaxed));
+        match self.running.lock().unwrap().as_ref() {
+            Some(running) => {
+                running.client_id != client_id && running.started.elapsed() >= threshold
+            }
+            None => false,
+        }
+    }
+
+    pub fn kill(&self) -> error::Result<()> {
+        match self.running.lock().unwrap().as_ref() {
+            None => return Err(Error::NotBusy),
+            Some(running) if running.wrote => return Err(Error::Unkillable),
+            Some(running) => running.killed.store(true, Ordering::Relaxed),
+        }
+        Ok(())
+    }
+}
+
+/// The interpreter of a function library. Its code runs once, when the library is loaded,
+/// and FCALL calls the callbacks it registered then.
+pub struct Interpreter {
+    lua: Mutex<Lua>,
+}
+
+pub fn sha1_hex(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+/// Run a script in a fresh Lua 5.1 interpreter.
+/// `redis.call` and `redis.pcall` run commands through `call`.
+pub fn run(
+    body: &str,
+    keys: Vec<String>,
+    argv: Vec<String>,
+    killed: Arc<AtomicBool>,
+    call: &mut dyn FnMut(Vec<String>) -> RespOut,
+) -> error::Result<RespOut> {
+    let lua =
+        new_lua().map_err(|e| Error::Err(format!("failed to create Lua interpreter: {}", e)))?;
+    execute(&lua, killed, call, |lua| {
+        let globals = lua.globals();
+        globals.set("KEYS", keys)?;
+        globals.set("ARGV", argv)?;
+        lua.load(body).set_name("@user_script").eval()
+    })
+}
+
+/// Run a function of a library, passing the keys and arguments as its two parameters.
+pub fn run_function(
+    interpreter: &Interpreter,
+    name: &str,
+    keys: Vec<String>,
+    argv: Vec<String>,
+    killed: Arc<AtomicBool>,
+    call: &mut dyn FnMut(Vec<String>) -> RespOut,
+) -> error::Result<RespOut> {
+    let lua = interpreter.lua.lock().unwrap();
+    execute(&lua, killed, call, |lua| {
+        registered_function(lua, name)?.call((keys, argv))
+    })
+}
+
+/// Run the code of a library to learn its name and the functions it registers.
+pub fn load_library(code: &str) -> Result<Library> {
+    let name = library_name(code)?;
+
+    let lua = new_lua().map_err(|e| anyhow::anyhow!("failed to create Lua interpreter: {}", e))?;
+    // the callbacks stay in the interpreter, only their descriptions are kept here
+    let functions: BTreeMap<String, Function> = {
+        let registered = (|| {
+            lua.globals().set("redis", lua.create_table()?)?;
+            register_functions(&lua, code)
+        })();
+        let functions = match registered {
+            Ok(functions) => functions,
+            Err(e) => bail!("Error registering functions: {}", error_message(&e)),
+        };
+        if functions.is_empty() {
+            bail!("No functions registered");
+        }
+        functions
+            .into_iter()
+            .map(|(function, _)| (function.name.clone(), function))
+            .collect()
+    };
+
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions,
+        interpreter: Arc::new(Interpreter {
+            lua: Mutex::new(lua),
+        }),
+    })
+}
+
+/// The name in the `#!lua name=<library>` header.
+fn library_name(code: &str) -> Result<String> {
+    let Some(header) = code.lines().next().and_then(|line| line.strip_prefix("#!")) else {
+        bail!("Missing library metadata");
+    };
+    let mut parts = header.split_whitespace();
+    match parts.next() {
+        Some("lua") => {}
+        Some(engine) => bail!("Engine '{}' not found", engine),
+        None => bail!("Missing library metadata"),
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("name", value)) => name = Some(value.to_string()),
+            _ => bail!("Invalid metadata value given: {}", part),
+        }
+    }
+    match name {
+        Some(name) if is_valid_name(&name) => Ok(name),
+        Some(_) => bail!(
+            "Library names can only contain letters, numbers, or underscores(_) and must be at least one character long"
+        ),
+        None => bail!("Library name was not given"),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Run the code of a library, collecting what it passes to `redis.register_function`.
+/// Expects the `redis` table to exist.
+fn register_functions<'lua>(
+    lua: &'lua Lua,
+    code: &str,
+) -> mlua::Result<Vec<(Function, mlua::Function<'lua>)>> {
+    lua.set_named_registry_value(REGISTERED_FUNCTIONS, lua.create_table()?)?;
+    let redis: Table = lua.globals().get("redis")?;
+    redis.set(
+        "register_function",
+        lua.create_function(|lua, args: MultiValue| {
+            let (name, callback, description, flags) = match args.len() {
+                1 => {
+                    let Some(Value::Table(table)) = args.into_iter().next() else {
+                        return Err(register_error("calling redis.register_function with a single argument is only applicable to Lua table (representing named arguments)."));
+                    };
+                    let flags: Option<Vec<String>> = table.get("flags")?;
+                    (
+                        table.get::<_, Option<String>>("function_name")?,
+                        table.get::<_, Option<mlua::Function>>("callback")?,
+                        table.get::<_, Option<String>>("description")?,
+                        flags.unwrap_or_default(),
+                    )
+                }
+                2 => {
+                    let mut args = args.into_iter();
+                    let name = match args.next() {
+                        Some(Value::String(s)) => Some(s.to_str()?.to_string()),
+                        _ => None,
+                    };
+                    let callback = match args.next() {
+                        Some(Value::Function(f)) => Some(f),
+                        _ => None,
+                    };
+                    (name, callback, None, Vec::new())
+                }
+                _ => return Err(register_error("wrong number of arguments to redis.register_function")),
+            };
+
+            let Some(name) = name.filter(|name| is_valid_name(name)) else {
+                return Err(register_error("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"));
+            };
+            let Some(callback) = callback else {
+                return Err(register_error("callback argument given to redis.register_function must be a function"));
+            };
+            for flag in &flags {
+                if !FUNCTION_FLAGS.contains(&flag.as_str()) {
+                    return Err(register_error("unknown flag given"));
+                }
+            }
+
+            let info = lua.create_table()?;
+            info.set("name", name)?;
+            info.set("description", description)?;
+            info.set("flags", flags)?;
+            let entry = lua.create_table()?;
+            entry.raw_set(1, info)?;
+            entry.raw_set(2, callback)?;
+
+            let registered: Table = lua.named_registry_value(REGISTERED_FUNCTIONS)?;
+            registered.raw_set(registered.raw_len() + 1, entry)?;
+            Ok(())
+        })?,
+    )?;
+
+    // Lua only skips a shebang line when loading files, keep the line numbers intact
+    let body = match code.split_once('\n') {
+        Some((_, rest)) => format!("\n{}", rest),
+        None => String::new(),
+    };
+    lua.load(&body).set_name("@user_function").exec()?;
+
+    let registered: Table = lua.named_registry_value(REGISTERED_FUNCTIONS)?;
+    let mut res: Vec<(Function, mlua::Function)> = Vec::new();
+    for entry in registered.sequence_values::<Table>() {
+        let entry = entry?;
+        let info: Table = entry.raw_get(1)?;
+        let function = Function {
+            name: info.get("name")?,
+            description: info.get("description")?,
+            flags: info.get("flags")?,
+        };
+        if res.iter().any(|(other, _)| other.name == function.name) {
+            return Err(register_error(&format!(
+                "Function {} already exists",
+                function.name
+            )));
+        }
+        res.push((function, entry.raw_get(2)?));
+    }
+    Ok(res)
+}
+
+/// The callback a library registered as `name` when it was loaded.
+fn registered_function<'lua>(lua: &'lua Lua, name: &str) -> mlua::Result<mlua::Function<'lua>> {
+    let registered: Table = lua.named_registry_value(REGISTERED_FUNCTIONS)?;
+    for entry in registered.sequence_values::<Table>() {
+        let entry = entry?;
+        let info: Table = entry.raw_get(1)?;
+        if info.get::<_, String>("name")? == name {
+            return entry.raw_get(2);
+        }
+    }
+    Err(mlua::Error::RuntimeError("Function not found".to_string()))
+}
+
+/// Where `redis.register_function` collects the functions in the Lua registry.
+const REGISTERED_FUNCTIONS: &str = "registered_functions";
+
+/// Flags functions may declare.
+const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+fn register_error(msg: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(msg.to_string())
+}
+
+fn new_lua() -> mlua::Result<Lua> {
+    Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::new(),
+    )
+}
+
+/// Give the interpreter the `redis` library, then run `body` in it.
+fn execute(
+    lua: &Lua,
+    killed: Arc<AtomicBool>,
+    call: &mut dyn FnMut(Vec<String>) -> RespOut,
+    body: impl for<'lua> FnOnce(&'lua Lua) -> mlua::Result<Value<'lua>>,
+) -> error::Result<RespOut> {
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(KILL_CHECK_INTERVAL),
+        move |_, _| match killed.load(Ordering::Relaxed) {
+            true => Err(mlua::Error::RuntimeError(
+                "Script killed by user with SCRIPT KILL...".to_string(),
+            )),
+            false => Ok(()),
+        },
+    );
+
+    let call = RefCell::new(call);
+    let res = lua.scope(|scope| {
+        let redis = lua.create_table()?;
+        redis.set(
+            "call",
+            scope.create_function(|lua, args: MultiValue| {
+                match (call.borrow_mut())(command_args(args)?) {
+                    RespOut::Error(e) => Err(mlua::Error::RuntimeError(e)),
+                    res => resp_to_lua(lua, res),
+                }
+            })?,
+        )?;
+        redis.set(
+            "pcall",
+            scope.create_function(|lua, args: MultiValue| {
+                let res = (call.borrow_mut())(command_args(args)?);
+                resp_to_lua(lua, res)
+            })?,
+        )?;
+        redis.set(
+            "error_reply",
+            lua.create_function(|lua, msg: String| resp_to_lua(lua, RespOut::Error(msg)))?,
+        )?;
+        redis.set(
+            "status_reply",
+            lua.create_function(|lua, msg: String| resp_to_lua(lua, RespOut::SimpleString(msg)))?,
+        )?;
+        redis.set(
+            "sha1hex",
+            lua.create_function(|_, s: mlua::String| Ok(sha1_hex(s.as_bytes())))?,
+        )?;
+        lua.globals().set("redis", redis)?;
+
+        Ok(lua_to_resp(body(lua)?))
+    });
+    // a library's interpreter is used again by the next call
+    lua.remove_hook();
+
+    let msg = match res {
+        Ok(res) => return Ok(res),
+        Err(e) => error_message(&e),
+    };
+    // errors replied to redis.call keep their code
+    let code = msg.split(' ').next().unwrap_or_default();
+    match !code.is_empty() && code.chars().all(|c| c.is_ascii_uppercase()) {
+        true => Err(Error::Reply(msg)),
+        false => Err(Error::Err(format!("Error running script: {}", msg))),
+    }
+}
+
+/// The message of the error that made the script fail, without Lua's wrapping.
+fn error_message(e: &mlua::Error) -> String {
+    match e {
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        // drop the stack traceback Lua appends
+        mlua::Error::RuntimeError(msg) => match msg.split_once("\nstack traceback:") {
+            Some((msg, _)) => msg.to_string(),
+            None => msg.clone(),
+        },
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        e => e.to_string(),
+    }
+}
+
+fn command_args(args: MultiValue) -> mlua::Result<Vec<String>> {
+    if args.is_empty() {
+        return Err(mlua::Error::RuntimeError(
+            "Please specify at least one argument for this redis lib call".to_string(),
+        ));
+    }
+
+    args.into_iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+            Value::Integer(i) => Ok(i.to_string()),
+            Value::Number(n) => Ok(n.to_string()),
+            _ => Err(mlua::Error::RuntimeError(
+                "Lua redis lib command arguments must be strings or integers".to_string(),
+            )),
+        })
+        .collect()
+}
+
+/// Convert a reply to the Lua value scripts see, the way Redis does.
+fn resp_to_lua(lua: &Lua, value: RespOut) -> mlua::Result<Value<'_>> {
+    let res = match value {
+        RespOut::SimpleString(s) => {
+            let table = lua.create_table()?;
+            table.raw_set("ok", s)?;
+            Value::Table(table)
+        }
+        RespOut::Error(e) => {
+            let table = lua.create_table()?;
+            table.raw_set("err", e)?;
+            Value::Table(table)
+        }
+        RespOut::Integer(i) => Value::Integer(i),
+        RespOut::BulkString(s) => Value::String(lua.create_string(&s)?),
+        RespOut::BulkBytes(bytes) => Value::String(lua.create_string(&bytes)?),
+        RespOut::Array(values) => {
+            let table = lua.create_table()?;
+            for (i, value) in values.into_iter().enumerate() {
+                table.raw_set(i + 1, resp_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        RespOut::Null => Value::Boolean(false),
+    };
+    Ok(res)
+}
+
+/// Convert what a script returns to a reply, the way Redis does.
+fn lua_to_resp(value: Value) -> RespOut {
+    match value {
+        Value::Boolean(true) => RespOut::Integer(1),
+        Value::Integer(i) => RespOut::Integer(i),
+        // Lua only has floats, replies only integers
+        Value::Number(n) => RespOut::Integer(n as i64),
+        Value::String(s) => RespOut::BulkString(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Value::Table(table) => table_to_resp(table),
+        _ => RespOut::Null,
+    }
+}
+
+fn table_to_resp(table: Table) -> RespOut {
+    if let Ok(Value::String(err)) = table.raw_get("err") {
+        return RespOut::Error(String::from_utf8_lossy(err.as_bytes()).into_owned());
+    }
+    if let Ok(Value::String(ok)) = table.raw_get("ok") {
+        return RespOut::SimpleString(String::from_utf8_lossy(ok.as_bytes()).into_owned());
+    }
+
+    // arrays end at the first nil
+    let mut res = Vec::new();
+    for i in 1.. {
+        match table.raw_get(i) {
+            Ok(Value::Nil) | Err(_) => break,
+            Ok(value) => res.push(lua_to_resp(value)),
+        }
+    }
+    RespOut::Array(res)
+}
@@ -0,0 +1,25 @@
+use crate::acl::SharedAcl;
+use crate::client::SharedClients;
+use crate::command::SharedCommands;
+use crate::config::SharedConfig;
+use crate::data::SharedData;
+use crate::functions::SharedFunctions;
+use crate::info::SharedInfo;
+use crate::module::SharedModules;
+use crate::pubsub::SharedPubSub;
+use crate::scripting::SharedScripts;
+
+/// Everything shared between connections and background tasks.
+#[derive(Clone)]
+pub struct State {
+    pub data: SharedData,
+    pub info: SharedInfo,
+    pub pubsub: SharedPubSub,
+    pub scripts: SharedScripts,
+    pub functions: SharedFunctions,
+    pub modules: SharedModules,
+    pub commands: SharedCommands,
+    pub acl: SharedAcl,
+    pub config: SharedConfig,
+    pub clients: SharedClients,
+}
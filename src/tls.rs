@@ -0,0 +1,83 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// TLS for client connections on the TLS port and for the link to the master.
+// The same certificate identifies us as a server and as a replica connecting to its master.
+
+/// Whether clients connecting over TLS must present a certificate signed by the CA.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum AuthClients {
+    No,
+    Yes,
+    /// Clients may connect without a certificate, but one they present must be valid.
+    Optional,
+}
+
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// CA certificates that client and master certificates are checked against.
+    pub ca_cert_file: Option<PathBuf>,
+    pub auth_clients: AuthClients,
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificate found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())?
+        .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+impl TlsConfig {
+    fn ca_certs(&self) -> Result<Arc<RootCertStore>> {
+        let Some(path) = &self.ca_cert_file else {
+            bail!("tls-ca-cert-file is required to verify peer certificates");
+        };
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(path)? {
+            roots.add(cert)?;
+        }
+        Ok(Arc::new(roots))
+    }
+
+    /// Accepts TLS connections of clients, checking their certificates as configured.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let builder = ServerConfig::builder();
+        let builder = match self.auth_clients {
+            AuthClients::No => builder.with_no_client_auth(),
+            AuthClients::Yes => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder(self.ca_certs()?).build()?,
+            ),
+            AuthClients::Optional => builder.with_client_cert_verifier(
+                WebPkiClientVerifier::builder(self.ca_certs()?)
+                    .allow_unauthenticated()
+                    .build()?,
+            ),
+        };
+        let config =
+            builder.with_single_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Connects to the master, checking its certificate and presenting ours.
+    pub fn connector(&self) -> Result<TlsConnector> {
+        let config = ClientConfig::builder()
+            .with_root_certificates(self.ca_certs()?)
+            .with_client_auth_cert(load_certs(&self.cert_file)?, load_key(&self.key_file)?)?;
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
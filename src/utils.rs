@@ -1,6 +1,196 @@
-pub fn print_buf(buf: &[u8], prefix: &str) {
-    println!(
-        "  (DEBUG) {prefix}: {:?}",
-        buf.iter().map(|b| *b as char).collect::<String>()
-    );
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current unix time in milliseconds.
+pub fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970")
+        .as_millis()
+}
+
+/// Compare secrets in a time that does not depend on where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Glob-style matching as done by Redis: `*`, `?`, `[abc]`, `[^a]`, `[a-z]` and `\` escapes.
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), string.as_bytes())
+}
+
+/// Iterative, remembering only the last `*`: when the rest does not match, that star takes
+/// one more byte. Backtracking into earlier stars is never needed, and recursing would take
+/// exponential time on patterns like `*a*a*a*a*b`.
+fn glob_match_bytes(p: &[u8], s: &[u8]) -> bool {
+    let (mut pi, mut si) = (0, 0);
+    // the token after the last star and where in `s` its match starts
+    let mut star = None;
+    loop {
+        if p.get(pi) == Some(&b'*') {
+            while p.get(pi) == Some(&b'*') {
+                pi += 1;
+            }
+            if pi == p.len() {
+                return true;
+            }
+            star = Some((pi, si));
+            continue;
+        }
+        let next = match (p.get(pi), s.get(si)) {
+            (None, None) => return true,
+            (Some(_), Some(&ch)) => match_token(p, pi, ch),
+            _ => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                pi = next;
+                si += 1;
+            }
+            (None, Some((star_p, star_s))) if star_s < s.len() => {
+                star = Some((star_p, star_s + 1));
+                pi = star_p;
+                si = star_s + 1;
+            }
+            (None, _) => return false,
+        }
+    }
+}
+
+/// Match the token of `p` at `pi`, anything but `*`, against `ch`.
+/// Returns where the next token starts.
+fn match_token(p: &[u8], pi: usize, ch: u8) -> Option<usize> {
+    match p[pi] {
+        b'?' => Some(pi + 1),
+        b'[' => {
+            let mut q = &p[pi + 1..];
+            let negate = q.first() == Some(&b'^');
+            if negate {
+                q = &q[1..];
+            }
+            let mut matched = false;
+            loop {
+                match q {
+                    [] | [b']', ..] => break,
+                    [b'\\', c, ..] => {
+                        matched |= *c == ch;
+                        q = &q[2..];
+                    }
+                    [a, b'-', b, ..] => {
+                        let (lo, hi) = if a <= b { (*a, *b) } else { (*b, *a) };
+                        matched |= (lo..=hi).contains(&ch);
+                        q = &q[3..];
+                    }
+                    [c, ..] => {
+                        matched |= *c == ch;
+                        q = &q[1..];
+                    }
+                }
+            }
+            if matched == negate {
+                return None;
+            }
+            // skip the `]`, an unterminated class ends the pattern
+            Some(p.len() - q.len() + usize::from(!q.is_empty()))
+        }
+        b'\\' if pi + 1 < p.len() => (p[pi + 1] == ch).then_some(pi + 2),
+        c => (c == ch).then_some(pi + 1),
+    }
+}
+
+/// Number of hash slots keys and sharded channels are distributed over.
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+/// The hash slot of a key or sharded channel.
+/// Only the part between the first `{` and the next `}` is hashed if it is not empty,
+/// so related names can be forced into the same slot.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let key = match key.iter().position(|&b| b == b'{') {
+        Some(start) => match key[start + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &key[start + 1..start + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(key) % CLUSTER_SLOTS
+}
+
+/// CRC-16/XMODEM as used by Redis Cluster.
+fn crc16(buf: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in buf {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h*llo", "hllo"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("*:*:end", "a:b:c:end"));
+        assert!(!glob_match("*:end", "a:end:"));
+    }
+
+    #[test]
+    fn glob_match_classes_and_escapes() {
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(!glob_match("h[a-c]llo", "hdllo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("[\\]]", "]"));
+        // an unterminated class ends the pattern
+        assert!(!glob_match("ab[", "abc"));
+    }
+
+    #[test]
+    fn glob_match_many_stars_in_linear_time() {
+        let pattern = "*a".repeat(30) + "b";
+        assert!(!glob_match(&pattern, &"a".repeat(100)));
+        assert!(glob_match(&pattern, &("a".repeat(100) + "b")));
+    }
+
+    #[test]
+    fn key_hash_slot_of_plain_keys() {
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"somekey"), 11058);
+        assert_eq!(key_hash_slot(b""), 0);
+    }
+
+    #[test]
+    fn key_hash_slot_of_hash_tags() {
+        assert_eq!(
+            key_hash_slot(b"{user1000}.following"),
+            key_hash_slot(b"{user1000}.followers")
+        );
+        assert_eq!(key_hash_slot(b"foo{bar}{zap}"), key_hash_slot(b"bar"));
+        assert_eq!(key_hash_slot(b"foo{{bar}}zap"), key_hash_slot(b"{bar"));
+        // an empty tag hashes the whole key
+        assert_ne!(key_hash_slot(b"foo{}{bar}"), key_hash_slot(b"bar"));
+        assert_eq!(
+            key_hash_slot(b"foo{}{bar}"),
+            crc16(b"foo{}{bar}") % CLUSTER_SLOTS
+        );
+    }
 }